@@ -0,0 +1,120 @@
+//! Benchmarks for the interpreter's parts in isolation, each over a fixed
+//! corpus and reporting a rate, so a change to one part shows up in its own
+//! number rather than in an end-to-end total:
+//!
+//! - `scanner`: source bytes scanned into tokens.
+//! - `compile`: source bytes compiled to bytecode.
+//! - `dispatch`: loop iterations of simple arithmetic.
+//! - `calls`: calls to a function that does nothing.
+//! - `allocation`: small lists allocated, and collected, by a running script.
+//!
+//! Run with `cargo bench --bench components`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rlox::compiler::{Compiler, CompilerLimits};
+use rlox::scanner::{Scanner, TokenType};
+use rlox::{memory, VM};
+use std::collections::HashMap;
+
+// A program touching most of the syntax, repeated to make the corpus
+const PROGRAM: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+var names = [\"alpha\", \"beta\", \"gamma\"];
+var lengths = {};
+for (var name in names) lengths[name] = len(name);
+var total = 0;
+for (var i = 0; i < 10; i = i + 1) {
+  total = total + fib(i) * 2 - 1 / 3;
+  if (total > 100 and i != 3 or !false) print total;
+}
+try { throw \"done\"; } catch (e) { print e; }
+";
+const PROGRAM_COPIES: usize = 200;
+
+// Each benchmark script's loop runs this many times
+const ITERATIONS: u64 = 20_000;
+
+fn corpus() -> String {
+    PROGRAM.repeat(PROGRAM_COPIES)
+}
+
+fn run(source: &str) {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    vm.set_output(Box::new(std::io::sink()));
+    vm.interpret(source.to_owned())
+        .expect("Benchmark script failed");
+}
+
+fn scanner(c: &mut Criterion) {
+    let source = corpus();
+    let mut group = c.benchmark_group("scanner");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("corpus", |b| {
+        b.iter(|| {
+            let mut scanner = Scanner::new(&source);
+            loop {
+                match scanner.scan_token() {
+                    Ok(token) if token.token_type == TokenType::Eof => break,
+                    Ok(_) => {}
+                    Err(error) => panic!("Corpus failed to scan: {error}"),
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+fn compile(c: &mut Criterion) {
+    let source = corpus();
+    let mut group = c.benchmark_group("compile");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("corpus", |b| {
+        b.iter(|| {
+            let mut allocator = memory::Allocator::new();
+            let mut identifiers = HashMap::new();
+            let mut compiler = Compiler::new(
+                &source,
+                &mut allocator,
+                &mut identifiers,
+                CompilerLimits::default(),
+            );
+            compiler.prepare();
+            if compiler.compile(false).is_err() {
+                panic!("Corpus failed to compile");
+            }
+        })
+    });
+    group.finish();
+}
+
+fn runtime(c: &mut Criterion) {
+    let dispatch = format!(
+        "var x = 0;
+for (var i = 0; i < {ITERATIONS}; i = i + 1) x = x + i * 2 - 1;"
+    );
+    let calls = format!(
+        "fun nothing() {{}}
+for (var i = 0; i < {ITERATIONS}; i = i + 1) nothing();"
+    );
+    let allocation = format!(
+        "var kept;
+for (var i = 0; i < {ITERATIONS}; i = i + 1) kept = [i, i];"
+    );
+    for (name, source) in [
+        ("dispatch", dispatch),
+        ("calls", calls),
+        ("allocation", allocation),
+    ] {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Elements(ITERATIONS));
+        group.bench_function("loop", |b| b.iter(|| run(&source)));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, scanner, compile, runtime);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+//! End-to-end benchmarks: each compiles and runs a script on a fresh VM, so
+//! they measure the dispatch loop, allocation, and collection together.
+//! Run with `cargo bench`; add a bench here for any workload a performance
+//! change targets before making the change.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::{memory, VM};
+
+const FIB: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+print fib(20);
+";
+
+// Trees built from two-element lists, stressing allocation and collection
+const BINARY_TREES: &str = "
+fun make(depth) {
+  if (depth == 0) return [nil, nil];
+  return [make(depth - 1), make(depth - 1)];
+}
+fun check(tree) {
+  if (tree[0] == nil) return 1;
+  return 1 + check(tree[0]) + check(tree[1]);
+}
+var total = 0;
+for (var i = 0; i < 10; i = i + 1) total = total + check(make(8));
+print total;
+";
+
+const STRING_CONCAT: &str = "
+var s = \"\";
+for (var i = 0; i < 2000; i = i + 1) s = s + \"x\";
+print len(s);
+";
+
+const CLOSURES: &str = "
+fun makeCounter() {
+  var count = 0;
+  fun increment() {
+    count = count + 1;
+    return count;
+  }
+  return increment;
+}
+var sum = 0;
+for (var i = 0; i < 200; i = i + 1) {
+  var counter = makeCounter();
+  for (var j = 0; j < 20; j = j + 1) sum = sum + counter();
+}
+print sum;
+";
+
+const MAPS: &str = "
+var map = {};
+for (var i = 0; i < 500; i = i + 1) map[toString(i)] = i;
+var sum = 0;
+for (var key in map) sum = sum + map[key];
+print sum;
+";
+
+fn run(source: &str) {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    vm.set_output(Box::new(std::io::sink()));
+    vm.interpret(source.to_owned())
+        .expect("Benchmark script failed");
+}
+
+fn benchmarks(c: &mut Criterion) {
+    for (name, source) in [
+        ("fib", FIB),
+        ("binary_trees", BINARY_TREES),
+        ("string_concat", STRING_CONCAT),
+        ("closures", CLOSURES),
+        ("maps", MAPS),
+    ] {
+        c.bench_function(name, |b| b.iter(|| run(source)));
+    }
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
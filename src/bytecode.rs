@@ -0,0 +1,281 @@
+//! The `.loxc` format: a compiled program saved so it can run later without
+//! re-parsing. A file is the magic bytes `LOXC`, a little-endian `u16`
+//! format version, then the script function. A function is written as its
+//! type, arity, upvalue count, and name, then its chunk: code, line numbers,
+//! the constant pool, the chunk's debug info, and its entry points. Nested functions are written
+//! in place in their parent's constant pool.
+//!
+//! Saved programs refer to globals by name: slots only mean something to the
+//! VM whose `Globals` assigned them, so code a VM compiled can't be saved.
+//!
+//! Loading doesn't verify the bytecode itself, so only load files you trust.
+
+use crate::chunk::{Chunk, LocalInfo, Span};
+use crate::memory::{Allocator, Gc};
+use crate::object_bytes::ObjBytes;
+use crate::object_closure::ObjClosure;
+use crate::object_function::{FunctionType, ObjFunction};
+use crate::object_string::ObjString;
+use crate::value::Value;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the encoding or the instruction set changes.
+pub const VERSION: u16 = 14;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+const TAG_BYTES: u8 = 6;
+// A closure over no variables, written as its function
+const TAG_CLOSURE: u8 = 7;
+
+/// Encodes the program whose script function is `function`.
+pub fn serialize(function: Gc<ObjFunction>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    write_function(&mut bytes, function.get());
+    bytes
+}
+
+/// Decodes a program written by `serialize`, allocating its functions and
+/// strings with `allocator`, and returns its script function.
+pub fn deserialize(bytes: &[u8], allocator: &mut Allocator) -> Result<Gc<ObjFunction>, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a compiled Lox file.".to_owned());
+    }
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported compiled file version {version}; expected {VERSION}."
+        ));
+    }
+    let function = reader.function(allocator)?;
+    if reader.position != bytes.len() {
+        return Err("Unexpected data after the end of the program.".to_owned());
+    }
+    Ok(function)
+}
+
+/// Drops the debug info from the program whose script function is
+/// `function` and every function nested in it, leaving what compiling it with
+/// debug info turned off would have.
+pub fn strip_debug_info(function: Gc<ObjFunction>) {
+    for function in ObjFunction::collect_functions(function) {
+        let chunk = &mut function.get_mut().chunk;
+        chunk.spans.clear();
+        chunk.locals.clear();
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("Chunk too large to serialize");
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &ObjFunction) {
+    bytes.push(match function.function_type {
+        FunctionType::Function => 0,
+        FunctionType::Script => 1,
+    });
+    bytes.push(function.arity);
+    write_u32(bytes, function.upvalue_count);
+    bytes.push(function.frame_bound as u8);
+    match &function.name {
+        Some(name) => {
+            bytes.push(1);
+            write_str(bytes, &name.str);
+        }
+        None => bytes.push(0),
+    }
+    write_chunk(bytes, &function.chunk);
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, chunk: &Chunk) {
+    write_u32(bytes, chunk.code.len());
+    bytes.extend_from_slice(&chunk.code);
+    write_u32(bytes, chunk.lines.len());
+    for (line, column) in chunk.lines.iter().zip(chunk.columns.iter()) {
+        write_u32(bytes, *line);
+        write_u32(bytes, *column);
+    }
+
+    write_u32(bytes, chunk.constants.len());
+    for constant in chunk.constants.iter() {
+        match constant {
+            Value::Nil => bytes.push(TAG_NIL),
+            Value::Bool(false) => bytes.push(TAG_FALSE),
+            Value::Bool(true) => bytes.push(TAG_TRUE),
+            Value::Number(number) => {
+                bytes.push(TAG_NUMBER);
+                bytes.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::ObjString(string) => {
+                bytes.push(TAG_STRING);
+                write_str(bytes, &string.get().str);
+            }
+            Value::ObjBytes(obj_bytes) => {
+                let contents = &obj_bytes.get().bytes;
+                bytes.push(TAG_BYTES);
+                write_u32(bytes, contents.len());
+                bytes.extend_from_slice(contents);
+            }
+            Value::ObjFunction(function) => {
+                bytes.push(TAG_FUNCTION);
+                write_function(bytes, function.get());
+            }
+            Value::ObjClosure(closure) => {
+                bytes.push(TAG_CLOSURE);
+                write_function(bytes, closure.get().function.get());
+            }
+            _ => panic!("Unexpected {} in constant pool", constant.type_name()),
+        }
+    }
+
+    write_u32(bytes, chunk.spans.len());
+    for (offset, span) in chunk.spans.iter() {
+        write_u32(bytes, *offset);
+        write_str(bytes, &span.text);
+        write_u32(bytes, span.line);
+        write_u32(bytes, span.column);
+    }
+    write_u32(bytes, chunk.locals.len());
+    for local in chunk.locals.iter() {
+        write_str(bytes, &local.name);
+        write_u32(bytes, local.slot);
+        write_u32(bytes, local.start);
+        write_u32(bytes, local.end);
+    }
+    write_u32(bytes, chunk.files.len());
+    for (offset, file) in chunk.files.iter() {
+        write_u32(bytes, *offset);
+        write_str(bytes, file);
+    }
+    write_u32(bytes, chunk.entry_points.len());
+    for entry_point in chunk.entry_points.iter() {
+        write_u32(bytes, *entry_point);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("Unexpected end of compiled file.")?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid string in compiled file.".to_owned())
+    }
+
+    fn function(&mut self, allocator: &mut Allocator) -> Result<Gc<ObjFunction>, String> {
+        let function_type = match self.u8()? {
+            0 => FunctionType::Function,
+            1 => FunctionType::Script,
+            other => return Err(format!("Invalid function type {other}.")),
+        };
+        let arity = self.u8()?;
+        let upvalue_count = self.u32()?;
+        let frame_bound = self.u8()? != 0;
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(ObjString::new(&self.string()?)),
+        };
+
+        let mut function = ObjFunction::new(function_type, name);
+        function.arity = arity;
+        function.upvalue_count = upvalue_count;
+        function.frame_bound = frame_bound;
+        self.chunk(&mut function.chunk, allocator)?;
+        Ok(allocator.heap_alloc(function))
+    }
+
+    fn chunk(&mut self, chunk: &mut Chunk, allocator: &mut Allocator) -> Result<(), String> {
+        let code_len = self.u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+        let line_count = self.u32()?;
+        for _ in 0..line_count {
+            chunk.lines.push(self.u32()?);
+            chunk.columns.push(self.u32()?);
+        }
+
+        let constant_count = self.u32()?;
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Bool(false),
+                TAG_TRUE => Value::Bool(true),
+                TAG_NUMBER => Value::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                TAG_STRING => {
+                    let string = self.string()?;
+                    Value::ObjString(allocator.heap_alloc(ObjString::new(&string)))
+                }
+                TAG_BYTES => {
+                    let len = self.u32()?;
+                    let contents = self.take(len)?.to_vec();
+                    Value::ObjBytes(allocator.heap_alloc(ObjBytes::new(contents)))
+                }
+                TAG_FUNCTION => Value::ObjFunction(self.function(allocator)?),
+                TAG_CLOSURE => {
+                    let function = self.function(allocator)?;
+                    Value::ObjClosure(allocator.heap_alloc(ObjClosure::new(function)))
+                }
+                other => return Err(format!("Invalid constant tag {other}.")),
+            };
+            chunk.add_constant(constant);
+        }
+
+        for _ in 0..self.u32()? {
+            let offset = self.u32()?;
+            let text = self.string()?;
+            let (line, column) = (self.u32()?, self.u32()?);
+            chunk.add_span(offset, Span { text, line, column });
+        }
+        for _ in 0..self.u32()? {
+            chunk.locals.push(LocalInfo {
+                name: self.string()?,
+                slot: self.u32()?,
+                start: self.u32()?,
+                end: self.u32()?,
+            });
+        }
+        for _ in 0..self.u32()? {
+            let offset = self.u32()?;
+            let file = self.string()?;
+            chunk.files.push((offset, file));
+        }
+        chunk.entry_points = (0..self.u32()?)
+            .map(|_| self.u32())
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use derive_more::Display;
 
 use crate::value::Value;
 
-#[derive(Display)]
+#[derive(Display, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Opcode {
     Return = 0,
@@ -34,12 +34,90 @@ pub enum Opcode {
     GetUpvalue,
     SetUpvalue,
     CloseUpvalue,
+    BuildList,
+    IndexGet,
+    IndexSet,
+    BuildMap,
+    IterNext,
+    Throw,
+    PushHandler,
+    PopHandler,
+    // Global access by slot, in code compiled by a VM; see `globals`
+    DefineGlobalSlot,
+    GetGlobalSlot,
+    SetGlobalSlot,
+    // The counterpart to `JumpIfFalse`, so each operand of `or` takes one jump
+    JumpIfTrue,
+    // Captured variables of a frame-bound function; see `ObjFunction::frame_bound`
+    GetCallerLocal,
+    SetCallerLocal,
+    // Bitwise operators, on numbers truncated to 32-bit integers
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    // Define a `const` global, which can't be assigned to or redefined
+    DefineGlobalConst,
+    DefineGlobalConstSlot,
+    // Jumps unless the value on top of the stack is nil, for `??`
+    JumpIfNotNil,
+    // Calls a method of a built-in type; see `methods`
+    Invoke,
+    // Reads a map entry as `map.name`, as for a namespace like `Number`
+    GetProperty,
+    // Pushes a fresh copy of a mutable constant, so each evaluation of a
+    // bytes literal gets its own
+    CopyConstant,
+}
+
+/// The source text of the expression an instruction was compiled from, so
+/// runtime errors can point at the offending code, and where it starts.
+#[derive(Clone)]
+pub struct Span {
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Debug info for a local variable: its name, its stack slot relative to the
+/// frame, and the range of bytecode offsets over which it is in scope.
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
 }
 
 pub struct Chunk {
     pub code: Vec<u8>,
     pub lines: Vec<usize>,
+    /// The column of the token each byte was compiled at, alongside `lines`
+    pub columns: Vec<usize>,
+    /// The values the chunk's instructions load. The table is the chunk's,
+    /// but objects in it belong to the allocator and may be shared with other
+    /// chunks; the table only keeps them reachable.
     pub constants: Vec<Value>,
+    /// Spans for the instructions that can raise operand errors, sorted by offset
+    pub spans: Vec<(usize, Span)>,
+    pub locals: Vec<LocalInfo>,
+    /// The source file each run of bytecode was compiled from, as the offset
+    /// the run starts at and the file's name, sorted by offset. Empty for
+    /// code compiled from an unnamed source.
+    pub files: Vec<(usize, String)>,
+    /// For a function with default parameter values, where a call starts for
+    /// each number of arguments it can be passed, fewest first: at the code
+    /// computing the first missing parameter's default, or with every
+    /// argument passed, at the body. Empty for any other function, which
+    /// always starts at 0.
+    pub entry_points: Vec<usize>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::new()
+    }
 }
 
 impl Chunk {
@@ -47,19 +125,126 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             lines: Vec::new(),
+            columns: Vec::new(),
             constants: Vec::new(),
+            spans: Vec::new(),
+            locals: Vec::new(),
+            files: Vec::new(),
+            entry_points: Vec::new(),
         }
     }
 
-    pub fn write_chunk(&mut self, byte: u8, line: usize) {
+    pub fn write_chunk(&mut self, byte: u8, line: usize, column: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
+    }
+
+    /// Attributes the bytecode written from here on to `file`.
+    pub fn set_file(&mut self, file: &str) {
+        if self.files.last().map(|(_, name)| name.as_str()) != Some(file) {
+            self.files.push((self.code.len(), file.to_owned()));
+        }
+    }
+
+    pub fn file_at(&self, offset: usize) -> Option<&str> {
+        let run = self.files.partition_point(|(start, _)| *start <= offset);
+        run.checked_sub(1).map(|run| self.files[run].1.as_str())
+    }
+
+    /// Describes where the instruction at `offset` came from, e.g. `main.lox:3`,
+    /// or `line 3` if its file isn't known.
+    pub fn location_at(&self, offset: usize) -> String {
+        let line = self.lines[offset];
+        match self.file_at(offset) {
+            Some(file) => format!("{file}:{line}"),
+            None => format!("line {line}"),
+        }
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
+
+    pub fn add_span(&mut self, offset: usize, span: Span) {
+        self.spans.push((offset, span));
+    }
+
+    pub fn span_at(&self, offset: usize) -> Option<&Span> {
+        self.spans
+            .binary_search_by_key(&offset, |(span_offset, _)| *span_offset)
+            .ok()
+            .map(|index| &self.spans[index].1)
+    }
+
+    /// Returns the locals in scope at `offset`.
+    pub fn locals_at(&self, offset: usize) -> impl Iterator<Item = &LocalInfo> {
+        self.locals
+            .iter()
+            .filter(move |local| local.start <= offset && offset < local.end)
+    }
+
+    /// Returns the size in bytes of the instruction at `offset`, including its operands.
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        let Ok(opcode) = Opcode::try_from(self.code[offset]) else {
+            return 1;
+        };
+        match opcode {
+            Opcode::Constant
+            | Opcode::CopyConstant
+            | Opcode::DefineGlobal
+            | Opcode::DefineGlobalConst
+            | Opcode::GetProperty
+            | Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::Call
+            | Opcode::GetUpvalue
+            | Opcode::SetUpvalue
+            | Opcode::BuildList
+            | Opcode::BuildMap
+            | Opcode::IterNext
+            | Opcode::GetCallerLocal
+            | Opcode::SetCallerLocal => 2,
+            Opcode::JumpIfFalse
+            | Opcode::Jump
+            | Opcode::Loop
+            | Opcode::PushHandler
+            | Opcode::DefineGlobalSlot
+            | Opcode::DefineGlobalConstSlot
+            | Opcode::GetGlobalSlot
+            | Opcode::SetGlobalSlot
+            | Opcode::JumpIfTrue
+            | Opcode::JumpIfNotNil => 3,
+            Opcode::Invoke => 3,
+            Opcode::Closure => {
+                let constant = self.code[offset + 1] as usize;
+                let upvalue_count = match &self.constants[constant] {
+                    Value::ObjFunction(obj_fun) => obj_fun.get().upvalue_count,
+                    _ => 0,
+                };
+                2 + upvalue_count * 2
+            }
+            _ => 1,
+        }
+    }
+}
+
+impl Opcode {
+    /// Decodes `byte` as an opcode without checking that it is one in release
+    /// builds, for the `unchecked-dispatch` feature.
+    ///
+    /// # Safety
+    ///
+    /// `byte` must be an opcode.
+    #[cfg(feature = "unchecked-dispatch")]
+    pub unsafe fn from_u8_unchecked(byte: u8) -> Opcode {
+        debug_assert!(Opcode::try_from(byte).is_ok(), "Invalid opcode {byte}");
+        // Opcodes are numbered from 0 with no gaps, up to the last
+        unsafe { std::mem::transmute::<u8, Opcode>(byte) }
+    }
 }
 
 impl TryFrom<u8> for Opcode {
@@ -95,6 +280,32 @@ impl TryFrom<u8> for Opcode {
             26 => Ok(Opcode::GetUpvalue),
             27 => Ok(Opcode::SetUpvalue),
             28 => Ok(Opcode::CloseUpvalue),
+            29 => Ok(Opcode::BuildList),
+            30 => Ok(Opcode::IndexGet),
+            31 => Ok(Opcode::IndexSet),
+            32 => Ok(Opcode::BuildMap),
+            33 => Ok(Opcode::IterNext),
+            34 => Ok(Opcode::Throw),
+            35 => Ok(Opcode::PushHandler),
+            36 => Ok(Opcode::PopHandler),
+            37 => Ok(Opcode::DefineGlobalSlot),
+            38 => Ok(Opcode::GetGlobalSlot),
+            39 => Ok(Opcode::SetGlobalSlot),
+            40 => Ok(Opcode::JumpIfTrue),
+            41 => Ok(Opcode::GetCallerLocal),
+            42 => Ok(Opcode::SetCallerLocal),
+            43 => Ok(Opcode::BitAnd),
+            44 => Ok(Opcode::BitOr),
+            45 => Ok(Opcode::BitXor),
+            46 => Ok(Opcode::BitNot),
+            47 => Ok(Opcode::ShiftLeft),
+            48 => Ok(Opcode::ShiftRight),
+            49 => Ok(Opcode::DefineGlobalConst),
+            50 => Ok(Opcode::DefineGlobalConstSlot),
+            51 => Ok(Opcode::JumpIfNotNil),
+            52 => Ok(Opcode::Invoke),
+            53 => Ok(Opcode::GetProperty),
+            54 => Ok(Opcode::CopyConstant),
             _ => Err(()),
         }
     }
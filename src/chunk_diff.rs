@@ -0,0 +1,103 @@
+use std::fmt::Write;
+
+use crate::debug::instruction_listing;
+use crate::memory::Gc;
+use crate::object_function::ObjFunction;
+
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Aligns two instruction listings on their longest common subsequence.
+pub fn diff_listings(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(DiffLine::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().cloned().map(DiffLine::Removed));
+    lines.extend(new[j..].iter().cloned().map(DiffLine::Added));
+    lines
+}
+
+/// Diffs the disassembly of two compiled programs function by function,
+/// pairing functions by name in the order they appear. Returns the rendered
+/// diff and whether any function changed.
+pub fn diff_programs(old: Gc<ObjFunction>, new: Gc<ObjFunction>) -> (String, bool) {
+    let old_functions = ObjFunction::collect_functions(old);
+    let mut new_functions: Vec<Option<Gc<ObjFunction>>> = ObjFunction::collect_functions(new)
+        .into_iter()
+        .map(Some)
+        .collect();
+
+    let mut out = String::new();
+    let mut changed = false;
+    for old_function in old_functions {
+        let name = old_function.get().to_string();
+        let new_function = new_functions
+            .iter_mut()
+            .find(
+                |function| matches!(function, Some(function) if function.get().to_string() == name),
+            )
+            .and_then(|function| function.take());
+
+        let old_listing = instruction_listing(&old_function.get().chunk);
+        let new_listing = match new_function {
+            Some(new_function) => instruction_listing(&new_function.get().chunk),
+            None => Vec::new(),
+        };
+        changed |= write_function_diff(&mut out, &name, &old_listing, &new_listing);
+    }
+
+    for new_function in new_functions.into_iter().flatten() {
+        let name = new_function.get().to_string();
+        let new_listing = instruction_listing(&new_function.get().chunk);
+        changed |= write_function_diff(&mut out, &name, &[], &new_listing);
+    }
+
+    (out, changed)
+}
+
+fn write_function_diff(out: &mut String, name: &str, old: &[String], new: &[String]) -> bool {
+    if old == new {
+        writeln!(out, "== {name} == (unchanged)").unwrap();
+        return false;
+    }
+
+    writeln!(out, "== {name} ==").unwrap();
+    for line in diff_listings(old, new) {
+        let (marker, text) = match &line {
+            DiffLine::Same(text) => (' ', text),
+            DiffLine::Removed(text) => ('-', text),
+            DiffLine::Added(text) => ('+', text),
+        };
+        for text_line in text.lines() {
+            writeln!(out, "{marker} {text_line}").unwrap();
+        }
+    }
+    true
+}
@@ -0,0 +1,116 @@
+/// Decides when the VM collects garbage. A collection marks every object
+/// reachable from the VM's roots and frees the rest.
+pub trait CollectorStrategy {
+    /// Whether to collect before the VM's next allocation, given the size of
+    /// the objects allocated so far.
+    fn should_collect(&mut self, bytes_allocated: usize) -> bool;
+
+    /// Called after each collection with the size of the objects that
+    /// survived it.
+    fn collected(&mut self, _bytes_allocated: usize) {}
+
+    /// How many objects to trace between instructions once a collection
+    /// starts, or `None` to trace them all before the allocation that started
+    /// it. Tracing a few at a time bounds how long the script pauses for.
+    fn mark_slice(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Collects whenever the heap has grown to twice the size it was after the
+/// last collection. The default.
+pub struct MarkSweep {
+    next_gc: usize,
+    min_threshold: usize,
+}
+
+impl MarkSweep {
+    /// Creates a collector that first collects once `threshold` bytes of
+    /// objects are allocated, and never collects a smaller heap.
+    pub fn new(threshold: usize) -> MarkSweep {
+        MarkSweep {
+            next_gc: threshold,
+            min_threshold: threshold,
+        }
+    }
+}
+
+impl Default for MarkSweep {
+    fn default() -> Self {
+        MarkSweep::new(1024 * 1024)
+    }
+}
+
+impl CollectorStrategy for MarkSweep {
+    fn should_collect(&mut self, bytes_allocated: usize) -> bool {
+        bytes_allocated > self.next_gc
+    }
+
+    fn collected(&mut self, bytes_allocated: usize) {
+        self.next_gc = (bytes_allocated * 2).max(self.min_threshold);
+    }
+}
+
+/// Starts collecting when `MarkSweep` would, but traces the heap a slice at a
+/// time between instructions, so no one pause grows with the size of the
+/// heap. While a collection is marking, every reference stored into an object
+/// is marked too, so the script can't hide an object from it by moving it
+/// into one already traced.
+pub struct Incremental {
+    mark_sweep: MarkSweep,
+    slice: usize,
+}
+
+impl Incremental {
+    /// Creates a collector that starts collecting as `MarkSweep::new` does,
+    /// tracing `slice` objects between each instruction.
+    pub fn new(threshold: usize, slice: usize) -> Incremental {
+        Incremental {
+            mark_sweep: MarkSweep::new(threshold),
+            slice: slice.max(1),
+        }
+    }
+}
+
+impl Default for Incremental {
+    fn default() -> Self {
+        Incremental {
+            mark_sweep: MarkSweep::default(),
+            slice: 100,
+        }
+    }
+}
+
+impl CollectorStrategy for Incremental {
+    fn should_collect(&mut self, bytes_allocated: usize) -> bool {
+        self.mark_sweep.should_collect(bytes_allocated)
+    }
+
+    fn collected(&mut self, bytes_allocated: usize) {
+        self.mark_sweep.collected(bytes_allocated)
+    }
+
+    fn mark_slice(&self) -> Option<usize> {
+        Some(self.slice)
+    }
+}
+
+/// Collects before every allocation, so that an object the VM forgets to
+/// root is freed, and its use caught, as soon as possible.
+pub struct Stress;
+
+impl CollectorStrategy for Stress {
+    fn should_collect(&mut self, _bytes_allocated: usize) -> bool {
+        true
+    }
+}
+
+/// Never collects, leaving every object alive until the allocator is
+/// dropped. Suits short-lived scripts, where collecting would only cost time.
+pub struct NoCollect;
+
+impl CollectorStrategy for NoCollect {
+    fn should_collect(&mut self, _bytes_allocated: usize) -> bool {
+        false
+    }
+}
@@ -1,42 +1,130 @@
-use crate::chunk::{Chunk, Opcode};
+use crate::chunk::{Chunk, LocalInfo, Opcode, Span};
 use crate::debug::disassemble_chunk;
-use crate::memory::{Allocator, GC};
-use crate::object_closure::Upvalue;
+use crate::error::{CompileError, CompileWarning};
+use crate::globals::Globals;
+use crate::memory::{Allocator, Gc, GC};
+use crate::natives;
+use crate::object_bytes::ObjBytes;
+use crate::object_closure::{ObjClosure, Upvalue};
 use crate::object_function::{FunctionType, ObjFunction};
 use crate::object_string::ObjString;
-use crate::scanner::{Scanner, Token, TokenType};
-use crate::value::Value;
-use std::alloc::Layout;
+use crate::peephole;
+use crate::scanner::{misspelled_keyword, ScanError, Scanner, Token, TokenType};
+use crate::value::{self, Value};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use tinyvec::ArrayVec;
 
 const MAX_LOCALS: usize = 256;
 
+/// Hard caps on how much work a single compilation may do, so pathological or
+/// hostile input becomes a compile error instead of exhausting memory.
+#[derive(Clone, Copy)]
+pub struct CompilerLimits {
+    /// Tokens scanned from the source
+    pub max_tokens: usize,
+    /// Constants added across every function's chunk
+    pub max_constants: usize,
+    /// Bytecode bytes emitted across every function's chunk
+    pub max_code_bytes: usize,
+}
+
+impl Default for CompilerLimits {
+    fn default() -> Self {
+        CompilerLimits {
+            max_tokens: 10_000_000,
+            max_constants: 1_000_000,
+            max_code_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
 pub struct Compiler<'a> {
     current: Token<'a>,
     previous: Token<'a>,
     scanner: Scanner<'a>,
-    had_error: bool,
+    errors: Vec<CompileError>,
     panic_mode: bool,
     compiler_states: Vec<CompilerState<'a>>,
     allocator: &'a mut Allocator,
-    debug_stress_gc: bool,
-    debug_log_gc: bool,
+    // Identifier strings interned across every compilation sharing this table
+    identifiers: &'a mut HashMap<String, Gc<ObjString>>,
+    limits: CompilerLimits,
+    // Whether chunks record local variable names and expression spans
+    debug_info: bool,
+    // Whether finished chunks go through the peephole pass
+    optimize: bool,
+    // Names defined at build time, substituted wherever they'd resolve as globals
+    defines: HashMap<String, Value>,
+    // How many times a define has been substituted, so a condition one made
+    // constant, as intended, isn't warned about
+    define_reads: usize,
+    // Pure builtins whose calls with literal arguments are evaluated here
+    foldable_natives: HashSet<String>,
+    // Where the latest global read was emitted, to recognize native calls.
+    // An offset in the current function's chunk, so cleared on entering or
+    // leaving a function
+    last_global_read: Option<usize>,
+    // The VM's globals, when compiling for it, so globals become slots
+    globals: Option<&'a mut Globals>,
+    token_count: usize,
+    constant_count: usize,
+    code_byte_count: usize,
+    // Set once any limit is hit; parsing then winds down as if at end of input
+    limit_exceeded: bool,
+    // Whether a trailing expression statement becomes the script's result
+    repl: bool,
+    returns_expression: bool,
+    warnings: Vec<CompileWarning>,
+    // Time spent in the scanner, when it's being measured
+    scan_time: Option<Duration>,
+}
+
+/// How compiled code refers to a global variable: by the slot it was
+/// resolved to, or by a constant holding its name.
+#[derive(Clone, Copy)]
+enum GlobalRef {
+    Slot(u16),
+    Name(u8),
 }
 
 pub struct CompilerState<'a> {
     locals: ArrayVec<[Local<'a>; MAX_LOCALS]>,
     upvalues: ArrayVec<[Upvalue; MAX_LOCALS]>,
     scope_depth: i32,
-    function: *mut ObjFunction,
+    function: Gc<ObjFunction>,
+    // How many loops enclose the code being compiled, within this function
+    loop_depth: usize,
+    // Constant-table slot already holding each value that can be shared
+    constant_indices: HashMap<ConstantKey, u8>,
+    // How many instructions refer to each constant, so discarding the last
+    // of them can free its slot
+    constant_uses: Vec<usize>,
+}
+
+/// What makes two constants interchangeable. Strings are immutable, so every
+/// literal with the same text can share one; interned strings are kept apart
+/// since globals are looked up by the interned pointer.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(String),
+    Identifier(String),
 }
 
 impl CompilerState<'_> {
-    pub fn new(function: *mut ObjFunction) -> CompilerState<'static> {
+    pub fn new(function: Gc<ObjFunction>) -> CompilerState<'static> {
         let mut locals = ArrayVec::new();
         let name_local = Local {
             name: None,
             is_captured: false,
             depth: 0,
+            debug_index: None,
+            closure: None,
+            escapes: false,
+            is_const: false,
+            used: false,
         };
         locals.push(name_local);
         CompilerState {
@@ -44,6 +132,9 @@ impl CompilerState<'_> {
             upvalues: ArrayVec::new(),
             scope_depth: 0,
             function,
+            loop_depth: 0,
+            constant_indices: HashMap::new(),
+            constant_uses: Vec::new(),
         }
     }
 
@@ -66,7 +157,7 @@ impl CompilerState<'_> {
     }
 
     pub fn add_upvalue(&mut self, index: u8, is_local: bool) -> usize {
-        let upvalue_count = unsafe { (*self.function).upvalue_count };
+        let upvalue_count = self.function.get().upvalue_count;
 
         // Check if we already have an upvalue pointing at this identifier
         for i in 0..upvalue_count {
@@ -79,9 +170,7 @@ impl CompilerState<'_> {
         // Add a new upvalue
         self.upvalues.push(Upvalue::new(index, is_local));
 
-        unsafe {
-            (*self.function).upvalue_count += 1;
-        }
+        self.function.get_mut().upvalue_count += 1;
         upvalue_count
     }
 }
@@ -91,6 +180,18 @@ pub struct Local<'a> {
     name: Option<Token<'a>>,
     is_captured: bool,
     depth: i32,
+    // Index of this local's entry in the chunk's local debug table
+    debug_index: Option<usize>,
+    // Where the `Closure` instruction creating the function this local was
+    // declared as starts, for binding it to the frame if it never escapes
+    closure: Option<usize>,
+    // Whether the local has been used other than as the callee of a call
+    escapes: bool,
+    // Whether the local was declared `const`, so can't be assigned to
+    is_const: bool,
+    // Whether the local is ever read, or captured by a closure that might
+    // read it; a local that isn't gets a warning
+    used: bool,
 }
 
 enum PrefixParserType {
@@ -99,14 +200,20 @@ enum PrefixParserType {
     Number,
     Literal,
     String,
+    Bytes,
     Variable,
+    List,
+    Map,
 }
 
 enum InfixParserType {
     Binary,
     And,
     Or,
+    Coalesce,
     Call,
+    Subscript,
+    Dot,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -114,13 +221,18 @@ enum InfixParserType {
 enum Precedence {
     None = 0,
     Assignment, // =
+    Coalesce,   // ??
     Or,         // or
     And,        // and
     Equality,   // == !=
     Comparison, // < > <= >=
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &
+    Shift,      // << >>
     Term,       // + -
     Factor,     // * /
-    Unary,      // ! -
+    Unary,      // ! - ~
     Call,       // . ()
     Primary,
 }
@@ -129,11 +241,16 @@ impl Precedence {
     fn next_level(self) -> Precedence {
         match self {
             Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
             Precedence::Unary => Precedence::Call,
@@ -147,22 +264,115 @@ impl<'a> Compiler<'a> {
     pub fn new(
         source: &'a str,
         allocator: &'a mut Allocator,
-        debug_stress_gc: bool,
-        debug_log_gc: bool,
+        identifiers: &'a mut HashMap<String, Gc<ObjString>>,
+        limits: CompilerLimits,
     ) -> Compiler<'a> {
-        let mut scanner = Scanner::new(source);
-        let starting_token = Compiler::advance_to_start(&mut scanner);
-        Compiler {
+        let starting_token = Token {
+            token_type: TokenType::Eof,
+            source: "",
+            file: None,
+            line: 1,
+            column: 1,
+        };
+        let mut compiler = Compiler {
             current: starting_token,
             previous: starting_token,
-            scanner,
-            had_error: false,
+            scanner: Scanner::new(source),
+            errors: Vec::new(),
             panic_mode: false,
             allocator,
+            identifiers,
             compiler_states: vec![],
-            debug_stress_gc,
-            debug_log_gc,
-        }
+            limits,
+            debug_info: true,
+            optimize: false,
+            defines: HashMap::new(),
+            define_reads: 0,
+            foldable_natives: HashSet::new(),
+            last_global_read: None,
+            globals: None,
+            token_count: 0,
+            constant_count: 0,
+            code_byte_count: 0,
+            limit_exceeded: false,
+            repl: false,
+            returns_expression: false,
+            warnings: Vec::new(),
+            scan_time: None,
+        };
+        compiler.advance();
+        compiler
+    }
+
+    /// Controls whether compiled chunks carry local variable names and source
+    /// spans. Without them bytecode is smaller, but runtime errors can't
+    /// quote source or suggest local names. On by default.
+    pub fn set_debug_info(&mut self, debug_info: bool) {
+        self.debug_info = debug_info;
+    }
+
+    /// Runs each compiled chunk through `peephole::optimize`. Off by default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Defines a compile-time constant. References to `name` that would
+    /// otherwise be globals compile to `value` directly, so branches on it can
+    /// be folded away.
+    pub fn define_constant(&mut self, name: &str, value: Value) {
+        self.defines.insert(name.to_owned(), value);
+    }
+
+    /// Lets calls to the named builtins be evaluated while compiling when
+    /// their arguments are all literals, as with `sqrt(4)`. Only builtins
+    /// listed in `natives::PURE` can be folded, and only names the caller
+    /// knows still refer to them belong here; names the source itself
+    /// declares or assigns to are never folded. None by default.
+    pub fn set_foldable_natives(&mut self, names: HashSet<String>) {
+        self.foldable_natives = names;
+    }
+
+    /// Resolves each global variable to its slot in `globals` as it's
+    /// compiled, so the code can only run on the VM those globals belong to.
+    /// Without this, globals are looked up by name when they're accessed.
+    pub fn set_globals(&mut self, globals: &'a mut Globals) {
+        self.globals = Some(globals);
+    }
+
+    /// Compiles for interactive use: if the source ends with an expression
+    /// statement, the script returns its value instead of discarding it, and
+    /// that statement's ';' may be left off. Off by default.
+    pub fn set_repl(&mut self, repl: bool) {
+        self.repl = repl;
+    }
+
+    /// Measures the time spent scanning, for `scan_time`. Off by default,
+    /// since timing every token slows scanning down.
+    pub fn set_time_scanning(&mut self, time_scanning: bool) {
+        self.scan_time = time_scanning.then_some(Duration::ZERO);
+    }
+
+    /// The time spent scanning so far, if `set_time_scanning` turned
+    /// measuring it on.
+    pub fn scan_time(&self) -> Option<Duration> {
+        self.scan_time
+    }
+
+    /// How many tokens have been read so far.
+    pub fn token_count(&self) -> usize {
+        self.token_count
+    }
+
+    /// Takes the warnings found so far. Unlike errors, these are returned
+    /// whether or not compiling succeeds.
+    pub fn take_warnings(&mut self) -> Vec<CompileWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Whether the compiled script returns the value of a trailing expression
+    /// statement, as enabled by `set_repl`.
+    pub fn returns_expression(&self) -> bool {
+        self.returns_expression
     }
 
     pub fn prepare(&mut self) {
@@ -172,26 +382,48 @@ impl<'a> Compiler<'a> {
 
     // Parsing
 
-    fn advance_to_start(scanner: &mut Scanner<'a>) -> Token<'a> {
-        loop {
-            let result = scanner.scan_token();
-            match result {
-                Ok(token) => return token,
-                Err(err) => eprintln!("Error at first token: {err}"),
-            }
-        }
-    }
-
     fn advance(&mut self) {
         self.previous = self.current;
+        if self.limit_exceeded {
+            self.current = Token {
+                token_type: TokenType::Eof,
+                source: "",
+                file: self.previous.file,
+                line: self.previous.line,
+                column: self.previous.column,
+            };
+            return;
+        }
+        self.token_count += 1;
+        if self.token_count > self.limits.max_tokens {
+            self.limit_error("Too many tokens in source.");
+        }
         loop {
+            let start = self.scan_time.map(|_| Instant::now());
             let result = self.scanner.scan_token();
+            if let (Some(scan_time), Some(start)) = (&mut self.scan_time, start) {
+                *scan_time += start.elapsed();
+            }
             match result {
                 Ok(token) => {
                     self.current = token;
                     return;
                 }
-                Err(err) => self.error_at_current(err.to_string().as_ref()),
+                Err(err) => {
+                    // Point at the unexpected character; the other scan
+                    // errors run into the end of the source
+                    let token = Token {
+                        token_type: match err {
+                            ScanError::UnexpectedCharacter => TokenType::Identifier,
+                            _ => TokenType::Eof,
+                        },
+                        source: &self.scanner.source[self.scanner.start..self.scanner.current],
+                        file: self.scanner.file,
+                        line: self.scanner.line,
+                        column: self.scanner.column(),
+                    };
+                    self.error_at(token, err.to_string().as_ref());
+                }
             }
         }
     }
@@ -200,7 +432,7 @@ impl<'a> Compiler<'a> {
         if self.current.token_type == token_type {
             return self.advance();
         }
-        self.error_at_current(message)
+        self.error_expecting(self.current, message, token_type.describe());
     }
 
     fn match_token(&mut self, token_type: TokenType) -> bool {
@@ -224,20 +456,82 @@ impl<'a> Compiler<'a> {
     }
 
     fn error_at(&mut self, token: Token, message: &str) {
-        if self.panic_mode {
+        self.report_error(token, message, None);
+    }
+
+    /// Reports an error at `token` where the parser was waiting for
+    /// `expected`, as described by `TokenType::describe`.
+    fn error_expecting(&mut self, token: Token, message: &str, expected: String) {
+        self.report_error(token, message, Some(expected));
+    }
+
+    fn report_error(&mut self, token: Token, message: &str, expected: Option<String>) {
+        if self.panic_mode || self.limit_exceeded {
             return;
         }
 
-        eprint!("[line {}] Error", token.line);
-        match token.token_type {
-            TokenType::Eof => eprint!(" at end"),
-            _ => eprint!(" at '{}'", token.source),
-        }
-        eprintln!(": {message}");
-        self.had_error = true;
+        self.errors.push(CompileError {
+            file: token.file.map(str::to_owned),
+            line: token.line,
+            column: token.column,
+            lexeme: match token.token_type {
+                TokenType::Eof => String::new(),
+                _ => token.source.to_owned(),
+            },
+            message: message.to_owned(),
+            expected,
+        });
         self.panic_mode = true;
     }
 
+    fn warning_at_current(&mut self, message: &str) {
+        self.warning_at(self.current, message)
+    }
+
+    fn warning_at(&mut self, token: Token, message: &str) {
+        if self.panic_mode || self.limit_exceeded {
+            return;
+        }
+        self.warnings.push(CompileWarning {
+            file: token.file.map(str::to_owned),
+            line: token.line,
+            column: token.column,
+            lexeme: token.source.to_owned(),
+            message: message.to_owned(),
+        });
+    }
+
+    /// Warns about each local deeper than `depth`, about to go out of scope,
+    /// that was never used. A name starting with `_` marks a local as unused
+    /// on purpose.
+    fn warn_unused_locals(&mut self, depth: i32) {
+        let unused: Vec<Token> = self
+            .current_compiler_state()
+            .locals
+            .iter()
+            .filter(|local| local.depth > depth && !local.used && !local.is_captured)
+            .filter_map(|local| local.name)
+            .filter(|name| !name.source.starts_with('_'))
+            .collect();
+        for name in unused {
+            let message = format!(
+                "Unused variable '{}'; prefix it with '_' if that's intended.",
+                name.source
+            );
+            self.warning_at(name, &message);
+        }
+    }
+
+    /// Reports a hit compiler limit, even mid-recovery, and stops parsing.
+    fn limit_error(&mut self, message: &str) {
+        if self.limit_exceeded {
+            return;
+        }
+        self.panic_mode = false;
+        self.error_at_current(message);
+        self.limit_exceeded = true;
+    }
+
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
@@ -247,6 +541,8 @@ impl<'a> Compiler<'a> {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -258,47 +554,80 @@ impl<'a> Compiler<'a> {
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
+        let closure = self.current_chunk().code.len();
         self.function();
+        if self.current_compiler_state().scope_depth > 0 {
+            self.current_compiler_state_mut()
+                .locals
+                .last_mut()
+                .unwrap()
+                .closure = Some(closure);
+        }
         self.define_variable(global);
     }
 
     fn function(&mut self) {
         // Allocate the ObjFunction
         let function = self.heap_alloc(ObjFunction::new(FunctionType::Function, None));
-        unsafe {
-            (*function).name = Some(ObjString::new(self.previous.source));
-        }
+        function.get_mut().name = Some(ObjString::new(self.previous.source));
 
         // Push a new function scope
         let compiler_state = CompilerState::new(function);
         self.compiler_states.push(compiler_state);
+        self.last_global_read = None;
         self.current_compiler_state_mut().begin_scope();
 
         // Parse parameters
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
         if !self.check(TokenType::RightParen) {
             loop {
-                unsafe {
-                    (*(self.current_compiler_state_mut().function)).arity += 1;
-                    let constant = self.parse_variable("Expect parameter name.");
-                    self.define_variable(constant);
-                    if !self.match_token(TokenType::Comma) {
-                        break;
-                    }
+                self.current_compiler_state_mut().function.get_mut().arity += 1;
+                let constant = self.parse_variable("Expect parameter name.");
+                // A default value is computed in place, leaving it in the
+                // parameter's slot, by code a call starts at when it leaves
+                // the parameter out; see `Chunk::entry_points`
+                if self.match_token(TokenType::Equal) {
+                    let start = self.current_chunk().code.len();
+                    self.current_chunk().entry_points.push(start);
+                    self.expression();
+                } else if !self.current_chunk().entry_points.is_empty() {
+                    self.error("Expect default value after a parameter with one.");
+                }
+                self.define_variable(constant);
+                // Callbacks are often passed more arguments than they need,
+                // so unused parameters aren't warned about
+                if let Some(parameter) = self.current_compiler_state_mut().locals.last_mut() {
+                    parameter.used = true;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
                 }
             }
         }
+        if !self.current_chunk().entry_points.is_empty() {
+            let body = self.current_chunk().code.len();
+            self.current_chunk().entry_points.push(body);
+        }
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
 
         // Parse function body
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         self.block();
+        self.warn_unused_locals(0);
 
         // Grab the upvalues before we end this function compiler scope
         let upvalues = self.current_compiler_state_mut().upvalues;
 
         // Output the closure + upvalue opcodes
         let function = self.end_compiler(false);
+        // A function declared in a loop that captures no variables gives the
+        // same closure every iteration, so it's created once, here, rather
+        // than allocated on every pass
+        if self.current_compiler_state().loop_depth > 0 && upvalues.is_empty() {
+            let closure = self.heap_alloc(ObjClosure::new(function));
+            self.emit_constant(Value::ObjClosure(closure));
+            return;
+        }
         let constant = self.make_constant(Value::ObjFunction(function));
         self.emit_bytes(Opcode::Closure as u8, constant);
         for upvalue in upvalues {
@@ -309,7 +638,10 @@ impl<'a> Compiler<'a> {
 
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
+        self.var_initializer(global);
+    }
 
+    fn var_initializer(&mut self, global: GlobalRef) {
         if self.match_token(TokenType::Equal) {
             self.expression();
         } else {
@@ -323,15 +655,47 @@ impl<'a> Compiler<'a> {
         self.define_variable(global);
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    /// A `const` declaration is a `var` declaration that must have an
+    /// initializer and can't be assigned to afterwards: a local's assignments
+    /// are rejected here, and a global's by the VM, since another script may
+    /// assign to it.
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+
+        if self.current_compiler_state().scope_depth > 0 {
+            self.mark_initialized();
+            self.current_compiler_state_mut()
+                .locals
+                .last_mut()
+                .unwrap()
+                .is_const = true;
+            return;
+        }
+        self.emit_global(
+            Opcode::DefineGlobalConst,
+            Opcode::DefineGlobalConstSlot,
+            global,
+        );
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> GlobalRef {
         self.consume(TokenType::Identifier, error_message);
         self.declare_variable();
         if self.current_compiler_state().scope_depth > 0 {
-            // We're handling a local; don't load the identifier into the
-            // constant table and return a dummy location
-            return 0;
+            // We're handling a local; don't resolve the identifier as a
+            // global and return a dummy location
+            return GlobalRef::Name(0);
         }
-        self.identifier_constant(self.previous.source)
+        if self.defines.contains_key(self.previous.source) {
+            self.error("Can't redeclare a compile-time constant.");
+        }
+        self.global_ref(self.previous.source)
     }
 
     fn declare_variable(&mut self) {
@@ -360,21 +724,66 @@ impl<'a> Compiler<'a> {
             name: Some(name),
             is_captured: false,
             depth: -1,
+            debug_index: None,
+            closure: None,
+            escapes: false,
+            is_const: false,
+            used: false,
         });
     }
 
-    fn identifier_constant(&mut self, name: &str) -> u8 {
-        let obj_str = self.heap_alloc(ObjString::new(name));
+    fn intern(&mut self, name: &str) -> Gc<ObjString> {
+        match self.identifiers.get(name) {
+            Some(&obj_str) => obj_str,
+            None => {
+                let obj_str = self.heap_alloc(ObjString::new(name));
+                self.identifiers.insert(name.to_owned(), obj_str);
+                obj_str
+            }
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &'a str) -> u8 {
+        if let Some(constant) = self.reuse_constant(&ConstantKey::Identifier(name.to_owned())) {
+            return constant;
+        }
+        let obj_str = self.intern(name);
         self.make_constant(Value::ObjString(obj_str))
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn global_ref(&mut self, name: &'a str) -> GlobalRef {
+        if self.globals.is_none() {
+            return GlobalRef::Name(self.identifier_constant(name));
+        }
+        let obj_str = self.intern(name);
+        let slot = self.globals.as_mut().unwrap().slot(obj_str);
+        match u16::try_from(slot) {
+            Ok(slot) => GlobalRef::Slot(slot),
+            Err(_) => {
+                self.error("Too many global variables.");
+                GlobalRef::Slot(0)
+            }
+        }
+    }
+
+    /// Emits `name_op` or `slot_op`, whichever `global` calls for.
+    fn emit_global(&mut self, name_op: Opcode, slot_op: Opcode, global: GlobalRef) {
+        match global {
+            GlobalRef::Name(constant) => self.emit_bytes(name_op as u8, constant),
+            GlobalRef::Slot(slot) => {
+                self.emit_byte(slot_op as u8);
+                self.emit_bytes((slot >> 8) as u8, slot as u8);
+            }
+        }
+    }
+
+    fn define_variable(&mut self, global: GlobalRef) {
         if self.current_compiler_state().scope_depth > 0 {
             self.mark_initialized();
             // We're handling a local; don't emit `DefineGlobal`
             return;
         }
-        self.emit_bytes(Opcode::DefineGlobal as u8, global);
+        self.emit_global(Opcode::DefineGlobal, Opcode::DefineGlobalSlot, global);
     }
 
     fn mark_initialized(&mut self) {
@@ -382,8 +791,29 @@ impl<'a> Compiler<'a> {
             return;
         }
         let slot = self.current_compiler_state().locals.len() - 1;
+        self.mark_local_initialized(slot);
+    }
+
+    fn mark_local_initialized(&mut self, slot: usize) {
         self.current_compiler_state_mut().locals[slot].depth =
             self.current_compiler_state().scope_depth;
+
+        // Start the local's debug range the first time it's initialized
+        if !self.debug_info {
+            return;
+        }
+        let local = &self.current_compiler_state().locals[slot];
+        if let (None, Some(name)) = (local.debug_index, local.name) {
+            let start = self.current_chunk().code.len();
+            let debug_index = self.current_chunk().locals.len();
+            self.current_chunk().locals.push(LocalInfo {
+                name: name.source.to_string(),
+                slot,
+                start,
+                end: usize::MAX,
+            });
+            self.current_compiler_state_mut().locals[slot].debug_index = Some(debug_index);
+        }
     }
 
     fn statement(&mut self) {
@@ -397,6 +827,10 @@ impl<'a> Compiler<'a> {
             self.return_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.current_compiler_state_mut().begin_scope();
             self.block();
@@ -419,7 +853,12 @@ impl<'a> Compiler<'a> {
         if self.match_token(TokenType::Semicolon) {
             // No initializer!
         } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
+            let global = self.parse_variable("Expect variable name.");
+            if self.match_token(TokenType::In) {
+                self.for_in_statement();
+                return;
+            }
+            self.var_initializer(global);
         } else {
             self.expression_statement();
         }
@@ -428,8 +867,10 @@ impl<'a> Compiler<'a> {
         let mut loop_start = self.current_chunk().code.len();
         let mut exit_jump = None;
         if !self.match_token(TokenType::Semicolon) {
+            let (condition, define_reads) = (self.current, self.define_reads);
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            self.warn_constant_condition(condition, loop_start, define_reads, true);
             exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse));
             self.emit_byte(Opcode::Pop as u8);
         }
@@ -447,7 +888,7 @@ impl<'a> Compiler<'a> {
             self.patch_jump(body_jump);
         }
 
-        self.statement();
+        self.loop_body();
         self.emit_loop(loop_start);
 
         if let Some(exit_jump) = exit_jump {
@@ -458,10 +899,85 @@ impl<'a> Compiler<'a> {
         self.end_scope();
     }
 
+    /// Compiles the rest of `for (var x in collection) body` once `in` has
+    /// been consumed. The loop variable is followed on the stack by two
+    /// hidden locals, the collection and a cursor into it, which
+    /// `IterNext` advances on every pass.
+    fn for_in_statement(&mut self) {
+        let variable_slot = self.current_compiler_state().locals.len() - 1;
+        // A loop that only counts its iterations never reads the variable,
+        // so it isn't warned about
+        self.current_compiler_state_mut().locals[variable_slot].used = true;
+        self.emit_byte(Opcode::Nil as u8);
+
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+        self.add_hidden_local();
+        self.emit_constant(Value::Number(0.0));
+        self.add_hidden_local();
+        self.mark_local_initialized(variable_slot);
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_bytes(Opcode::IterNext as u8, variable_slot as u8);
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_byte(Opcode::Pop as u8);
+
+        self.loop_body();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(Opcode::Pop as u8);
+
+        self.end_scope();
+    }
+
+    /// Reserves a stack slot for a compiler-generated local that user code
+    /// can't name.
+    fn add_hidden_local(&mut self) {
+        if self.current_compiler_state().locals.len() >= MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        let depth = self.current_compiler_state().scope_depth;
+        self.current_compiler_state_mut().locals.push(Local {
+            name: None,
+            is_captured: false,
+            depth,
+            debug_index: None,
+            closure: None,
+            escapes: false,
+            is_const: false,
+            used: false,
+        });
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition_start = self.current_chunk().code.len();
+        let (condition, define_reads) = (self.current, self.define_reads);
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.warn_constant_condition(condition, condition_start, define_reads, false);
+
+        // A constant condition picks its branch at compile time. The other
+        // branch is still compiled, so it's checked for errors, then dropped.
+        if let Some(condition) = self.literal_value(condition_start) {
+            let is_truthy = !condition.is_falsey();
+            self.discard_literal(condition_start);
+            let then_start = self.current_chunk().code.len();
+            self.statement();
+            if !is_truthy {
+                self.discard_code(then_start);
+            }
+            if self.match_token(TokenType::Else) {
+                let else_start = self.current_chunk().code.len();
+                self.statement();
+                if is_truthy {
+                    self.discard_code(else_start);
+                }
+            }
+            return;
+        }
 
         let then_jump = self.emit_jump(Opcode::JumpIfFalse);
         self.emit_byte(Opcode::Pop as u8);
@@ -475,10 +991,84 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump);
     }
 
+    /// Warns if the condition compiled since `start`, which begins at
+    /// `token`, is a literal, unless a define read after `define_reads` made
+    /// it one. A loop meant to run until it returns is written
+    /// `while (true)`, so a loop condition is only warned about if it's
+    /// always false.
+    fn warn_constant_condition(
+        &mut self,
+        token: Token<'a>,
+        start: usize,
+        define_reads: usize,
+        is_loop: bool,
+    ) {
+        if self.define_reads != define_reads {
+            return;
+        }
+        match self.literal_value(start) {
+            Some(value) if value.is_falsey() => {
+                self.warning_at(token, "Condition is always false.")
+            }
+            Some(_) if !is_loop => self.warning_at(token, "Condition is always true."),
+            _ => (),
+        }
+    }
+
+    /// If the code emitted since `start` is a single literal, returns its
+    /// value.
+    fn literal_value(&mut self, start: usize) -> Option<Value> {
+        let end = self.current_chunk().code.len();
+        self.literal_between(start, end)
+    }
+
+    /// If the code from `start` up to `end` is a single literal, returns its
+    /// value.
+    fn literal_between(&mut self, start: usize, end: usize) -> Option<Value> {
+        let chunk = self.current_chunk();
+        if start >= end || start + chunk.instruction_len(start) != end {
+            return None;
+        }
+        match Opcode::try_from(chunk.code[start]) {
+            Ok(Opcode::True) => Some(Value::Bool(true)),
+            Ok(Opcode::False) => Some(Value::Bool(false)),
+            Ok(Opcode::Nil) => Some(Value::Nil),
+            Ok(Opcode::Constant) => Some(chunk.constants[chunk.code[start + 1] as usize].clone()),
+            _ => None,
+        }
+    }
+
+    /// Drops the single literal emitted at `start`, found by `literal_value`,
+    /// releasing its constant so folding it away can free the slot.
+    fn discard_literal(&mut self, start: usize) {
+        let chunk = self.current_chunk();
+        if chunk.code[start] == Opcode::Constant as u8 {
+            let constant = chunk.code[start + 1] as usize;
+            self.release_constant(constant);
+        }
+        self.discard_code(start);
+    }
+
+    /// Drops the bytecode emitted since `start`, along with its debug info.
+    fn discard_code(&mut self, start: usize) {
+        let chunk = self.current_chunk();
+        chunk.code.truncate(start);
+        chunk.lines.truncate(start);
+        chunk.columns.truncate(start);
+        chunk.spans.retain(|(offset, _)| *offset < start);
+        chunk.files.retain(|(offset, _)| *offset < start);
+        // Locals declared in the dropped code are the last ones recorded
+        while matches!(chunk.locals.last(), Some(local) if local.start > start) {
+            chunk.locals.pop();
+        }
+        // Later code could be emitted over the read's offset
+        if self.last_global_read.is_some_and(|offset| offset >= start) {
+            self.last_global_read = None;
+        }
+    }
+
     fn return_statement(&mut self) {
-        if let FunctionType::Script =
-            unsafe { (*self.current_compiler_state().function).function_type }
-        {
+        if let FunctionType::Script = self.current_compiler_state().function.get().function_type {
             self.error("Can't return from top-level code.");
             return;
         }
@@ -491,21 +1081,70 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(Opcode::Throw as u8);
+    }
+
+    fn try_statement(&mut self) {
+        // While the try block runs, a handler points the VM at the catch block
+        let handler_jump = self.emit_jump(Opcode::PushHandler);
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.current_compiler_state_mut().begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_byte(Opcode::PopHandler as u8);
+        let exit_jump = self.emit_jump(Opcode::Jump);
+
+        // The VM unwinds the stack to where it was at `try` and pushes the
+        // exception, which becomes the catch block's variable
+        self.patch_jump(handler_jump);
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.current_compiler_state_mut().begin_scope();
+        self.consume(TokenType::Identifier, "Expect exception variable name.");
+        self.declare_variable();
+        self.mark_initialized();
+        // The syntax needs a name even when the handler ignores what was
+        // thrown, so an unused one isn't warned about
+        if let Some(exception) = self.current_compiler_state_mut().locals.last_mut() {
+            exception.used = true;
+        }
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after exception variable.",
+        );
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch block.");
+        self.block();
+        self.end_scope();
+
+        self.patch_jump(exit_jump);
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let (condition, define_reads) = (self.current, self.define_reads);
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.warn_constant_condition(condition, loop_start, define_reads, true);
 
         let exit_jump = self.emit_jump(Opcode::JumpIfFalse);
         self.emit_byte(Opcode::Pop as u8);
-        self.statement();
+        self.loop_body();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(Opcode::Pop as u8);
     }
 
+    fn loop_body(&mut self) {
+        self.current_compiler_state_mut().loop_depth += 1;
+        self.statement();
+        self.current_compiler_state_mut().loop_depth -= 1;
+    }
+
     fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(Opcode::Loop as u8);
         let offset = self.current_chunk().code.len() - loop_start + 2;
@@ -541,7 +1180,15 @@ impl<'a> Compiler<'a> {
     }
 
     fn block(&mut self) {
+        let mut exited = false;
+        let mut warned = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            // Only the first unreachable statement is worth pointing out
+            if exited && !warned {
+                self.warning_at_current("Unreachable code.");
+                warned = true;
+            }
+            exited |= self.check(TokenType::Return) || self.check(TokenType::Throw);
             self.declaration();
         }
 
@@ -550,23 +1197,125 @@ impl<'a> Compiler<'a> {
 
     fn end_scope(&mut self) {
         self.current_compiler_state_mut().scope_depth -= 1;
+        self.warn_unused_locals(self.current_compiler_state().scope_depth);
 
         // Emit instructions to pop from the stack everything now out of scope
         for i in (0..(self.current_compiler_state().locals.len())).rev() {
             let local = &self.current_compiler_state().locals[i];
             if local.depth > self.current_compiler_state().scope_depth {
-                self.emit_byte(if local.is_captured {
+                let debug_index = local.debug_index;
+                let is_captured = local.is_captured;
+                self.bind_to_frame(i);
+                self.emit_byte(if is_captured {
                     Opcode::CloseUpvalue as u8
                 } else {
                     Opcode::Pop as u8
                 });
+                if let Some(debug_index) = debug_index {
+                    let end = self.current_chunk().code.len();
+                    self.current_chunk().locals[debug_index].end = end;
+                }
                 self.current_compiler_state_mut().locals.pop();
             }
         }
     }
 
+    /// If the local in `slot` was declared as a function that captures only
+    /// this frame's variables and is never used except by calling it
+    /// directly, so it can't outlive this frame and only runs as its callee,
+    /// makes the function read its captures from this frame's stack instead
+    /// of through upvalues. See `ObjFunction::frame_bound`.
+    fn bind_to_frame(&mut self, slot: usize) {
+        let local = &self.current_compiler_state().locals[slot];
+        let (Some(offset), false, false) = (local.closure, local.escapes, local.is_captured) else {
+            return;
+        };
+        let chunk = self.current_chunk();
+        if chunk.code.get(offset) != Some(&(Opcode::Closure as u8)) {
+            return;
+        }
+        let Value::ObjFunction(function) = chunk.constants[chunk.code[offset + 1] as usize] else {
+            return;
+        };
+        let function = function.get_mut();
+        let captures = &chunk.code[offset + 2..offset + 2 + function.upvalue_count * 2];
+        // Each capture's slot in this frame, if they're all this frame's locals
+        let Some(slots) = captures
+            .chunks(2)
+            .map(|pair| (pair[0] == 1).then_some(pair[1]))
+            .collect::<Option<Vec<u8>>>()
+        else {
+            return;
+        };
+        if slots.is_empty() {
+            return;
+        }
+
+        // Closures the function creates can't take its upvalues, since it
+        // won't have any
+        let chunk = &mut function.chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let len = chunk.instruction_len(offset);
+            if chunk.code[offset] == Opcode::Closure as u8
+                && chunk.code[offset + 2..offset + len]
+                    .chunks(2)
+                    .any(|pair| pair[0] == 0)
+            {
+                return;
+            }
+            offset += len;
+        }
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let replacement = match Opcode::try_from(chunk.code[offset]) {
+                Ok(Opcode::GetUpvalue) => Some(Opcode::GetCallerLocal),
+                Ok(Opcode::SetUpvalue) => Some(Opcode::SetCallerLocal),
+                _ => None,
+            };
+            if let Some(replacement) = replacement {
+                chunk.code[offset] = replacement as u8;
+                chunk.code[offset + 1] = slots[chunk.code[offset + 1] as usize];
+            }
+            offset += chunk.instruction_len(offset);
+        }
+        function.frame_bound = true;
+    }
+
     fn expression_statement(&mut self) {
+        let start = self.current;
         self.expression();
+
+        if self.repl && self.compiler_states.len() == 1 {
+            let at_top_level = self.current_compiler_state().scope_depth == 0;
+            if at_top_level && (self.check(TokenType::Eof) || self.check(TokenType::Semicolon)) {
+                self.match_token(TokenType::Semicolon);
+                if self.check(TokenType::Eof) {
+                    self.emit_byte(Opcode::Return as u8);
+                    self.returns_expression = true;
+                } else {
+                    self.emit_byte(Opcode::Pop as u8);
+                }
+                return;
+            }
+        }
+
+        // An identifier expression that isn't followed by ';' is often a typo'd keyword
+        if !self.check(TokenType::Semicolon) && start.token_type == TokenType::Identifier {
+            if let Some(keyword) = misspelled_keyword(start.source) {
+                self.error_at(
+                    start,
+                    format!(
+                        "Unknown statement '{}'. Did you mean '{keyword}'?",
+                        start.source
+                    )
+                    .as_str(),
+                );
+                self.skip_statement();
+                return;
+            }
+        }
+
         self.consume(
             TokenType::Semicolon,
             "Expect ';' after expression statement expression.",
@@ -574,6 +1323,32 @@ impl<'a> Compiler<'a> {
         self.emit_byte(Opcode::Pop as u8);
     }
 
+    /// Skips past the end of a statement we couldn't make sense of, including
+    /// any braced body, so that a single typo doesn't produce a cascade of errors.
+    fn skip_statement(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.current.token_type {
+                TokenType::Eof => break,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth <= 1 => {
+                    if depth == 1 {
+                        self.advance();
+                    }
+                    break;
+                }
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Semicolon if depth == 0 => {
+                    self.advance();
+                    break;
+                }
+                _ => (),
+            }
+            self.advance();
+        }
+        self.panic_mode = false;
+    }
+
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
@@ -585,11 +1360,14 @@ impl<'a> Compiler<'a> {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return,
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try => return,
                 _ => (),
             }
             self.advance();
@@ -613,16 +1391,26 @@ impl<'a> Compiler<'a> {
     fn string(&mut self) {
         // Trim the leading and trailing quotes
         let string = &self.previous.source[1..self.previous.source.len() - 1];
-        let obj = ObjString::new(string);
-        let layout = Layout::new::<ObjString>();
-        unsafe {
-            // This will never be garbage collected, but that's okay, because it's a constant
-            let ptr = std::alloc::alloc(layout) as *mut ObjString;
-            if ptr.is_null() {
-                std::alloc::handle_alloc_error(layout);
+        if let Some(constant) = self.reuse_constant(&ConstantKey::String(string.to_owned())) {
+            self.emit_bytes(Opcode::Constant as u8, constant);
+            return;
+        }
+        // Kept alive by the constant pool of the function that uses it
+        let obj_str = self.heap_alloc(ObjString::new(string));
+        self.emit_constant(Value::ObjString(obj_str));
+    }
+
+    fn bytes(&mut self) {
+        // Trim the leading `b"` and the trailing quote
+        let literal = &self.previous.source[2..self.previous.source.len() - 1];
+        match ObjBytes::parse_literal(literal) {
+            Ok(bytes) => {
+                // Scripts can change bytes, so the constant is only a template
+                let obj_bytes = self.heap_alloc(ObjBytes::new(bytes));
+                let constant = self.make_constant(Value::ObjBytes(obj_bytes));
+                self.emit_bytes(Opcode::CopyConstant as u8, constant);
             }
-            *ptr = obj;
-            self.emit_constant(Value::ObjString(ptr));
+            Err(message) => self.error(&message),
         }
     }
 
@@ -630,7 +1418,7 @@ impl<'a> Compiler<'a> {
         self.named_variable(self.previous, can_assign);
     }
 
-    fn named_variable(&mut self, name: Token, can_assign: bool) {
+    fn named_variable(&mut self, name: Token<'a>, can_assign: bool) {
         // Attempt to resolve as a local
         let arg = match self.current_compiler_state().resolve_local(name) {
             Ok(arg) => arg,
@@ -640,32 +1428,55 @@ impl<'a> Compiler<'a> {
             }
         };
 
-        let (set_op, get_op, arg) = match arg {
-            Some(arg) => (Opcode::SetLocal, Opcode::GetLocal, arg as u8),
+        let (set_op, get_op, arg, is_const) = match arg {
+            Some(arg) => {
+                // A local function stays bound to this frame only as long as
+                // every reference to it calls it
+                if !self.check(TokenType::LeftParen) {
+                    self.current_compiler_state_mut().locals[arg].escapes = true;
+                }
+                if !(can_assign && self.check(TokenType::Equal)) {
+                    self.current_compiler_state_mut().locals[arg].used = true;
+                }
+                let is_const = self.current_compiler_state().locals[arg].is_const;
+                (Opcode::SetLocal, Opcode::GetLocal, arg as u8, is_const)
+            }
             None => {
                 // Attempt to resolve as an upvalue
                 match self.resolve_upvalue(self.compiler_states.len() - 1, name) {
                     Ok(arg) => match arg {
-                        Some(arg) => (Opcode::SetUpvalue, Opcode::GetUpvalue, arg as u8),
-                        // If not local or upvalue, assume the identifier is a global
-                        None => (
-                            Opcode::SetGlobal,
-                            Opcode::GetGlobal,
-                            self.identifier_constant(name.source),
+                        Some(arg) => (
+                            Opcode::SetUpvalue,
+                            Opcode::GetUpvalue,
+                            arg as u8,
+                            self.captures_const(name),
                         ),
+                        // Compile-time constants take the place of globals
+                        None if self.defines.contains_key(name.source) => {
+                            self.define_reference(name.source, can_assign);
+                            return;
+                        }
+                        // If not local or upvalue, assume the identifier is a global
+                        None => {
+                            let global = self.global_ref(name.source);
+                            self.global_variable(global, can_assign);
+                            return;
+                        }
                     },
                     Err(err) => {
                         self.error(err.as_str());
-                        (
-                            Opcode::SetGlobal,
-                            Opcode::GetGlobal,
-                            self.identifier_constant(name.source),
-                        )
+                        let global = self.global_ref(name.source);
+                        self.global_variable(global, can_assign);
+                        return;
                     }
                 }
             }
         };
 
+        if is_const && can_assign && self.check(TokenType::Equal) {
+            self.error_at_current("Can't assign to a constant.");
+            return;
+        }
         if self.match_token(TokenType::Equal) && can_assign {
             self.expression();
             self.emit_bytes(set_op as u8, arg);
@@ -674,6 +1485,38 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn global_variable(&mut self, global: GlobalRef, can_assign: bool) {
+        if self.match_token(TokenType::Equal) && can_assign {
+            self.expression();
+            self.emit_global(Opcode::SetGlobal, Opcode::SetGlobalSlot, global);
+        } else {
+            self.last_global_read = Some(self.current_chunk().code.len());
+            self.emit_global(Opcode::GetGlobal, Opcode::GetGlobalSlot, global);
+        }
+    }
+
+    fn define_reference(&mut self, name: &str, can_assign: bool) {
+        if can_assign && self.check(TokenType::Equal) {
+            self.error_at_current("Can't assign to a compile-time constant.");
+            return;
+        }
+        self.define_reads += 1;
+        self.emit_value(self.defines[name].clone());
+    }
+
+    /// Whether `name`, which resolved to an upvalue, captures a local
+    /// declared `const`.
+    fn captures_const(&self, name: Token) -> bool {
+        self.compiler_states[..self.compiler_states.len() - 1]
+            .iter()
+            .rev()
+            .find_map(|state| {
+                let local = state.resolve_local(name).ok()??;
+                Some(state.locals[local].is_const)
+            })
+            .unwrap_or(false)
+    }
+
     fn resolve_upvalue(
         &mut self,
         compiler_state_index: usize,
@@ -717,18 +1560,57 @@ impl<'a> Compiler<'a> {
     }
 
     fn unary(&mut self) {
-        let operator_type = self.previous.token_type;
+        let operator = self.previous;
+        let operand_start = self.current_chunk().code.len();
         self.parse_precedence(Precedence::Unary);
-        match operator_type {
+
+        // `!` on a literal folds to the opposite boolean, and `-` and `~` on
+        // a number to its negation and complement
+        let folded = match (operator.token_type, self.literal_value(operand_start)) {
+            (TokenType::Bang, Some(operand)) => Some(Value::Bool(operand.is_falsey())),
+            (TokenType::Minus, Some(Value::Number(operand))) => Some(Value::Number(-operand)),
+            (TokenType::Tilde, Some(Value::Number(operand))) => {
+                Some(Value::Number(!value::to_int32(operand) as f64))
+            }
+            _ => None,
+        };
+        if let Some(value) = folded {
+            self.discard_literal(operand_start);
+            self.emit_value(value);
+            return;
+        }
+
+        self.record_span(operator);
+        match operator.token_type {
             TokenType::Minus => self.emit_byte(Opcode::Negate as u8),
             TokenType::Bang => self.emit_byte(Opcode::Not as u8),
+            TokenType::Tilde => self.emit_byte(Opcode::BitNot as u8),
             _ => self.error("Expect unary operator."),
         }
     }
 
-    fn binary(&mut self) {
+    /// Compiles the right operand of a binary operator whose left operand's
+    /// code begins at `left_start`.
+    fn binary(&mut self, start: Token<'a>, left_start: usize) {
         let operator_type = self.previous.token_type;
+        let right_start = self.current_chunk().code.len();
         self.parse_precedence(operator_type.precedence().next_level());
+
+        let left = self.literal_between(left_start, right_start);
+        if let (Some(left), Some(right)) = (left, self.literal_value(right_start)) {
+            if let Some(value) = Compiler::fold_binary(operator_type, &left, &right) {
+                self.discard_literal(right_start);
+                self.discard_literal(left_start);
+                self.emit_value(value);
+                return;
+            }
+        }
+
+        self.record_span(start);
+        if let Some(opcode) = operator_type.bitwise_opcode() {
+            self.emit_byte(opcode as u8);
+            return;
+        }
         match operator_type {
             TokenType::Plus => self.emit_byte(Opcode::Add as u8),
             TokenType::Minus => self.emit_byte(Opcode::Subtract as u8),
@@ -744,29 +1626,270 @@ impl<'a> Compiler<'a> {
         }
     }
 
-    fn and(&mut self) {
+    /// The value of a binary operator applied to two literals, where that
+    /// can't fail at runtime: arithmetic, bitwise operations and comparison
+    /// of numbers, and equality of numbers, booleans, and nil. Strings aren't
+    /// folded.
+    fn fold_binary(operator_type: TokenType, left: &Value, right: &Value) -> Option<Value> {
+        if let (Value::Number(a), Value::Number(b)) = (left, right) {
+            let (a, b) = (*a, *b);
+            if let Some(opcode) = operator_type.bitwise_opcode() {
+                return Some(Value::Number(value::bitwise(opcode, a, b)));
+            }
+            return Some(match operator_type {
+                TokenType::Plus => Value::Number(a + b),
+                TokenType::Minus => Value::Number(a - b),
+                TokenType::Star => Value::Number(a * b),
+                TokenType::Slash => Value::Number(a / b),
+                TokenType::Greater => Value::Bool(a > b),
+                // As at runtime, `>=` and `<=` are the negations of `<` and
+                // `>`, so they're true when either side is NaN
+                TokenType::GreaterEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+                TokenType::Less => Value::Bool(a < b),
+                TokenType::LessEqual => Value::Bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+                TokenType::EqualEqual => Value::Bool(a == b),
+                TokenType::BangEqual => Value::Bool(a != b),
+                _ => return None,
+            });
+        }
+        let scalar =
+            |value: &Value| matches!(value, Value::Number(_) | Value::Bool(_) | Value::Nil);
+        if !scalar(left) || !scalar(right) {
+            return None;
+        }
+        match operator_type {
+            TokenType::EqualEqual => Some(Value::Bool(left == right)),
+            TokenType::BangEqual => Some(Value::Bool(left != right)),
+            _ => None,
+        }
+    }
+
+    /// Records the source text from `start` through the previous token as the
+    /// span of the next instruction emitted.
+    fn record_span(&mut self, start: Token) {
+        if !self.debug_info {
+            return;
+        }
+        let source = self.scanner.source;
+        let start_offset = start.source.as_ptr() as usize - source.as_ptr() as usize;
+        let end_offset = self.previous.source.as_ptr() as usize - source.as_ptr() as usize
+            + self.previous.source.len();
+        let span = Span {
+            text: source[start_offset..end_offset].to_string(),
+            line: start.line,
+            column: start.column,
+        };
+        let offset = self.current_chunk().code.len();
+        self.current_chunk().add_span(offset, span);
+    }
+
+    fn and(&mut self, left_start: usize) {
+        self.thread_jumps(left_start, Opcode::JumpIfTrue);
         let jump = self.emit_jump(Opcode::JumpIfFalse);
         self.emit_byte(Opcode::Pop as u8);
         self.parse_precedence(Precedence::And);
         self.patch_jump(jump);
     }
 
-    fn or(&mut self) {
-        let else_jump = self.emit_jump(Opcode::JumpIfFalse);
-        let end_jump = self.emit_jump(Opcode::Jump);
+    fn or(&mut self, left_start: usize) {
+        self.thread_jumps(left_start, Opcode::JumpIfFalse);
+        let jump = self.emit_jump(Opcode::JumpIfTrue);
+        self.emit_byte(Opcode::Pop as u8);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(jump);
+    }
 
-        self.patch_jump(else_jump);
+    /// `a ?? b` is `a` unless it's nil, in which case it's `b`, which is
+    /// only evaluated then.
+    fn coalesce(&mut self) {
+        let jump = self.emit_jump(Opcode::JumpIfNotNil);
         self.emit_byte(Opcode::Pop as u8);
+        self.parse_precedence(Precedence::Coalesce);
+        self.patch_jump(jump);
+    }
 
-        self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_jump);
+    /// Before the conditional jump for an `and` or `or` is emitted, retargets
+    /// the `opposite` jumps in its left operand, from `left_start`, that land
+    /// on it. A value those jumps carry always falls through the new jump, so
+    /// in `(a or b) and c` a true `a` skips straight to `c` instead of being
+    /// tested again.
+    fn thread_jumps(&mut self, left_start: usize, opposite: Opcode) {
+        let chunk = self.current_chunk();
+        let here = chunk.code.len();
+        let mut offset = left_start;
+        while offset < here {
+            if chunk.code[offset] == opposite as u8 {
+                let jump = u16::from_be_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]);
+                // Past the new jump, to the `Pop` that follows it
+                match jump.checked_add(3) {
+                    Some(threaded) if offset + 3 + jump as usize == here => {
+                        let [high, low] = threaded.to_be_bytes();
+                        chunk.code[offset + 1] = high;
+                        chunk.code[offset + 2] = low;
+                    }
+                    _ => {}
+                }
+            }
+            offset += chunk.instruction_len(offset);
+        }
     }
 
     fn call(&mut self) {
+        let args_start = self.current_chunk().code.len();
+        // The callee is a global if its read was the last thing emitted
+        let callee_start = self.last_global_read.filter(|&offset| {
+            offset < args_start
+                && offset + self.current_chunk().instruction_len(offset) == args_start
+        });
         let arg_count = self.argument_list();
+        if let Some(callee_start) = callee_start {
+            if self.fold_native_call(callee_start, arg_count) {
+                return;
+            }
+        }
         self.emit_bytes(Opcode::Call as u8, arg_count);
     }
 
+    /// If the call whose callee, a global read, was compiled at
+    /// `callee_start` is to a foldable native with only literal arguments,
+    /// replaces it with the value the native returns. A call the native
+    /// fails is left to fail when it runs.
+    fn fold_native_call(&mut self, callee_start: usize, arg_count: u8) -> bool {
+        let chunk = self.current_chunk();
+        let args_start = callee_start + chunk.instruction_len(callee_start);
+        let name = match Opcode::try_from(chunk.code[callee_start]) {
+            Ok(Opcode::GetGlobalSlot) => {
+                let slot = u16::from_be_bytes([
+                    chunk.code[callee_start + 1],
+                    chunk.code[callee_start + 2],
+                ]);
+                match &self.globals {
+                    Some(globals) => globals.name(slot as usize),
+                    None => return false,
+                }
+            }
+            Ok(Opcode::GetGlobal) => {
+                match &chunk.constants[chunk.code[callee_start + 1] as usize] {
+                    Value::ObjString(name) => &name.get().str,
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        };
+        if !self.foldable_natives.contains(name) {
+            return false;
+        }
+        let Some((arity, function)) = natives::pure_builtin(name) else {
+            return false;
+        };
+
+        // Each argument must be exactly one literal instruction
+        let chunk = self.current_chunk();
+        let mut args = Vec::new();
+        let mut offset = args_start;
+        while offset < chunk.code.len() {
+            match Opcode::try_from(chunk.code[offset]) {
+                Ok(Opcode::True) => args.push(Value::Bool(true)),
+                Ok(Opcode::False) => args.push(Value::Bool(false)),
+                Ok(Opcode::Nil) => args.push(Value::Nil),
+                Ok(Opcode::Constant) => {
+                    args.push(chunk.constants[chunk.code[offset + 1] as usize].clone())
+                }
+                _ => return false,
+            }
+            offset += chunk.instruction_len(offset);
+        }
+        if args.len() != arg_count as usize || args.len() != arity {
+            return false;
+        }
+        let Ok(result) = function(self.allocator, &args) else {
+            return false;
+        };
+
+        let chunk = self.current_chunk();
+        let mut released = Vec::new();
+        let mut offset = callee_start;
+        while offset < chunk.code.len() {
+            if matches!(
+                Opcode::try_from(chunk.code[offset]),
+                Ok(Opcode::Constant | Opcode::GetGlobal)
+            ) {
+                released.push(chunk.code[offset + 1] as usize);
+            }
+            offset += chunk.instruction_len(offset);
+        }
+        for constant in released {
+            self.release_constant(constant);
+        }
+        self.discard_code(callee_start);
+        self.emit_value(result);
+        true
+    }
+
+    fn list(&mut self) {
+        let mut item_count = 0;
+        while !self.check(TokenType::RightBracket) {
+            self.expression();
+            if item_count == u8::MAX {
+                self.error("Can't have more than 255 items in a list literal.");
+            } else {
+                item_count += 1;
+            }
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(Opcode::BuildList as u8, item_count);
+    }
+
+    fn map(&mut self) {
+        let mut entry_count = 0;
+        while !self.check(TokenType::RightBrace) {
+            self.expression();
+            self.consume(TokenType::Colon, "Expect ':' after map key.");
+            self.expression();
+            if entry_count == u8::MAX {
+                self.error("Can't have more than 255 entries in a map literal.");
+            } else {
+                entry_count += 1;
+            }
+
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(Opcode::BuildMap as u8, entry_count);
+    }
+
+    fn subscript(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(Opcode::IndexSet as u8);
+        } else {
+            self.emit_byte(Opcode::IndexGet as u8);
+        }
+    }
+
+    /// A method call, `value.name(args)`, on a value of a built-in type, or
+    /// a read of a map's entry, `map.name`.
+    fn dot(&mut self) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous.source);
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_bytes(Opcode::Invoke as u8, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_bytes(Opcode::GetProperty as u8, name);
+        }
+    }
+
     fn argument_list(&mut self) -> u8 {
         let mut arg_count = 0;
         if !self.check(TokenType::RightParen) {
@@ -785,6 +1908,8 @@ impl<'a> Compiler<'a> {
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
+        let start = self.previous;
+        let code_start = self.current_chunk().code.len();
 
         match self.previous.token_type.prefix_parser_type() {
             Some(prefix_parser_type) => match prefix_parser_type {
@@ -793,19 +1918,45 @@ impl<'a> Compiler<'a> {
                 PrefixParserType::Number => self.number(),
                 PrefixParserType::Literal => self.literal(),
                 PrefixParserType::String => self.string(),
+                PrefixParserType::Bytes => self.bytes(),
                 PrefixParserType::Variable => self.variable(precedence <= Precedence::Assignment),
+                PrefixParserType::List => self.list(),
+                PrefixParserType::Map => self.map(),
             },
-            None => self.error("Expect expression with prefix parser."),
+            None => self.error_expecting(
+                self.previous,
+                "Expect expression with prefix parser.",
+                "an expression".to_owned(),
+            ),
         }
 
+        let mut follows_comparison = false;
         while precedence <= self.current.token_type.precedence() {
             self.advance();
+            // `a < b < c` would compare `c` with the boolean `a < b`, which
+            // is never what was meant
+            let comparison = matches!(
+                self.previous.token_type,
+                TokenType::Less
+                    | TokenType::LessEqual
+                    | TokenType::Greater
+                    | TokenType::GreaterEqual
+            );
+            if comparison && follows_comparison {
+                self.error("Comparisons can't be chained; combine them with 'and'.");
+            }
+            follows_comparison = comparison;
             match self.previous.token_type.infix_parser_type() {
                 Some(infix_parser_type) => match infix_parser_type {
-                    InfixParserType::Binary => self.binary(),
-                    InfixParserType::And => self.and(),
-                    InfixParserType::Or => self.or(),
+                    InfixParserType::Binary => self.binary(start, code_start),
+                    InfixParserType::And => self.and(code_start),
+                    InfixParserType::Or => self.or(code_start),
+                    InfixParserType::Coalesce => self.coalesce(),
+                    InfixParserType::Dot => self.dot(),
                     InfixParserType::Call => self.call(),
+                    InfixParserType::Subscript => {
+                        self.subscript(precedence <= Precedence::Assignment)
+                    }
                 },
                 None => self.error("Expect expression with infix parser."),
             }
@@ -819,7 +1970,7 @@ impl<'a> Compiler<'a> {
     // Code generation
 
     fn current_chunk(&mut self) -> &mut Chunk {
-        unsafe { &mut (*self.current_compiler_state().function).chunk }
+        &mut self.current_compiler_state().function.get_mut().chunk
     }
 
     fn current_compiler_state(&self) -> &CompilerState<'a> {
@@ -830,32 +1981,126 @@ impl<'a> Compiler<'a> {
         self.compiler_states.last_mut().unwrap()
     }
 
-    pub fn compile(&mut self, debug_print_code: bool) -> Option<*mut ObjFunction> {
+    pub fn compile(
+        &mut self,
+        debug_print_code: bool,
+    ) -> Result<Gc<ObjFunction>, Vec<CompileError>> {
+        self.exclude_redefined_natives(self.scanner.source);
+        self.compile_declarations();
+        self.finish(debug_print_code)
+    }
+
+    /// Compiles several named sources, in order, into a single program in
+    /// place of the source passed to `new`. Diagnostics and the resulting
+    /// bytecode are attributed to the file each piece of code came from.
+    pub fn compile_sources(
+        &mut self,
+        sources: &[(&'a str, &'a str)],
+        debug_print_code: bool,
+    ) -> Result<Gc<ObjFunction>, Vec<CompileError>> {
+        for &(_, source) in sources {
+            self.exclude_redefined_natives(source);
+        }
+        for &(name, source) in sources {
+            self.scanner = Scanner::with_file(name, source);
+            self.panic_mode = false;
+            self.advance();
+            self.compile_declarations();
+        }
+        self.finish(debug_print_code)
+    }
+
+    /// Stops folding calls to natives that `source` may rebind: any name
+    /// following `var` or `fun`, or followed by `=`, whatever its scope.
+    fn exclude_redefined_natives(&mut self, source: &str) {
+        if self.foldable_natives.is_empty() {
+            return;
+        }
+        let start = Instant::now();
+        let mut scanner = Scanner::new(source);
+        let mut previous = TokenType::Eof;
+        let mut previous_name = "";
+        loop {
+            let token = match scanner.scan_token() {
+                Ok(token) => token,
+                Err(ScanError::UnexpectedCharacter) => continue,
+                Err(_) => break,
+            };
+            if token.token_type == TokenType::Identifier
+                && matches!(previous, TokenType::Var | TokenType::Const | TokenType::Fun)
+            {
+                self.foldable_natives.remove(token.source);
+            }
+            if token.token_type == TokenType::Equal && previous == TokenType::Identifier {
+                self.foldable_natives.remove(previous_name);
+            }
+            if token.token_type == TokenType::Eof {
+                break;
+            }
+            previous = token.token_type;
+            previous_name = token.source;
+        }
+        if let Some(scan_time) = &mut self.scan_time {
+            *scan_time += start.elapsed();
+        }
+    }
+
+    fn compile_declarations(&mut self) {
         while !self.match_token(TokenType::Eof) {
             self.declaration();
         }
         self.consume(TokenType::Eof, "Expect end of expression.");
+    }
+
+    fn finish(&mut self, debug_print_code: bool) -> Result<Gc<ObjFunction>, Vec<CompileError>> {
         let function = self.end_compiler(debug_print_code);
-        if !self.had_error {
-            Some(function)
+        if self.errors.is_empty() {
+            Ok(function)
         } else {
-            None
+            Err(std::mem::take(&mut self.errors))
         }
     }
 
-    fn end_compiler(&mut self, debug_print_code: bool) -> *mut ObjFunction {
+    fn end_compiler(&mut self, debug_print_code: bool) -> Gc<ObjFunction> {
         self.emit_return();
-        if debug_print_code && !self.had_error {
+
+        // Locals still in scope at the end of the function live until its last instruction
+        let end = self.current_chunk().code.len();
+        let open_locals: Vec<usize> = self
+            .current_compiler_state()
+            .locals
+            .iter()
+            .filter_map(|local| local.debug_index)
+            .collect();
+        for debug_index in open_locals {
+            self.current_chunk().locals[debug_index].end = end;
+        }
+
+        for slot in 0..self.current_compiler_state().locals.len() {
+            self.bind_to_frame(slot);
+        }
+        if self.optimize && self.errors.is_empty() {
+            peephole::optimize(self.current_chunk());
+        }
+        if debug_print_code && self.errors.is_empty() {
             disassemble_chunk(self.current_chunk(), "code");
         }
         let function = self.current_compiler_state().function;
         self.compiler_states.pop();
+        self.last_global_read = None;
         function
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        let line = self.previous.line;
-        self.current_chunk().write_chunk(byte, line);
+        let (line, column) = (self.previous.line, self.previous.column);
+        if let Some(file) = self.previous.file {
+            self.current_chunk().set_file(file);
+        }
+        self.current_chunk().write_chunk(byte, line, column);
+        self.code_byte_count += 1;
+        if self.code_byte_count > self.limits.max_code_bytes {
+            self.limit_error("Too much code in program.");
+        }
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -868,13 +2113,80 @@ impl<'a> Compiler<'a> {
         self.emit_byte(Opcode::Return as u8);
     }
 
+    /// Emits code loading `value`: the dedicated opcode for booleans and
+    /// nil, which never take up a constant slot, and a constant otherwise.
+    fn emit_value(&mut self, value: Value) {
+        match value {
+            Value::Bool(true) => self.emit_byte(Opcode::True as u8),
+            Value::Bool(false) => self.emit_byte(Opcode::False as u8),
+            Value::Nil => self.emit_byte(Opcode::Nil as u8),
+            value => self.emit_constant(value),
+        }
+    }
+
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
         self.emit_bytes(Opcode::Constant as u8, constant);
     }
 
+    /// The slot of an existing constant equal to `key`, counting a new use
+    /// of it.
+    fn reuse_constant(&mut self, key: &ConstantKey) -> Option<u8> {
+        let state = self.current_compiler_state_mut();
+        let constant = *state.constant_indices.get(key)?;
+        state.constant_uses[constant as usize] += 1;
+        Some(constant)
+    }
+
+    /// Gives up one use of `constant`. Once the constants at the end of the
+    /// table are unused, they're removed.
+    fn release_constant(&mut self, constant: usize) {
+        self.current_compiler_state_mut().constant_uses[constant] -= 1;
+        while self.current_compiler_state().constant_uses.last() == Some(&0) {
+            let state = self.current_compiler_state_mut();
+            state.constant_uses.pop();
+            let removed = state.constant_uses.len();
+            state
+                .constant_indices
+                .retain(|_, constant| *constant as usize != removed);
+            self.current_chunk().constants.pop();
+            self.constant_count -= 1;
+        }
+    }
+
     fn make_constant(&mut self, value: Value) -> u8 {
+        debug_assert!(
+            !matches!(value, Value::Bool(_) | Value::Nil),
+            "Booleans and nil have their own opcodes; see emit_value"
+        );
+        let key = match &value {
+            Value::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            Value::ObjString(obj_str) => {
+                let text = &obj_str.get().str;
+                if self.identifiers.get(text) == Some(obj_str) {
+                    Some(ConstantKey::Identifier(text.clone()))
+                } else {
+                    Some(ConstantKey::String(text.clone()))
+                }
+            }
+            // Functions and closures are distinct, and bytes are mutable
+            _ => None,
+        };
+        if let Some(constant) = key.as_ref().and_then(|key| self.reuse_constant(key)) {
+            return constant;
+        }
+
         let constant = self.current_chunk().add_constant(value);
+        self.current_compiler_state_mut().constant_uses.push(1);
+        if let (Some(key), Ok(index)) = (key, u8::try_from(constant)) {
+            self.current_compiler_state_mut()
+                .constant_indices
+                .insert(key, index);
+        }
+        self.constant_count += 1;
+        if self.constant_count > self.limits.max_constants {
+            self.limit_error("Too many constants in program.");
+        }
         if constant > u8::MAX as usize {
             self.error("Too many constants in one chunk.");
             return 0;
@@ -882,34 +2194,15 @@ impl<'a> Compiler<'a> {
         constant as u8
     }
 
-    fn heap_alloc<T>(&mut self, obj: T) -> *mut T
+    // Compiling never collects garbage: only the VM can see every root. What
+    // the compiler allocates is reachable from the script function it returns.
+    #[track_caller]
+    fn heap_alloc<T>(&mut self, obj: T) -> Gc<T>
     where
         T: GC + std::fmt::Display + 'static,
     {
-        if self.debug_stress_gc {
-            self.collect_garbage()
-        }
         self.allocator.heap_alloc(obj)
     }
-
-    fn collect_garbage(&mut self) {
-        if self.debug_log_gc {
-            println!("-- gc begin (compiler)");
-        }
-
-        for state in self.compiler_states.iter_mut() {
-            unsafe {
-                if self.debug_log_gc {
-                    println!("mark {}", (*state.function))
-                }
-                (*state.function).is_marked = true;
-            }
-        }
-
-        if self.debug_log_gc {
-            println!("-- gc end (compiler)");
-        }
-    }
 }
 
 impl TokenType {
@@ -929,15 +2222,35 @@ impl TokenType {
             TokenType::GreaterEqual => Precedence::Comparison,
             TokenType::Less => Precedence::Comparison,
             TokenType::LessEqual => Precedence::Comparison,
+            TokenType::Pipe => Precedence::BitOr,
+            TokenType::Caret => Precedence::BitXor,
+            TokenType::Ampersand => Precedence::BitAnd,
+            TokenType::LessLess => Precedence::Shift,
+            TokenType::GreaterGreater => Precedence::Shift,
             TokenType::String => Precedence::None,
             TokenType::Identifier => Precedence::None,
             TokenType::And => Precedence::And,
             TokenType::Or => Precedence::Or,
+            TokenType::QuestionQuestion => Precedence::Coalesce,
             TokenType::LeftParen => Precedence::Call,
+            TokenType::LeftBracket => Precedence::Call,
+            TokenType::Dot => Precedence::Call,
             _ => Precedence::None,
         }
     }
 
+    /// The instruction for a binary bitwise operator.
+    fn bitwise_opcode(&self) -> Option<Opcode> {
+        match self {
+            TokenType::Ampersand => Some(Opcode::BitAnd),
+            TokenType::Pipe => Some(Opcode::BitOr),
+            TokenType::Caret => Some(Opcode::BitXor),
+            TokenType::LessLess => Some(Opcode::ShiftLeft),
+            TokenType::GreaterGreater => Some(Opcode::ShiftRight),
+            _ => None,
+        }
+    }
+
     fn prefix_parser_type(&self) -> Option<PrefixParserType> {
         match self {
             TokenType::LeftParen => Some(PrefixParserType::Grouping),
@@ -947,8 +2260,12 @@ impl TokenType {
             TokenType::True => Some(PrefixParserType::Literal),
             TokenType::False => Some(PrefixParserType::Literal),
             TokenType::Bang => Some(PrefixParserType::Unary),
+            TokenType::Tilde => Some(PrefixParserType::Unary),
             TokenType::String => Some(PrefixParserType::String),
+            TokenType::Bytes => Some(PrefixParserType::Bytes),
             TokenType::Identifier => Some(PrefixParserType::Variable),
+            TokenType::LeftBracket => Some(PrefixParserType::List),
+            TokenType::LeftBrace => Some(PrefixParserType::Map),
             _ => None,
         }
     }
@@ -956,6 +2273,8 @@ impl TokenType {
     fn infix_parser_type(&self) -> Option<InfixParserType> {
         match self {
             TokenType::LeftParen => Some(InfixParserType::Call),
+            TokenType::LeftBracket => Some(InfixParserType::Subscript),
+            TokenType::Dot => Some(InfixParserType::Dot),
             TokenType::Plus => Some(InfixParserType::Binary),
             TokenType::Minus => Some(InfixParserType::Binary),
             TokenType::Star => Some(InfixParserType::Binary),
@@ -966,8 +2285,14 @@ impl TokenType {
             TokenType::GreaterEqual => Some(InfixParserType::Binary),
             TokenType::Less => Some(InfixParserType::Binary),
             TokenType::LessEqual => Some(InfixParserType::Binary),
+            TokenType::Pipe => Some(InfixParserType::Binary),
+            TokenType::Caret => Some(InfixParserType::Binary),
+            TokenType::Ampersand => Some(InfixParserType::Binary),
+            TokenType::LessLess => Some(InfixParserType::Binary),
+            TokenType::GreaterGreater => Some(InfixParserType::Binary),
             TokenType::And => Some(InfixParserType::And),
             TokenType::Or => Some(InfixParserType::Or),
+            TokenType::QuestionQuestion => Some(InfixParserType::Coalesce),
             _ => None,
         }
     }
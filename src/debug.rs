@@ -1,77 +1,299 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
 use crate::{
     chunk::{Chunk, Opcode},
+    memory::Gc,
+    object_function::ObjFunction,
     value::Value,
 };
 
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {} ==", name);
+    print!("{}", disassemble_chunk_to_string(chunk, name));
+}
+
+/// Renders the same listing `disassemble_chunk` prints, for snapshot testing codegen.
+pub fn disassemble_chunk_to_string(chunk: &Chunk, name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "== {} ==", name).unwrap();
+    write_code(&mut out, chunk);
+    out
+}
+
+/// Renders every function in the program whose script function is
+/// `function`: for each, its arity, constant pool, the variables it captures
+/// as upvalues, and its code.
+pub fn disassemble_program_to_string(function: Gc<ObjFunction>) -> String {
+    let functions = ObjFunction::collect_functions(function);
+
+    // A function's upvalues are described by the `Closure` instruction that
+    // creates it, in its enclosing function's code
+    let mut captures = HashMap::new();
+    for function in functions.iter() {
+        let chunk = &function.get().chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (_, next_offset) = disassemble_instruction_to_string(chunk, offset);
+            if chunk.code[offset] == Opcode::Closure as u8 {
+                if let Value::ObjFunction(nested) =
+                    &chunk.constants[chunk.code[offset + 1] as usize]
+                {
+                    let pairs = chunk.code[offset + 2..next_offset]
+                        .chunks(2)
+                        .map(|pair| (pair[0] == 1, pair[1]))
+                        .collect::<Vec<_>>();
+                    captures.insert(*nested, pairs);
+                }
+            }
+            offset = next_offset;
+        }
+    }
+
+    let mut out = String::new();
+    for (i, function) in functions.iter().enumerate() {
+        let function_ref = function.get();
+        let chunk = &function_ref.chunk;
+        if i > 0 {
+            writeln!(out).unwrap();
+        }
+        writeln!(out, "== {} ==", function_ref).unwrap();
+        let min_arity = function_ref.min_arity();
+        let arity = if min_arity == function_ref.arity as usize {
+            min_arity.to_string()
+        } else {
+            format!("{min_arity} to {}", function_ref.arity)
+        };
+        writeln!(
+            out,
+            "arity {arity}, {} upvalue(s)",
+            function_ref.upvalue_count
+        )
+        .unwrap();
+
+        writeln!(out, "-- constants --").unwrap();
+        for (index, constant) in chunk.constants.iter().enumerate() {
+            writeln!(
+                out,
+                "{:4} {:<8} '{}'",
+                index,
+                constant.type_name(),
+                constant
+            )
+            .unwrap();
+        }
+
+        if let Some(pairs) = captures.get(function).filter(|pairs| !pairs.is_empty()) {
+            writeln!(out, "-- upvalues --").unwrap();
+            for (index, (is_local, slot)) in pairs.iter().enumerate() {
+                let kind = if *is_local { "local" } else { "upvalue" };
+                writeln!(out, "{:4} {} {}", index, kind, slot).unwrap();
+            }
+        }
+
+        writeln!(out, "-- code --").unwrap();
+        write_code(&mut out, chunk);
+    }
+    out
+}
 
+fn write_code(out: &mut String, chunk: &Chunk) {
     let mut offset = 0;
     while offset < chunk.code.len() {
-        print!("{:04} ", offset);
+        write!(out, "{:04} ", offset).unwrap();
 
         if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-            print!("   | ");
+            write!(out, "   | ").unwrap();
         } else {
-            print!("{:4} ", chunk.lines[offset]);
+            write!(out, "{:4} ", chunk.lines[offset]).unwrap();
         }
 
-        let byte = chunk.code[offset];
-        if let Ok(opcode) = Opcode::try_from(byte) {
-            offset = disassemble_instruction(&opcode, chunk, offset);
-        } else {
-            println!("Unknown opcode {byte}");
-            offset += 1;
+        offset = write_instruction(out, chunk, offset);
+    }
+}
+
+/// Renders the instruction at `offset`, returning it along with the offset of
+/// the next instruction.
+pub fn disassemble_instruction_to_string(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let mut out = String::new();
+    let next_offset = write_instruction(&mut out, chunk, offset);
+    (out, next_offset)
+}
+
+/// Renders one line of execution trace: the current stack contents followed by
+/// the instruction about to execute.
+pub fn trace_instruction_to_string(stack: &[Value], chunk: &Chunk, offset: usize) -> String {
+    let mut out = String::from("          ");
+    for slot in stack.iter() {
+        write!(out, "[ {slot} ]").unwrap();
+    }
+    writeln!(out).unwrap();
+    write_instruction(&mut out, chunk, offset);
+    out
+}
+
+/// Renders each instruction in the chunk without its offset and line columns,
+/// so listings of two compilations can be lined up against each other. Jumps
+/// go to labels numbered in code order, each on its own line before the
+/// instruction it marks, and constants are shown without their index, so code
+/// added in one place doesn't change the listing anywhere else.
+pub fn instruction_listing(chunk: &Chunk) -> Vec<String> {
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        if let Some(target) = jump_target(chunk, offset) {
+            targets.push(target);
         }
+        offset = disassemble_instruction_to_string(chunk, offset).1;
+    }
+    targets.sort();
+    targets.dedup();
+    let label = |target: usize| format!("L{}", targets.binary_search(&target).unwrap() + 1);
+
+    let mut listing = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        if targets.binary_search(&offset).is_ok() {
+            listing.push(format!("{}:", label(offset)));
+        }
+        let (instruction, next_offset) = disassemble_instruction_to_string(chunk, offset);
+        let opcode = Opcode::try_from(chunk.code[offset]);
+        let constant = || &chunk.constants[chunk.code[offset + 1] as usize];
+        let line = match (opcode, jump_target(chunk, offset)) {
+            (Ok(opcode), Some(target)) => {
+                format!("{:<16} {}", opcode.to_string(), label(target))
+            }
+            (
+                Ok(
+                    opcode @ (Opcode::Constant
+                    | Opcode::CopyConstant
+                    | Opcode::DefineGlobal
+                    | Opcode::DefineGlobalConst
+                    | Opcode::GetGlobal
+                    | Opcode::SetGlobal
+                    | Opcode::GetProperty),
+                ),
+                _,
+            ) => format!("{:<16} '{}'", opcode.to_string(), constant()),
+            (Ok(opcode @ Opcode::Invoke), _) => format!(
+                "{:<16} ({} args) '{}'",
+                opcode.to_string(),
+                chunk.code[offset + 2],
+                constant()
+            ),
+            (Ok(opcode @ Opcode::Closure), _) => {
+                let mut line = format!("{:<16} {}", opcode.to_string(), constant());
+                // Each captured variable, without the offset the disassembly repeats
+                for upvalue in chunk.code[offset + 2..next_offset].chunks(2) {
+                    let kind = if upvalue[0] == 1 { "local" } else { "upvalue" };
+                    line.push_str(&format!("\n  {kind} {}", upvalue[1]));
+                }
+                line
+            }
+            _ => instruction.trim_end().to_owned(),
+        };
+        listing.push(line);
+        offset = next_offset;
+    }
+    listing
+}
+
+/// Where the jump at `offset` goes, if the instruction there is a jump.
+fn jump_target(chunk: &Chunk, offset: usize) -> Option<usize> {
+    let forward = match Opcode::try_from(chunk.code[offset]) {
+        Ok(
+            Opcode::Jump
+            | Opcode::JumpIfFalse
+            | Opcode::JumpIfTrue
+            | Opcode::JumpIfNotNil
+            | Opcode::PushHandler,
+        ) => true,
+        Ok(Opcode::Loop) => false,
+        _ => return None,
+    };
+    let jump = ((chunk.code[offset + 1] as u16) << 8 | chunk.code[offset + 2] as u16) as usize;
+    Some(if forward {
+        offset + 3 + jump
+    } else {
+        offset + 3 - jump
+    })
+}
+
+fn write_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    let byte = chunk.code[offset];
+    if let Ok(opcode) = Opcode::try_from(byte) {
+        write_opcode(out, &opcode, chunk, offset)
+    } else {
+        writeln!(out, "Unknown opcode {byte}").unwrap();
+        offset + 1
     }
 }
 
-pub fn disassemble_instruction(opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
+fn write_opcode(out: &mut String, opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
     match opcode {
-        Opcode::Return => disassemble_simple_instruction(opcode, offset),
-        Opcode::Constant => disassemble_constant_instruction(opcode, chunk, offset),
-        Opcode::Negate => disassemble_simple_instruction(opcode, offset),
-        Opcode::Nil => disassemble_simple_instruction(opcode, offset),
-        Opcode::True => disassemble_simple_instruction(opcode, offset),
-        Opcode::False => disassemble_simple_instruction(opcode, offset),
-        Opcode::Add => disassemble_simple_instruction(opcode, offset),
-        Opcode::Subtract => disassemble_simple_instruction(opcode, offset),
-        Opcode::Multiply => disassemble_simple_instruction(opcode, offset),
-        Opcode::Divide => disassemble_simple_instruction(opcode, offset),
-        Opcode::Not => disassemble_simple_instruction(opcode, offset),
-        Opcode::Equal => disassemble_simple_instruction(opcode, offset),
-        Opcode::Greater => disassemble_simple_instruction(opcode, offset),
-        Opcode::Less => disassemble_simple_instruction(opcode, offset),
-        Opcode::Print => disassemble_simple_instruction(opcode, offset),
-        Opcode::Pop => disassemble_simple_instruction(opcode, offset),
-        Opcode::DefineGlobal => disassemble_constant_instruction(opcode, chunk, offset),
-        Opcode::GetGlobal => disassemble_constant_instruction(opcode, chunk, offset),
-        Opcode::SetGlobal => disassemble_constant_instruction(opcode, chunk, offset),
-        Opcode::GetLocal => disassemble_byte_instruction(opcode, chunk, offset),
-        Opcode::SetLocal => disassemble_byte_instruction(opcode, chunk, offset),
-        Opcode::JumpIfFalse => disassemble_jump_instruction(opcode, chunk, offset, true),
-        Opcode::Jump => disassemble_jump_instruction(opcode, chunk, offset, true),
-        Opcode::Loop => disassemble_jump_instruction(opcode, chunk, offset, false),
-        Opcode::Call => disassemble_byte_instruction(opcode, chunk, offset),
+        Opcode::Return => simple_instruction(out, opcode, offset),
+        Opcode::Constant => constant_instruction(out, opcode, chunk, offset),
+        Opcode::Negate => simple_instruction(out, opcode, offset),
+        Opcode::Nil => simple_instruction(out, opcode, offset),
+        Opcode::True => simple_instruction(out, opcode, offset),
+        Opcode::False => simple_instruction(out, opcode, offset),
+        Opcode::Add => simple_instruction(out, opcode, offset),
+        Opcode::Subtract => simple_instruction(out, opcode, offset),
+        Opcode::Multiply => simple_instruction(out, opcode, offset),
+        Opcode::Divide => simple_instruction(out, opcode, offset),
+        Opcode::Not => simple_instruction(out, opcode, offset),
+        Opcode::BitAnd => simple_instruction(out, opcode, offset),
+        Opcode::BitOr => simple_instruction(out, opcode, offset),
+        Opcode::BitXor => simple_instruction(out, opcode, offset),
+        Opcode::BitNot => simple_instruction(out, opcode, offset),
+        Opcode::ShiftLeft => simple_instruction(out, opcode, offset),
+        Opcode::ShiftRight => simple_instruction(out, opcode, offset),
+        Opcode::Equal => simple_instruction(out, opcode, offset),
+        Opcode::Greater => simple_instruction(out, opcode, offset),
+        Opcode::Less => simple_instruction(out, opcode, offset),
+        Opcode::Print => simple_instruction(out, opcode, offset),
+        Opcode::Pop => simple_instruction(out, opcode, offset),
+        Opcode::DefineGlobal => constant_instruction(out, opcode, chunk, offset),
+        Opcode::DefineGlobalConst => constant_instruction(out, opcode, chunk, offset),
+        Opcode::GetGlobal => constant_instruction(out, opcode, chunk, offset),
+        Opcode::SetGlobal => constant_instruction(out, opcode, chunk, offset),
+        Opcode::GetLocal => byte_instruction(out, opcode, chunk, offset),
+        Opcode::SetLocal => byte_instruction(out, opcode, chunk, offset),
+        Opcode::GetCallerLocal => byte_instruction(out, opcode, chunk, offset),
+        Opcode::SetCallerLocal => byte_instruction(out, opcode, chunk, offset),
+        Opcode::JumpIfFalse => jump_instruction(out, opcode, chunk, offset),
+        Opcode::JumpIfTrue => jump_instruction(out, opcode, chunk, offset),
+        Opcode::JumpIfNotNil => jump_instruction(out, opcode, chunk, offset),
+        Opcode::Jump => jump_instruction(out, opcode, chunk, offset),
+        Opcode::Loop => jump_instruction(out, opcode, chunk, offset),
+        Opcode::Call => byte_instruction(out, opcode, chunk, offset),
+        Opcode::Invoke => invoke_instruction(out, opcode, chunk, offset),
+        Opcode::GetProperty => constant_instruction(out, opcode, chunk, offset),
+        Opcode::CopyConstant => constant_instruction(out, opcode, chunk, offset),
         Opcode::Closure => {
             let constant_offset = chunk.code[offset + 1];
-            println!(
+            writeln!(
+                out,
                 "{:<16} {:>4} {}",
-                opcode, constant_offset, chunk.constants[constant_offset as usize]
-            );
+                opcode.to_string(),
+                constant_offset,
+                chunk.constants[constant_offset as usize]
+            )
+            .unwrap();
 
             let upvalue_count =
                 if let Value::ObjFunction(obj_fun) = &chunk.constants[constant_offset as usize] {
-                    let upvalue_count = unsafe { (**obj_fun).upvalue_count };
+                    let upvalue_count = obj_fun.get().upvalue_count;
                     for i in 0..upvalue_count {
-                        let is_local = chunk.code[(offset + 2) + i];
-                        let index = chunk.code[(offset + 2) + (i + 1)];
-                        println!(
+                        let is_local = chunk.code[offset + 2 + i * 2];
+                        let index = chunk.code[offset + 3 + i * 2];
+                        writeln!(
+                            out,
                             "{:>4}       |                     {} {}",
                             offset,
                             if is_local == 1 { "local" } else { "upvalue" },
                             index
-                        );
+                        )
+                        .unwrap();
                     }
                     upvalue_count
                 } else {
@@ -80,44 +302,78 @@ pub fn disassemble_instruction(opcode: &Opcode, chunk: &Chunk, offset: usize) ->
 
             offset + 2 + (upvalue_count * 2)
         }
-        Opcode::GetUpvalue => disassemble_byte_instruction(opcode, chunk, offset),
-        Opcode::SetUpvalue => disassemble_byte_instruction(opcode, chunk, offset),
-        Opcode::CloseUpvalue => disassemble_simple_instruction(opcode, offset),
+        Opcode::GetUpvalue => byte_instruction(out, opcode, chunk, offset),
+        Opcode::SetUpvalue => byte_instruction(out, opcode, chunk, offset),
+        Opcode::CloseUpvalue => simple_instruction(out, opcode, offset),
+        Opcode::BuildList => byte_instruction(out, opcode, chunk, offset),
+        Opcode::IndexGet => simple_instruction(out, opcode, offset),
+        Opcode::IndexSet => simple_instruction(out, opcode, offset),
+        Opcode::BuildMap => byte_instruction(out, opcode, chunk, offset),
+        Opcode::IterNext => byte_instruction(out, opcode, chunk, offset),
+        Opcode::Throw => simple_instruction(out, opcode, offset),
+        Opcode::PushHandler => jump_instruction(out, opcode, chunk, offset),
+        Opcode::PopHandler => simple_instruction(out, opcode, offset),
+        Opcode::DefineGlobalSlot => short_instruction(out, opcode, chunk, offset),
+        Opcode::DefineGlobalConstSlot => short_instruction(out, opcode, chunk, offset),
+        Opcode::GetGlobalSlot => short_instruction(out, opcode, chunk, offset),
+        Opcode::SetGlobalSlot => short_instruction(out, opcode, chunk, offset),
     }
 }
 
-fn disassemble_simple_instruction(opcode: &Opcode, offset: usize) -> usize {
-    println!("{}", opcode);
+fn simple_instruction(out: &mut String, opcode: &Opcode, offset: usize) -> usize {
+    writeln!(out, "{}", opcode).unwrap();
     offset + 1
 }
 
-fn disassemble_constant_instruction(opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
+fn constant_instruction(out: &mut String, opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
     let constant_offset = chunk.code[offset + 1];
-    println!(
+    writeln!(
+        out,
         "{:<16} {:>4} '{}'",
-        opcode, constant_offset, chunk.constants[constant_offset as usize]
-    );
+        opcode.to_string(),
+        constant_offset,
+        chunk.constants[constant_offset as usize]
+    )
+    .unwrap();
     offset + 2
 }
 
-fn disassemble_byte_instruction(opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
+fn invoke_instruction(out: &mut String, opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
+    let constant = chunk.code[offset + 1];
+    let arg_count = chunk.code[offset + 2];
+    writeln!(
+        out,
+        "{:<16} ({} args) {:>4} '{}'",
+        opcode.to_string(),
+        arg_count,
+        constant,
+        chunk.constants[constant as usize]
+    )
+    .unwrap();
+    offset + 3
+}
+
+fn byte_instruction(out: &mut String, opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
     let slot = chunk.code[offset + 1];
-    println!("{:<16} {:>4}", opcode, slot);
+    writeln!(out, "{:<16} {:>4}", opcode.to_string(), slot).unwrap();
     offset + 2
 }
 
-fn disassemble_jump_instruction(
-    opcode: &Opcode,
-    chunk: &Chunk,
-    offset: usize,
-    forward: bool,
-) -> usize {
-    let jump = ((chunk.code[offset + 1] as u16) << 8 | chunk.code[offset + 2] as u16) as usize;
-    let target = if forward {
-        offset + 3 + jump
-    } else {
-        offset + 3 - jump
-    };
-    println!("{:<16} {:>4} -> {}", opcode, offset, target);
+fn short_instruction(out: &mut String, opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
+    let operand = (chunk.code[offset + 1] as u16) << 8 | chunk.code[offset + 2] as u16;
+    writeln!(out, "{:<16} {:>4}", opcode.to_string(), operand).unwrap();
+    offset + 3
+}
+
+fn jump_instruction(out: &mut String, opcode: &Opcode, chunk: &Chunk, offset: usize) -> usize {
+    let target = jump_target(chunk, offset).unwrap();
+    writeln!(
+        out,
+        "{:<16} {:>4} -> {}",
+        opcode.to_string(),
+        offset,
+        target
+    )
+    .unwrap();
     offset + 3
 }
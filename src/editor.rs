@@ -0,0 +1,158 @@
+//! Line editing for the REPL: highlights the bracket matching the one at the
+//! cursor, keeps reading lines while brackets are left open, and indents
+//! those continuation lines to the open brackets' depth.
+
+use rlox::scanner::{ScanError, Scanner, TokenType};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Cmd, ConditionalEventHandler, Event, EventContext, Helper, RepeatCount};
+use std::borrow::Cow;
+
+const INDENT: &str = "    ";
+
+/// A bracket in the input, by byte offset.
+struct Bracket {
+    offset: usize,
+    open: bool,
+    kind: TokenType,
+}
+
+/// The brackets in `source`, as scanned, skipping any inside strings or
+/// comments, and whether the source ends inside a string or block comment.
+fn scan_brackets(source: &str) -> (Vec<Bracket>, bool) {
+    let mut brackets = Vec::new();
+    let mut scanner = Scanner::new(source);
+    loop {
+        match scanner.scan_token() {
+            Ok(token) => {
+                let (open, kind) = match token.token_type {
+                    TokenType::Eof => return (brackets, false),
+                    TokenType::LeftParen => (true, TokenType::LeftParen),
+                    TokenType::RightParen => (false, TokenType::LeftParen),
+                    TokenType::LeftBrace => (true, TokenType::LeftBrace),
+                    TokenType::RightBrace => (false, TokenType::LeftBrace),
+                    TokenType::LeftBracket => (true, TokenType::LeftBracket),
+                    TokenType::RightBracket => (false, TokenType::LeftBracket),
+                    _ => continue,
+                };
+                brackets.push(Bracket {
+                    offset: scanner.start,
+                    open,
+                    kind,
+                });
+            }
+            Err(ScanError::UnexpectedCharacter) => continue,
+            Err(ScanError::UnterminatedString | ScanError::UnterminatedComment) => {
+                return (brackets, true)
+            }
+        }
+    }
+}
+
+/// How many brackets are still open at the end of `source`, and whether it
+/// ends inside a string or block comment. Stray closing brackets are left
+/// for the compiler to report.
+fn open_depth(source: &str) -> (usize, bool) {
+    let (brackets, unterminated) = scan_brackets(source);
+    let depth = brackets.iter().fold(0usize, |depth, bracket| {
+        if bracket.open {
+            depth + 1
+        } else {
+            depth.saturating_sub(1)
+        }
+    });
+    (depth, unterminated)
+}
+
+/// The offset of the bracket matching the one at `offset`, if there's a
+/// bracket there and it's matched.
+fn matching_bracket(source: &str, offset: usize) -> Option<usize> {
+    let (brackets, _) = scan_brackets(source);
+    let index = brackets
+        .iter()
+        .position(|bracket| bracket.offset == offset)?;
+    let bracket = &brackets[index];
+    let mut depth = 0;
+    let candidates: Box<dyn Iterator<Item = &Bracket>> = if bracket.open {
+        Box::new(brackets[index..].iter())
+    } else {
+        Box::new(brackets[..=index].iter().rev())
+    };
+    for candidate in candidates.filter(|candidate| candidate.kind == bracket.kind) {
+        if candidate.open == bracket.open {
+            depth += 1;
+        } else {
+            depth -= 1;
+        }
+        if depth == 0 {
+            return Some(candidate.offset);
+        }
+    }
+    None
+}
+
+pub struct LoxHelper;
+
+impl Helper for LoxHelper {}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        // Prefer the bracket just typed, before the cursor, to the one under it
+        let bracket_at = [pos.checked_sub(1), Some(pos)]
+            .into_iter()
+            .flatten()
+            .find_map(|offset| Some((offset, matching_bracket(line, offset)?)));
+        let Some((first, second)) = bracket_at else {
+            return Cow::Borrowed(line);
+        };
+
+        let mut highlighted = String::with_capacity(line.len() + 16);
+        for (offset, c) in line.char_indices() {
+            if offset == first || offset == second {
+                highlighted.push_str("\x1b[1;34m");
+                highlighted.push(c);
+                highlighted.push_str("\x1b[0m");
+            } else {
+                highlighted.push(c);
+            }
+        }
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        true
+    }
+}
+
+impl Validator for LoxHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        match open_depth(ctx.input()) {
+            (0, false) => Ok(ValidationResult::Valid(None)),
+            _ => Ok(ValidationResult::Incomplete),
+        }
+    }
+}
+
+/// Starts a continuation line indented to the depth of the open brackets
+/// when Enter is pressed on incomplete input.
+pub struct AutoIndent;
+
+impl ConditionalEventHandler for AutoIndent {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        match open_depth(ctx.line()) {
+            (0, false) => None,
+            (_, true) => Some(Cmd::Insert(1, "\n".to_owned())),
+            (depth, false) => Some(Cmd::Insert(1, format!("\n{}", INDENT.repeat(depth)))),
+        }
+    }
+}
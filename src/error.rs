@@ -0,0 +1,365 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::Write;
+use std::rc::Rc;
+
+/// A run of characters on one line of source, for pointing at code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceRange {
+    pub line: usize,
+    /// The column the range starts at, counting characters from 1
+    pub column: usize,
+    /// How many characters the range covers, at least 1
+    pub len: usize,
+}
+
+impl SourceRange {
+    /// The range covering `text`, which starts at `line` and `column`, up to
+    /// the end of its first line.
+    pub(crate) fn of(text: &str, line: usize, column: usize) -> SourceRange {
+        let len = text.lines().next().map_or(0, |text| text.chars().count());
+        SourceRange {
+            line,
+            column,
+            len: len.max(1),
+        }
+    }
+
+    /// The range's line of `source`, with carets under the range followed by
+    /// `label`, if it isn't empty:
+    ///
+    /// ```text
+    ///    3 | print a + ;
+    ///      |           ^ expected an expression, found ';'
+    /// ```
+    ///
+    /// `None` if `source` doesn't have the line.
+    pub fn excerpt(&self, source: &str, label: &str) -> Option<String> {
+        let text = source.lines().nth(self.line.checked_sub(1)?)?;
+        let gutter = self.line.to_string().len();
+        // Tabs are kept under the code so the carets line up however wide
+        // the terminal shows them
+        let indent: String = text
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let mut excerpt = format!(
+            "{:>gutter$} | {text}\n{:gutter$} | {indent}{}",
+            self.line,
+            "",
+            "^".repeat(self.len.max(1))
+        );
+        if !label.is_empty() {
+            excerpt.push(' ');
+            excerpt.push_str(label);
+        }
+        Some(excerpt)
+    }
+}
+
+/// An error found while compiling, located at the token where it was noticed.
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub file: Option<String>,
+    pub line: usize,
+    /// The column the offending token starts at, counting characters from 1
+    pub column: usize,
+    /// The offending token's text, or empty at the end of the source
+    pub lexeme: String,
+    pub message: String,
+    /// What the parser was waiting for instead, like `';'` or `an
+    /// expression`, when it was waiting for something in particular
+    pub expected: Option<String>,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "[{file}:{}] Error", self.line)?,
+            None => write!(f, "[line {}] Error", self.line)?,
+        }
+        if self.lexeme.is_empty() {
+            write!(f, " at end")?;
+        } else {
+            write!(f, " at '{}'", self.lexeme)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl CompileError {
+    /// The source the error points at: the offending token, or the place
+    /// just past the last one at the end of the source.
+    pub fn range(&self) -> SourceRange {
+        SourceRange::of(&self.lexeme, self.line, self.column)
+    }
+
+    /// What the parser expected and what it found instead, to label the
+    /// error's excerpt with, or nothing if it wasn't expecting anything in
+    /// particular.
+    pub fn label(&self) -> String {
+        let Some(expected) = &self.expected else {
+            return String::new();
+        };
+        if self.lexeme.is_empty() {
+            format!("expected {expected}, found the end of the source")
+        } else {
+            format!("expected {expected}, found '{}'", self.lexeme)
+        }
+    }
+}
+
+/// A problem found while compiling that doesn't stop the program from
+/// running, like code that can never run.
+#[derive(Debug)]
+pub struct CompileWarning {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub lexeme: String,
+    pub message: String,
+}
+
+impl Display for CompileWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "[{file}:{}] Warning", self.line)?,
+            None => write!(f, "[line {}] Warning", self.line)?,
+        }
+        write!(f, " at '{}': {}", self.lexeme, self.message)
+    }
+}
+
+impl CompileWarning {
+    pub fn range(&self) -> SourceRange {
+        SourceRange::of(&self.lexeme, self.line, self.column)
+    }
+}
+
+/// Where a VM reports warnings, kept apart from errors: compile warnings,
+/// and anything natives want to flag without failing, like a deprecated
+/// argument. Clones share one writer, so a native can hold on to a clone and
+/// still follow the host's `VM::set_warnings`.
+#[derive(Clone)]
+pub struct Warnings(Rc<RefCell<Box<dyn Write>>>);
+
+impl Warnings {
+    pub fn new(writer: Box<dyn Write>) -> Warnings {
+        Warnings(Rc::new(RefCell::new(writer)))
+    }
+
+    /// Reports `message` as a warning.
+    pub fn warn(&self, message: impl Display) {
+        self.write_line(format!("Warning: {message}"));
+    }
+
+    pub(crate) fn compile_warning(&self, warning: &CompileWarning) {
+        self.write_line(warning);
+    }
+
+    pub(crate) fn set_writer(&self, writer: Box<dyn Write>) {
+        *self.0.borrow_mut() = writer;
+    }
+
+    // A warning that can't be written isn't worth failing the program over
+    fn write_line(&self, line: impl Display) {
+        let _ = writeln!(self.0.borrow_mut(), "{line}");
+    }
+}
+
+/// A call that was active when a runtime error was raised.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    /// The function's name, or `None` for the top-level script
+    pub function: Option<String>,
+    /// The line of the instruction the frame was running, the call for all
+    /// but the innermost frame; `None` for a native function
+    pub line: Option<usize>,
+    /// The file the function's code came from, when it's known
+    pub file: Option<String>,
+}
+
+impl Display for StackFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (_, None) => write!(f, "[native]")?,
+            (Some(file), Some(line)) => write!(f, "[{file}:{line}]")?,
+            (None, Some(line)) => write!(f, "[line {line}]")?,
+        }
+        match &self.function {
+            Some(function) => write!(f, " in {function}()"),
+            None => write!(f, " in script"),
+        }
+    }
+}
+
+/// The calls active when a runtime error was raised, innermost first, shown
+/// one per line as `[line N] in function()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StackTrace {
+    pub frames: Vec<StackFrame>,
+}
+
+impl StackTrace {
+    /// Records that the error was raised inside the native function `name`,
+    /// called from the innermost frame so far.
+    pub(crate) fn push_native(&mut self, name: &str) {
+        let frame = StackFrame {
+            function: Some(name.to_owned()),
+            line: None,
+            file: None,
+        };
+        self.frames.insert(0, frame);
+    }
+}
+
+impl Display for StackTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A runtime error that no `try` block caught.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The source text of the expression that failed, when it's known
+    pub span: Option<String>,
+    /// Where the failing code is in its file, when it's known: the span if
+    /// it starts on the failing instruction's line, or else the token the
+    /// instruction was compiled at
+    pub range: Option<SourceRange>,
+    pub stack_trace: StackTrace,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> RuntimeError {
+        RuntimeError {
+            message: message.into(),
+            span: None,
+            range: None,
+            stack_trace: StackTrace::default(),
+        }
+    }
+}
+
+impl RuntimeError {
+    /// The error as its `Display` shows it, but with the failing line of
+    /// `source` and carets under the failing code in place of the quoted
+    /// span, if the error happened in code from `file` and its place is
+    /// known.
+    pub fn with_excerpt(&self, file: Option<&str>, source: &str) -> String {
+        let innermost = self
+            .stack_trace
+            .frames
+            .iter()
+            .find(|frame| frame.line.is_some());
+        let excerpt = match (innermost, self.range) {
+            (Some(frame), Some(range)) if frame.file.as_deref() == file => {
+                range.excerpt(source, "")
+            }
+            _ => None,
+        };
+        let Some(excerpt) = excerpt else {
+            return self.to_string();
+        };
+        let mut out = format!("{}\n{excerpt}", self.message);
+        if !self.stack_trace.frames.is_empty() {
+            out.push_str(&format!("\n{}", self.stack_trace));
+        }
+        out
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(span) = &self.span {
+            write!(f, "\n    {span}")?;
+        }
+        if !self.stack_trace.frames.is_empty() {
+            write!(f, "\n{}", self.stack_trace)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a program failed: every error found while compiling it, the runtime
+/// error that stopped it, the execution limit it ran past, or an interrupt.
+#[derive(Debug)]
+pub enum LoxError {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+    /// The program ran more instructions or allocated more memory than the
+    /// VM allows; see `VM::set_instruction_limit`. Unlike a runtime error, a
+    /// `try` block can't catch this.
+    LimitExceeded(RuntimeError),
+    /// The host stopped the program through `VM::interrupt_handle`.
+    Interrupted(RuntimeError),
+}
+
+/// How many compile errors `LoxError::with_excerpts` shows before just
+/// counting the rest, which past the first few are mostly confusion caused
+/// by the earlier ones.
+pub const MAX_SHOWN_ERRORS: usize = 10;
+
+impl LoxError {
+    /// The error as its `Display` shows it, with each error in code from
+    /// `file` followed by an excerpt of `source` pointing at it; see
+    /// `SourceRange::excerpt`. Only the first `MAX_SHOWN_ERRORS` compile
+    /// errors are shown, and a count ends a list of several.
+    pub fn with_excerpts(&self, file: Option<&str>, source: &str) -> String {
+        let errors = match self {
+            LoxError::Compile(errors) => errors,
+            LoxError::Runtime(error)
+            | LoxError::LimitExceeded(error)
+            | LoxError::Interrupted(error) => return error.with_excerpt(file, source),
+        };
+        let mut lines: Vec<String> = errors
+            .iter()
+            .take(MAX_SHOWN_ERRORS)
+            .map(
+                |error| match error.range().excerpt(source, &error.label()) {
+                    Some(excerpt) if error.file.as_deref() == file => format!("{error}\n{excerpt}"),
+                    _ => error.to_string(),
+                },
+            )
+            .collect();
+        if errors.len() > MAX_SHOWN_ERRORS {
+            lines.push(format!(
+                "... and {} more errors.",
+                errors.len() - MAX_SHOWN_ERRORS
+            ));
+        }
+        if errors.len() > 1 {
+            lines.push(format!("Aborting due to {} compile errors.", errors.len()));
+        }
+        lines.join("\n")
+    }
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Compile(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{error}")?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(error)
+            | LoxError::LimitExceeded(error)
+            | LoxError::Interrupted(error) => write!(f, "{error}"),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! `rlox examples`: runs the annotated scripts that specify the language,
+//! checks each against the output its comments show, and renders them all as
+//! a Markdown tour of the language. An example script reads as the tour does:
+//!
+//! - A comment starting in the first column is prose, written in Markdown.
+//! - Everything else is code, shown as it's written.
+//! - `// expect: text` after a line of code, or on its own line, says the
+//!   code prints `text`. It's shown as the code's output rather than as a
+//!   comment.
+//! - `// expect runtime error: message` says the code stops with that
+//!   runtime error, shown with the output.
+//!
+//! The tour is only written if every example passes, so it never shows
+//! behavior the interpreter doesn't have.
+
+use crate::{read_file, Options, OutputBuffer};
+use rlox::LoxError;
+use std::path::Path;
+use std::process::exit;
+
+/// The comments that say what running an example does.
+const EXPECT_OUTPUT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+
+/// A stretch of an example, as the tour shows it.
+enum Block {
+    Prose(Vec<String>),
+    Code {
+        lines: Vec<String>,
+        output: Vec<String>,
+    },
+}
+
+/// An example script, split into blocks, with what running it should do.
+struct Example {
+    blocks: Vec<Block>,
+    output: Vec<String>,
+    runtime_error: Option<String>,
+}
+
+impl Example {
+    fn parse(source: &str) -> Example {
+        let mut example = Example {
+            blocks: Vec::new(),
+            output: Vec::new(),
+            runtime_error: None,
+        };
+        for line in source.lines() {
+            let (code, expected) = match line.split_once(EXPECT_OUTPUT) {
+                Some((code, output)) => {
+                    example.output.push(output.to_owned());
+                    (code, Some(output.to_owned()))
+                }
+                None => match line.split_once(EXPECT_RUNTIME_ERROR) {
+                    Some((code, message)) => {
+                        example.runtime_error = Some(message.to_owned());
+                        (code, Some(format!("Runtime error: {message}")))
+                    }
+                    None => (line, None),
+                },
+            };
+            let code = code.trim_end();
+
+            if let Some(expected) = expected {
+                if !code.is_empty() {
+                    example.code_block().0.push(code.to_owned());
+                }
+                example.code_block().1.push(expected);
+            } else if let Some(prose) = code.strip_prefix("//") {
+                let prose = prose.strip_prefix(' ').unwrap_or(prose);
+                match example.blocks.last_mut() {
+                    Some(Block::Prose(lines)) => lines.push(prose.to_owned()),
+                    _ => example.blocks.push(Block::Prose(vec![prose.to_owned()])),
+                }
+            } else if code.is_empty() {
+                // Blank lines separate paragraphs of prose, and are kept
+                // between lines of code, but not before the first
+                match example.blocks.last_mut() {
+                    Some(Block::Prose(lines)) => lines.push(String::new()),
+                    Some(Block::Code { lines, .. }) if !lines.is_empty() => {
+                        lines.push(String::new())
+                    }
+                    _ => {}
+                }
+            } else {
+                example.code_block().0.push(code.to_owned());
+            }
+        }
+        example
+    }
+
+    /// The lines and output of the code block being read, starting one if
+    /// the last block was prose.
+    fn code_block(&mut self) -> (&mut Vec<String>, &mut Vec<String>) {
+        if !matches!(self.blocks.last(), Some(Block::Code { .. })) {
+            self.blocks.push(Block::Code {
+                lines: Vec::new(),
+                output: Vec::new(),
+            });
+        }
+        match self.blocks.last_mut() {
+            Some(Block::Code { lines, output }) => (lines, output),
+            _ => unreachable!("A code block was just pushed"),
+        }
+    }
+
+    /// Checks what running the example printed, and how it ended, against
+    /// what it should have, describing how they differ if they do.
+    fn check(&self, printed: &str, result: &Result<(), LoxError>) -> Result<(), String> {
+        let printed: Vec<&str> = printed.lines().collect();
+        if printed != self.output {
+            return Err(format!(
+                "Expected output:\n{}\nbut got:\n{}",
+                self.output.join("\n"),
+                printed.join("\n")
+            ));
+        }
+        match (result, &self.runtime_error) {
+            (Ok(()), None) => Ok(()),
+            (Err(LoxError::Runtime(error)), Some(expected)) if error.message == *expected => Ok(()),
+            (Ok(()), Some(expected)) => Err(format!(
+                "Expected runtime error: {expected}\nbut the example ran cleanly"
+            )),
+            (Err(error), _) => Err(error.to_string()),
+        }
+    }
+
+    /// Appends the example to the tour, as prose and fenced code, with each
+    /// code block's output after it.
+    fn render(&self, tour: &mut String) {
+        for block in self.blocks.iter() {
+            match block {
+                Block::Prose(lines) => {
+                    let text = lines.join("\n");
+                    tour.push_str(text.trim());
+                    tour.push_str("\n\n");
+                }
+                Block::Code { lines, output } => {
+                    let code = lines.join("\n");
+                    let code = code.trim_end();
+                    if !code.is_empty() {
+                        tour.push_str(&format!("```lox\n{code}\n```\n\n"));
+                    }
+                    if !output.is_empty() {
+                        tour.push_str(&format!("```text\n{}\n```\n\n", output.join("\n")));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `rlox examples [dir] [-o path]`: checks every example under `dir`,
+/// `examples` by default, in name order, each on its own VM, then writes the
+/// tour to `path`, or prints it if no path is given. Each example's result is
+/// reported on stderr; if any fail, no tour is written and the exit status is
+/// 1.
+pub fn examples(options: &Options, args: &[String]) {
+    let mut dir = "examples";
+    let mut out = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            let Some(path) = args.next() else {
+                eprintln!("Usage: rlox examples [dir] [-o path]");
+                exit(64);
+            };
+            out = Some(path.as_str());
+        } else {
+            dir = arg;
+        }
+    }
+
+    let mut paths = Vec::new();
+    crate::collect_scripts(Path::new(dir), &mut paths);
+    let mut tour = format!(
+        "# A tour of Lox\n\n\
+         Generated by `rlox examples` from the scripts in `{dir}/`, each run \
+         and checked against the output shown here.\n\n"
+    );
+    let mut failed = 0;
+    for path in paths.iter() {
+        let path = path.to_string_lossy();
+        let source = read_file(&path);
+        let example = Example::parse(&source);
+
+        let output = OutputBuffer::default();
+        let mut allocator = options.allocator();
+        let mut vm = options.vm(&mut allocator);
+        vm.set_output(Box::new(output.clone()));
+        let result = vm.interpret_sources(&[(&path, source.as_str())]);
+        let printed = String::from_utf8_lossy(&output.0.take()).into_owned();
+        match example.check(&printed, &result) {
+            Ok(()) => eprintln!("PASS {path}"),
+            Err(failure) => {
+                failed += 1;
+                eprintln!("FAIL {path}");
+                for line in failure.lines() {
+                    eprintln!("    {line}");
+                }
+            }
+        }
+        example.render(&mut tour);
+    }
+
+    eprintln!("{} passed, {failed} failed", paths.len() - failed);
+    if failed > 0 {
+        exit(1);
+    }
+    let tour = tour.trim_end();
+    match out {
+        Some(path) => {
+            if let Err(error) = std::fs::write(path, format!("{tour}\n")) {
+                eprintln!("Failed to write {path}: {error}");
+                exit(74);
+            }
+        }
+        None => println!("{tour}"),
+    }
+}
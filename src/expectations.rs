@@ -0,0 +1,122 @@
+//! Reading what a test script should do from its comments, and comparing that
+//! with what it did. Both `rlox test` and the integration tests use these.
+//!
+//! - `// expect: text` expects `text` as the next line printed.
+//! - `// expect runtime error: message` expects the script to stop with an
+//!   uncaught runtime error with that message.
+//! - `// Error at 'x': message` expects a compile error on the comment's
+//!   line; `// [line N] Error ...` expects one on line `N`, as does
+//!   `// [c line N] Error ...`, the form the Crafting Interpreters suite
+//!   uses for errors only its C implementation reports.
+
+use crate::{CompileError, LoxError};
+
+/// What a script printed and the errors it stopped with, or what its comments
+/// say it should.
+#[derive(Default, PartialEq, Debug)]
+pub struct Outcome {
+    pub output: Vec<String>,
+    pub compile_errors: Vec<String>,
+    pub runtime_error: Option<String>,
+}
+
+impl Outcome {
+    /// What the comments in `source` say running it should do.
+    pub fn expected(source: &str) -> Outcome {
+        let mut expected = Outcome::default();
+        for (i, line) in source.lines().enumerate() {
+            let Some((_, comment)) = line.split_once("// ") else {
+                continue;
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expected.output.push(output.to_owned());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expected.runtime_error = Some(message.to_owned());
+            } else if comment.starts_with("[line ") {
+                expected.compile_errors.push(comment.to_owned());
+            } else if let Some(error) = comment.strip_prefix("[c line ") {
+                expected.compile_errors.push(format!("[line {error}"));
+            } else if comment.starts_with("Error") {
+                expected
+                    .compile_errors
+                    .push(format!("[line {}] {comment}", i + 1));
+            }
+        }
+        expected
+    }
+
+    /// What a script that printed `printed` and ended with `result` did.
+    pub fn actual(printed: &str, result: &Result<(), LoxError>) -> Outcome {
+        let mut outcome = Outcome {
+            output: printed.lines().map(str::to_owned).collect(),
+            ..Outcome::default()
+        };
+        match result {
+            Ok(()) => (),
+            Err(LoxError::Compile(errors)) => {
+                // Expectations only give the line, whichever file it's in
+                outcome.compile_errors = errors
+                    .iter()
+                    .map(|error| {
+                        let error = CompileError {
+                            file: None,
+                            ..error.clone()
+                        };
+                        error.to_string()
+                    })
+                    .collect();
+            }
+            Err(
+                LoxError::Runtime(error)
+                | LoxError::LimitExceeded(error)
+                | LoxError::Interrupted(error),
+            ) => outcome.runtime_error = Some(error.message.clone()),
+        }
+        outcome
+    }
+
+    /// Describes how `self`, what a script did, differs from `expected`, one
+    /// line per difference.
+    pub fn differences(&self, expected: &Outcome) -> Vec<String> {
+        let mut differences = Vec::new();
+        let lines = self.output.len().max(expected.output.len());
+        for i in 0..lines {
+            match (self.output.get(i), expected.output.get(i)) {
+                (Some(actual), Some(expected)) if actual == expected => (),
+                (Some(actual), Some(expected)) => differences.push(format!(
+                    "Expected output '{expected}' on line {}, but got '{actual}'.",
+                    i + 1
+                )),
+                (Some(actual), None) => {
+                    differences.push(format!("Got unexpected output '{actual}'."))
+                }
+                (None, Some(expected)) => {
+                    differences.push(format!("Missing expected output '{expected}'."))
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+        for error in expected.compile_errors.iter() {
+            if !self.compile_errors.contains(error) {
+                differences.push(format!("Missing expected compile error: {error}"));
+            }
+        }
+        for error in self.compile_errors.iter() {
+            if !expected.compile_errors.contains(error) {
+                differences.push(format!("Unexpected compile error: {error}"));
+            }
+        }
+        match (&self.runtime_error, &expected.runtime_error) {
+            (Some(actual), Some(expected)) if actual == expected => (),
+            (Some(actual), Some(expected)) => differences.push(format!(
+                "Expected runtime error '{expected}', but got '{actual}'."
+            )),
+            (Some(actual), None) => differences.push(format!("Unexpected runtime error: {actual}")),
+            (None, Some(expected)) => {
+                differences.push(format!("Missing expected runtime error: {expected}"))
+            }
+            (None, None) => (),
+        }
+        differences
+    }
+}
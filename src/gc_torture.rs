@@ -0,0 +1,332 @@
+//! `rlox gc-torture`: generates random programs heavy on closures, upvalues,
+//! strings, lists, and maps, and runs each twice: once never collecting, and
+//! once collecting before every allocation with the heap verified after each
+//! collection. A collector bug shows up as a failed verification or as the
+//! two runs printing different output.
+
+use rlox::{memory, NoCollect, Stress, VmBuilder};
+use std::cell::RefCell;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+/// A small xorshift generator, so a failing program can be regenerated from
+/// its seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Xorshift gets stuck at zero
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    String,
+    // A non-empty list of strings
+    List,
+    // A map from strings to strings
+    Map,
+    // A function taking and returning a string
+    Function,
+}
+
+/// Writes a random program. Expressions never join two variables, so strings
+/// grow linearly with the program's length rather than exponentially.
+struct Generator {
+    rng: Rng,
+    source: String,
+    // The variables in scope, innermost scope last
+    scopes: Vec<Vec<(String, Type)>>,
+    next_name: usize,
+    indent: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        Generator {
+            rng: Rng::new(seed),
+            source: String::new(),
+            scopes: vec![Vec::new()],
+            next_name: 0,
+            indent: 0,
+        }
+    }
+
+    fn program(mut self) -> String {
+        for _ in 0..3 {
+            self.declare(Type::String);
+        }
+        for _ in 0..20 + self.rng.below(20) {
+            self.statement(0);
+        }
+        for (name, _) in self.scopes[0].clone() {
+            self.line(&format!("print {name};"));
+        }
+        self.source
+    }
+
+    fn line(&mut self, line: &str) {
+        self.source.push_str(&"  ".repeat(self.indent));
+        self.source.push_str(line);
+        self.source.push('\n');
+    }
+
+    fn fresh_name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{prefix}{}", self.next_name)
+    }
+
+    fn literal(&mut self) -> String {
+        format!("\"s{}\"", self.rng.below(100))
+    }
+
+    fn variable(&mut self, wanted: Type) -> Option<String> {
+        let candidates: Vec<String> = self
+            .scopes
+            .iter()
+            .flatten()
+            .filter(|(_, variable_type)| *variable_type == wanted)
+            .map(|(name, _)| name.clone())
+            .collect();
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(self.rng.pick(&candidates).clone())
+        }
+    }
+
+    /// A short expression producing a string.
+    fn atom(&mut self) -> String {
+        let variable = match self.rng.below(4) {
+            0 => self.variable(Type::String),
+            1 => self.variable(Type::List).map(|list| format!("{list}[0]")),
+            2 => {
+                let literal = self.literal();
+                self.variable(Type::Function)
+                    .map(|function| format!("{function}({literal})"))
+            }
+            _ => None,
+        };
+        variable.unwrap_or_else(|| self.literal())
+    }
+
+    fn string_expression(&mut self) -> String {
+        let atom = self.atom();
+        match self.rng.below(3) {
+            0 => atom,
+            1 => format!("{atom} + {}", self.literal()),
+            _ => format!("{} + {atom}", self.literal()),
+        }
+    }
+
+    fn declare(&mut self, variable_type: Type) {
+        let name = self.fresh_name("v");
+        let value = match variable_type {
+            Type::String => self.string_expression(),
+            Type::List => format!("[{}, {}]", self.string_expression(), self.literal()),
+            Type::Map => format!("{{{}: {}}}", self.literal(), self.string_expression()),
+            Type::Function => {
+                self.closure_factory(&name);
+                return;
+            }
+        };
+        self.line(&format!("var {name} = {value};"));
+        self.scopes.last_mut().unwrap().push((name, variable_type));
+    }
+
+    /// Declares `name` as a closure over an accumulator it appends to.
+    fn closure_factory(&mut self, name: &str) {
+        let factory = self.fresh_name("make");
+        let accumulator = self.fresh_name("acc");
+        let initial = self.string_expression();
+        self.line(&format!("fun {factory}(p) {{"));
+        self.indent += 1;
+        self.scopes.push(vec![("p".to_owned(), Type::String)]);
+        self.line(&format!("var {accumulator} = p + {initial};"));
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .push((accumulator.clone(), Type::String));
+        self.statement(2);
+        self.line("fun step(x) {");
+        self.indent += 1;
+        self.scopes.push(vec![("x".to_owned(), Type::String)]);
+        self.statement(2);
+        self.line(&format!("{accumulator} = {accumulator} + x;"));
+        self.line(&format!("return {accumulator};"));
+        self.scopes.pop();
+        self.indent -= 1;
+        self.line("}");
+        self.line("return step;");
+        self.scopes.pop();
+        self.indent -= 1;
+        self.line("}");
+        let argument = self.literal();
+        self.line(&format!("var {name} = {factory}({argument});"));
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .push((name.to_owned(), Type::Function));
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let choice = if depth >= 2 {
+            self.rng.below(4)
+        } else {
+            self.rng.below(9)
+        };
+        match choice {
+            0 => {
+                let expression = self.string_expression();
+                self.line(&format!("print {expression};"));
+            }
+            1 => {
+                let Some(name) = self.variable(Type::String) else {
+                    return self.declare(Type::String);
+                };
+                let expression = self.string_expression();
+                self.line(&format!("{name} = {expression};"));
+            }
+            2 => {
+                let Some(list) = self.variable(Type::List) else {
+                    return self.declare(Type::List);
+                };
+                let expression = self.string_expression();
+                self.line(&format!("push({list}, {expression});"));
+            }
+            3 => {
+                let Some(map) = self.variable(Type::Map) else {
+                    return self.declare(Type::Map);
+                };
+                let key = self.literal();
+                let expression = self.string_expression();
+                self.line(&format!("{map}[{key}] = {expression};"));
+            }
+            4 => {
+                let variable_type = *self.rng.pick(&[Type::String, Type::List, Type::Map]);
+                self.declare(variable_type);
+            }
+            5 => self.declare(Type::Function),
+            6 => {
+                // Closures capturing a loop's variables, called after the loop
+                let list = self.fresh_name("fs");
+                let count = 1 + self.rng.below(5);
+                self.line(&format!("var {list} = [];"));
+                self.line(&format!("for (var i = 0; i < {count}; i = i + 1) {{"));
+                self.indent += 1;
+                self.scopes.push(Vec::new());
+                self.declare(Type::String);
+                let captured = self.scopes.last().unwrap()[0].0.clone();
+                self.line(&format!("fun f() {{ return {captured} + \"!\"; }}"));
+                self.line(&format!("push({list}, f);"));
+                self.statement(depth + 1);
+                self.scopes.pop();
+                self.indent -= 1;
+                self.line("}");
+                self.line(&format!("for (var f in {list}) print f();"));
+            }
+            7 => {
+                self.line("{");
+                self.indent += 1;
+                self.scopes.push(Vec::new());
+                for _ in 0..1 + self.rng.below(4) {
+                    self.statement(depth + 1);
+                }
+                self.scopes.pop();
+                self.indent -= 1;
+                self.line("}");
+            }
+            _ => {
+                let thrown = self.string_expression();
+                self.line(&format!("try {{ throw {thrown}; }} catch (e) {{"));
+                self.indent += 1;
+                self.scopes.push(vec![("e".to_owned(), Type::String)]);
+                self.statement(depth + 1);
+                self.scopes.pop();
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+/// Collects what a run prints, so the two runs' output can be compared.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `source`, returning what it printed or why it failed.
+fn run(source: &str, stress: bool) -> Result<String, String> {
+    let mut allocator = memory::Allocator::new();
+    let builder = VmBuilder::new(&mut allocator);
+    let builder = if stress {
+        builder.collector(Stress)
+    } else {
+        builder.collector(NoCollect)
+    };
+    let mut vm = builder.build().map_err(|error| error.to_string())?;
+    vm.set_verify_heap(stress);
+    let output = Output::default();
+    vm.set_output(Box::new(output.clone()));
+    // Generated programs declare plenty of variables they never read
+    vm.set_warnings(Box::new(std::io::sink()));
+    vm.interpret(source.to_owned())
+        .map_err(|error| error.to_string())?;
+    let printed = output.0.borrow().clone();
+    Ok(String::from_utf8_lossy(&printed).into_owned())
+}
+
+/// Runs `runs` random programs starting from `seed`, returning the first one
+/// that fails and the reason. The seed being run is shown on stderr, since a
+/// use of a freed object may crash the process before it can be reported.
+pub fn torture(seed: u64, runs: u64) -> Result<(), (u64, String, String)> {
+    for seed in seed..seed + runs {
+        eprint!("\rseed {seed}");
+        let source = Generator::new(seed).program();
+        let baseline = match run(&source, false) {
+            Ok(output) => output,
+            Err(error) => return Err((seed, source, format!("generated program failed: {error}"))),
+        };
+        let stressed = panic::catch_unwind(AssertUnwindSafe(|| run(&source, true)));
+        match stressed {
+            Ok(Ok(output)) if output == baseline => (),
+            Ok(Ok(output)) => {
+                let message = format!(
+                    "output differs under stress collection:\n{output}\nexpected:\n{baseline}"
+                );
+                return Err((seed, source, message));
+            }
+            Ok(Err(error)) => return Err((seed, source, error)),
+            Err(_) => return Err((seed, source, "panicked".to_owned())),
+        }
+    }
+    eprintln!();
+    Ok(())
+}
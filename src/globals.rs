@@ -0,0 +1,109 @@
+//! A VM's global variables, stored by slot. The compiler resolves each global
+//! name in code the VM compiles to a slot once, so reading or writing a
+//! global at runtime is an index rather than a hash lookup. Bytecode compiled
+//! without a VM, like a `.loxc` file, names its globals instead, and those
+//! names are looked up here on every access.
+
+use crate::memory::Gc;
+use crate::object_string::ObjString;
+use crate::value::Value;
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct Globals {
+    // Keyed by interned name
+    slots: HashMap<Gc<ObjString>, usize>,
+    names: Vec<Gc<ObjString>>,
+    // A slot holds `None` until its global is defined
+    values: Vec<Option<Value>>,
+    // Whether each slot's global was declared `const`
+    consts: Vec<bool>,
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals::default()
+    }
+
+    /// The slot for the interned `name`, assigning the next free one if it
+    /// hasn't been seen before. Slots are never reused, so compiled code can
+    /// keep referring to one.
+    pub fn slot(&mut self, name: Gc<ObjString>) -> usize {
+        *self.slots.entry(name).or_insert_with(|| {
+            self.names.push(name);
+            self.values.push(None);
+            self.consts.push(false);
+            self.names.len() - 1
+        })
+    }
+
+    /// The name the global in `slot` was given.
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot].get().str
+    }
+
+    pub fn get(&self, name: Gc<ObjString>) -> Option<&Value> {
+        self.get_slot(*self.slots.get(&name)?)
+    }
+
+    pub fn get_slot(&self, slot: usize) -> Option<&Value> {
+        self.values[slot].as_ref()
+    }
+
+    /// Defines the global `name`, or redefines it if it already exists.
+    pub fn define(&mut self, name: Gc<ObjString>, value: Value) {
+        let slot = self.slot(name);
+        self.values[slot] = Some(value);
+    }
+
+    pub fn define_slot(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    /// Defines the global in `slot` as a constant, which scripts can't
+    /// assign to or redefine. The VM enforces that; a host can still
+    /// redefine it.
+    pub fn define_const_slot(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+        self.consts[slot] = true;
+    }
+
+    pub fn is_const(&self, slot: usize) -> bool {
+        self.consts[slot]
+    }
+
+    /// Assigns to an existing global, returning false without assigning if
+    /// it hasn't been defined.
+    pub fn assign(&mut self, name: Gc<ObjString>, value: Value) -> bool {
+        let slot = self.slot(name);
+        self.assign_slot(slot, value)
+    }
+
+    pub fn assign_slot(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(global) => {
+                *global = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every defined global's name and value, in the order their slots were
+    /// assigned.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.names
+            .iter()
+            .zip(self.values.iter())
+            .filter_map(|(name, value)| Some((name.get().str.as_str(), value.as_ref()?)))
+    }
+
+    /// Every name given a slot, whether or not its global is defined yet.
+    pub fn names(&self) -> impl Iterator<Item = Gc<ObjString>> + '_ {
+        self.names.iter().copied()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flatten()
+    }
+}
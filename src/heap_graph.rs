@@ -0,0 +1,162 @@
+//! Snapshots of the heap as a graph of objects and the references between
+//! them, written as Graphviz DOT or JSON for visualization.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::memory::{Allocator, Gc, GC};
+
+/// Labels longer than this are cut short, so long strings don't swamp a graph.
+const MAX_LABEL_LEN: usize = 40;
+
+pub struct HeapNode {
+    /// The object's address, which identifies it in edges.
+    pub id: usize,
+    pub kind: &'static str,
+    pub label: String,
+    pub size: usize,
+    /// The ids of the objects this one refers to.
+    pub references: Vec<usize>,
+}
+
+/// A named reference into the heap from outside it, like a global variable.
+pub struct HeapRoot {
+    pub name: String,
+    pub id: usize,
+}
+
+pub struct HeapGraph {
+    pub nodes: Vec<HeapNode>,
+    pub roots: Vec<HeapRoot>,
+}
+
+fn id(object: Gc<dyn GC>) -> usize {
+    object.as_ptr() as *const () as usize
+}
+
+impl HeapGraph {
+    /// Walks every object `allocator` has allocated. References to objects
+    /// that live outside the allocator, like the compiler's string constants,
+    /// are left out.
+    pub fn new(allocator: &Allocator) -> HeapGraph {
+        let objects = allocator.objects();
+        let ids: HashSet<usize> = objects.iter().map(|object| id(*object)).collect();
+        let nodes = objects
+            .iter()
+            .map(|object| {
+                let object_ref = object.get();
+                let mut label = object_ref.label();
+                if let Some((cut, _)) = label.char_indices().nth(MAX_LABEL_LEN) {
+                    label.truncate(cut);
+                    label.push_str("...");
+                }
+                HeapNode {
+                    id: id(*object),
+                    kind: object_ref.kind(),
+                    label,
+                    size: object_ref.layout().size(),
+                    references: object_ref
+                        .references()
+                        .into_iter()
+                        .map(id)
+                        .filter(|reference| ids.contains(reference))
+                        .collect(),
+                }
+            })
+            .collect();
+        HeapGraph {
+            nodes,
+            roots: Vec::new(),
+        }
+    }
+
+    /// Records that `object` is reachable from outside the heap as `name`.
+    pub fn add_root(&mut self, name: &str, object: Gc<dyn GC>) {
+        self.roots.push(HeapRoot {
+            name: name.to_owned(),
+            id: id(object),
+        });
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph heap {\n    node [shape=box];\n");
+        for node in self.nodes.iter() {
+            writeln!(
+                out,
+                "    o{:x} [label=\"{}\\n{}\"];",
+                node.id,
+                node.kind,
+                escape(&node.label)
+            )
+            .unwrap();
+        }
+        for (i, root) in self.roots.iter().enumerate() {
+            writeln!(
+                out,
+                "    r{i} [label=\"{}\", shape=plaintext];\n    r{i} -> o{:x};",
+                escape(&root.name),
+                root.id
+            )
+            .unwrap();
+        }
+        for node in self.nodes.iter() {
+            for reference in node.references.iter() {
+                writeln!(out, "    o{:x} -> o{:x};", node.id, reference).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n  \"nodes\": [");
+        for (i, node) in self.nodes.iter().enumerate() {
+            let references: Vec<String> = node
+                .references
+                .iter()
+                .map(|reference| reference.to_string())
+                .collect();
+            write!(
+                out,
+                "{}\n    {{\"id\": {}, \"kind\": \"{}\", \"label\": \"{}\", \"size\": {}, \"references\": [{}]}}",
+                if i > 0 { "," } else { "" },
+                node.id,
+                node.kind,
+                escape(&node.label),
+                node.size,
+                references.join(", ")
+            )
+            .unwrap();
+        }
+        out.push_str("\n  ],\n  \"roots\": [");
+        for (i, root) in self.roots.iter().enumerate() {
+            write!(
+                out,
+                "{}\n    {{\"name\": \"{}\", \"id\": {}}}",
+                if i > 0 { "," } else { "" },
+                escape(&root.name),
+                root.id
+            )
+            .unwrap();
+        }
+        out.push_str("\n  ]\n}\n");
+        out
+    }
+}
+
+/// Escapes `text` for a double-quoted string, which DOT and JSON both
+/// escape the same way.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
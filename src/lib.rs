@@ -0,0 +1,48 @@
+//! A bytecode compiler and virtual machine for the Lox language, for embedding
+//! in other Rust programs. The `rlox` binary is a thin command-line wrapper.
+
+pub mod bytecode;
+pub mod chunk;
+pub mod chunk_diff;
+pub mod collector;
+pub mod compiler;
+pub mod debug;
+mod edit_distance;
+pub mod error;
+pub mod expectations;
+pub mod globals;
+pub mod heap_graph;
+pub mod memory;
+mod methods;
+pub mod metrics;
+mod natives;
+pub mod object_bytes;
+pub mod object_closure;
+pub mod object_function;
+pub mod object_list;
+pub mod object_map;
+pub mod object_native;
+pub mod object_string;
+pub mod object_upvalue;
+pub mod peephole;
+pub mod profile;
+pub mod scanner;
+pub mod session;
+pub mod stdlib;
+pub mod value;
+pub mod vm;
+pub mod vm_builder;
+
+pub use collector::{CollectorStrategy, Incremental, MarkSweep, NoCollect, Stress};
+pub use compiler::{Compiler, CompilerLimits};
+pub use error::{
+    CompileError, CompileWarning, LoxError, RuntimeError, SourceRange, StackFrame, StackTrace,
+    Warnings,
+};
+pub use memory::{Allocator, GcMetrics};
+pub use object_native::NativeError;
+pub use profile::Profile;
+pub use session::Session;
+pub use value::{IntoValue, Value};
+pub use vm::{InterruptHandle, VM};
+pub use vm_builder::VmBuilder;
@@ -1,57 +1,975 @@
-mod chunk;
-mod compiler;
-mod debug;
-mod memory;
-mod object_closure;
-mod object_function;
-mod object_native;
-mod object_string;
-mod object_upvalue;
-mod scanner;
-mod value;
-mod vm;
+mod editor;
+mod examples;
+mod gc_torture;
 
+use editor::{AutoIndent, LoxHelper};
+use rlox::expectations::Outcome;
+use rlox::memory::Gc;
+use rlox::object_function::ObjFunction;
+use rlox::object_string::ObjString;
+use rlox::scanner::{Scanner, TokenType};
+use rlox::vm::SLOTS_PER_FRAME;
+use rlox::{bytecode, chunk_diff, compiler, debug, memory, metrics};
+use rlox::{Incremental, LoxError, Session, Value, VM};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Editor, EventHandler, KeyCode, KeyEvent, Modifiers};
+use signal_hook::consts::SIGINT;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::{io::Read, process::exit};
-use vm::{InterpretResult, VM};
+
+/// Settings from the global flags, which may appear anywhere on the command
+/// line, applied to every VM the command creates.
+struct Options {
+    debug_info: bool,
+    optimize: bool,
+    disassemble: bool,
+    trace_execution: bool,
+    stress_gc: bool,
+    incremental_gc: bool,
+    log_gc: bool,
+    quiet: bool,
+    vm_stats: bool,
+    track_allocations: bool,
+    leak_report: bool,
+    warnings: bool,
+    stdlib: bool,
+    // Where to write the heap graph after running, as JSON if the path ends
+    // with `.json` and as DOT otherwise
+    heap_graph: Option<String>,
+    // How deeply calls may nest, if not the VM's default
+    max_frames: Option<usize>,
+    // Where to write the time spent in each phase as JSON, if anywhere
+    self_profile: Option<String>,
+}
+
+impl Options {
+    /// Removes the global flags from `args`. Of `--strip` and `--debug-info`,
+    /// the last one wins; `--quiet` overrides the debug output flags.
+    ///
+    /// Flags are only read up to the script: everything after its path, or
+    /// after the code given with `-e`, is left for the script as it is. A
+    /// subcommand reads its own arguments, so flags are read among those,
+    /// up to any `--`. Otherwise `--` ends the flags and is removed.
+    fn parse(args: &mut Vec<String>) -> Options {
+        let mut options = Options {
+            debug_info: true,
+            optimize: false,
+            disassemble: false,
+            trace_execution: false,
+            stress_gc: false,
+            incremental_gc: false,
+            log_gc: false,
+            quiet: false,
+            vm_stats: false,
+            track_allocations: false,
+            leak_report: false,
+            warnings: true,
+            stdlib: true,
+            heap_graph: None,
+            max_frames: None,
+            self_profile: None,
+        };
+        let mut subcommand = false;
+        let mut i = 1;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            if arg == "--" {
+                if !subcommand {
+                    args.remove(i);
+                }
+                break;
+            }
+            if options.apply_flag(arg) {
+                args.remove(i);
+                continue;
+            }
+            if subcommand {
+                i += 1;
+            } else if SUBCOMMANDS.contains(&arg) {
+                subcommand = true;
+                i += 1;
+            } else {
+                // The script's path, or `-e` and the code that follows it
+                break;
+            }
+        }
+        options
+    }
+
+    /// Sets the option `arg` stands for, returning false if it isn't one.
+    fn apply_flag(&mut self, arg: &str) -> bool {
+        match arg {
+            "--debug-info" => self.debug_info = true,
+            "--strip" => self.debug_info = false,
+            "-O" => self.optimize = true,
+            "--disassemble" => self.disassemble = true,
+            "--trace-execution" => self.trace_execution = true,
+            "--stress-gc" => self.stress_gc = true,
+            "--incremental-gc" => self.incremental_gc = true,
+            "--log-gc" => self.log_gc = true,
+            "--quiet" => self.quiet = true,
+            "--vm-stats" => self.vm_stats = true,
+            "--track-allocations" => self.track_allocations = true,
+            "--leak-report" => self.leak_report = true,
+            "--no-warnings" => self.warnings = false,
+            "--no-stdlib" => self.stdlib = false,
+            "--self-profile" => self.self_profile = Some(SELF_PROFILE.to_owned()),
+            _ => {
+                if let Some(path) = arg.strip_prefix("--heap-graph=") {
+                    self.heap_graph = Some(path.to_owned());
+                } else if let Some(path) = arg.strip_prefix("--self-profile=") {
+                    self.self_profile = Some(path.to_owned());
+                } else if let Some(depth) = arg.strip_prefix("--max-frames=") {
+                    match depth.parse::<usize>() {
+                        Ok(depth) if depth > 0 => self.max_frames = Some(depth),
+                        _ => {
+                            eprintln!("--max-frames expects a positive number.");
+                            exit(64);
+                        }
+                    }
+                } else {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// An allocator that checks every free against its allocation if asked
+    /// to with `--track-allocations`.
+    fn allocator(&self) -> memory::Allocator {
+        if self.track_allocations {
+            memory::Allocator::with_tracking()
+        } else {
+            memory::Allocator::new()
+        }
+    }
+
+    fn vm<'a>(&self, allocator: &'a mut memory::Allocator) -> VM<'a> {
+        let mut vm = VM::new(allocator, self.stress_gc, self.log_gc && !self.quiet);
+        if self.incremental_gc && !self.stress_gc {
+            vm.set_collector(Box::new(Incremental::default()));
+        }
+        // Loaded before the debug output is turned on, so it only shows
+        // the user's code
+        if self.stdlib {
+            if let Err(error) = vm.load_stdlib() {
+                eprintln!("Failed to load the standard library: {error}");
+                exit(70);
+            }
+        }
+        vm.set_debug_info(self.debug_info);
+        vm.set_optimize(self.optimize);
+        vm.set_print_code(self.disassemble && !self.quiet);
+        vm.set_trace_execution(self.trace_execution && !self.quiet);
+        if !self.warnings {
+            vm.set_warnings(Box::new(std::io::sink()));
+        }
+        if let Some(depth) = self.max_frames {
+            vm.set_max_frames(depth);
+            vm.set_stack_max(depth * SLOTS_PER_FRAME);
+        }
+        vm.set_self_profile(self.self_profile.is_some());
+        interrupt_on_ctrl_c(&vm);
+        vm
+    }
+
+    /// Prints `vm`'s statistics if asked to with `--vm-stats`, lists the
+    /// objects still alive if asked to with `--leak-report`, and writes its
+    /// heap graph if asked to with `--heap-graph` and its profile if asked to
+    /// with `--self-profile`, then exits if the script it ran failed with
+    /// `status`.
+    fn finish(&self, vm: &mut VM, status: i32) {
+        if self.vm_stats {
+            eprintln!("{}", vm.gc_metrics());
+        }
+        if self.leak_report {
+            eprint!("{}", vm.leak_report());
+        }
+        if let Some(path) = &self.heap_graph {
+            let graph = vm.heap_graph();
+            let contents = if path.ends_with(".json") {
+                graph.to_json()
+            } else {
+                graph.to_dot()
+            };
+            if let Err(error) = std::fs::write(path, contents) {
+                eprintln!("Failed to write {path}: {error}");
+                exit(74);
+            }
+        }
+        if let (Some(path), Some(profile)) = (&self.self_profile, vm.profile()) {
+            if let Err(error) = std::fs::write(path, profile.to_json()) {
+                eprintln!("Failed to write {path}: {error}");
+                exit(74);
+            }
+        }
+        if status != 0 {
+            exit(status);
+        }
+    }
+}
+
+/// The commands `rlox` runs in place of a script.
+const SUBCOMMANDS: [&str; 10] = [
+    "run",
+    "compile",
+    "strip",
+    "metrics",
+    "disasm",
+    "tokens",
+    "check",
+    "test",
+    "examples",
+    "gc-torture",
+];
+
+/// The exit status after an internal error in the VM, as opposed to the 70
+/// of an error in the script.
+const CRASH_STATUS: i32 = 71;
+/// The exit status when Ctrl-C stops a script, as for a process killed by
+/// SIGINT.
+const INTERRUPTED_STATUS: i32 = 130;
+/// Where a crash report is written, in the current directory.
+const CRASH_REPORT: &str = ".rlox-crash";
+/// Where `--self-profile` writes the profile unless given a path.
+const SELF_PROFILE: &str = "rlox-profile.json";
+/// The message and location of the latest panic, saved by the hook `main`
+/// installs so a crash report can include them.
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let mut garbage_collector = memory::Allocator::new();
-    let mut vm = VM::new(&mut garbage_collector, true, true);
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        *LAST_PANIC.lock().unwrap() = Some(info.to_string());
+        default_hook(info);
+    }));
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let options = Options::parse(&mut args);
+
+    let mut garbage_collector = options.allocator();
     if args.len() == 1 {
-        repl(&mut vm);
-    } else if args.len() == 2 {
-        run_file(&mut vm, args[1].as_str());
+        repl(&options, options.vm(&mut garbage_collector));
+    } else if args[1] == "run" {
+        run(&options, &args[2..]);
+    } else if args[1] == "compile" {
+        compile(&options, &args[2..]);
+    } else if args[1] == "strip" {
+        strip(&args[2..]);
+    } else if args[1] == "metrics" {
+        metrics(&args[2..]);
+    } else if args[1] == "disasm" {
+        disasm(&args[2..]);
+    } else if args[1] == "tokens" {
+        tokens(&args[2..]);
+    } else if args[1] == "check" {
+        check(&options, &args[2..]);
+    } else if args[1] == "test" {
+        test(&options, &args[2..]);
+    } else if args[1] == "examples" {
+        examples::examples(&options, &args[2..]);
+    } else if args[1] == "gc-torture" {
+        gc_torture(&args[2..]);
+    } else if args[1] == "-e" {
+        let Some(code) = args.get(2) else {
+            eprintln!(
+                "Usage: clox [-O] [--disassemble] [--trace-execution] [--stress-gc] [--incremental-gc] [--log-gc] [--quiet] [--vm-stats] [--track-allocations] [--leak-report] [--no-warnings] [--no-stdlib] [--heap-graph=path] [--max-frames=N] [--self-profile[=path]] [--] [path | -e code | -] [args]...\n"
+            );
+            exit(64);
+        };
+        let mut vm = options.vm(&mut garbage_collector);
+        set_script_args(&mut vm, &args[3..]);
+        let status = run_source(&mut vm, "<command line>", code.as_str());
+        options.finish(&mut vm, status);
+    } else if args[1] == "-" {
+        let mut source = String::new();
+        std::io::stdin()
+            .read_to_string(&mut source)
+            .expect("Failed to read stdin");
+        let mut vm = options.vm(&mut garbage_collector);
+        set_script_args(&mut vm, &args[2..]);
+        let status = run_source(&mut vm, "<stdin>", source.as_str());
+        options.finish(&mut vm, status);
     } else {
-        eprintln!("Usage: clox [path]\n");
-        exit(64);
+        let mut vm = options.vm(&mut garbage_collector);
+        set_script_args(&mut vm, &args[2..]);
+        let status = run_file(&mut vm, args[1].as_str());
+        options.finish(&mut vm, status);
+    }
+}
+
+/// Makes Ctrl-C stop the script `vm` is running. A second Ctrl-C before the VM
+/// has noticed the first, such as while a native blocks on input, exits
+/// straight away.
+fn interrupt_on_ctrl_c(vm: &VM) {
+    let flag = vm.interrupt_handle().flag().clone();
+    let registered =
+        signal_hook::flag::register_conditional_shutdown(SIGINT, INTERRUPTED_STATUS, flag.clone())
+            .and_then(|_| signal_hook::flag::register(SIGINT, flag));
+    if let Err(error) = registered {
+        eprintln!("Failed to handle Ctrl-C: {error}");
     }
 }
 
-fn repl(vm: &mut VM) {
-    let mut line = String::new();
+/// Exposes the arguments after the script to it as the global list `args`.
+fn set_script_args(vm: &mut VM, args: &[String]) {
+    vm.set_global("args", args.to_vec());
+}
+
+/// Reads and evaluates lines from stdin. Input with brackets still open
+/// continues onto the next line. With `--quiet`, no prompt is shown, so piped
+/// output holds only the program's own. Warnings are shown in yellow when
+/// stderr is a terminal.
+fn repl(options: &Options, mut vm: VM) {
+    if options.warnings && std::io::stderr().is_terminal() {
+        vm.set_warnings(Box::new(Yellow(std::io::stderr())));
+    }
+    let mut session = Session::from_vm(vm);
+    let mut editor: Editor<LoxHelper, DefaultHistory> =
+        Editor::new().expect("Failed to set up line editor");
+    editor.set_helper(Some(LoxHelper));
+    editor.bind_sequence(
+        KeyEvent(KeyCode::Enter, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(AutoIndent)),
+    );
+    let prompt = if options.quiet { "" } else { "> " };
     loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => panic!("Failed to read line: {error}"),
+        };
+        let _ = editor.add_history_entry(line.as_str());
 
-        std::io::stdin()
-            .read_line(&mut line)
-            .expect("Failed to read line");
+        let result = report_crashes(
+            &mut session,
+            |session, report| session.vm().write_crash_report(report),
+            |session| session.eval(line.as_str()),
+        );
+        match result {
+            Ok(Some(value)) => println!("{value}"),
+            Ok(None) => (),
+            // Functions from earlier lines run as well, so only a compile
+            // error is sure to be in this one
+            Err(error @ LoxError::Compile(_)) => {
+                eprintln!("{}", error.with_excerpts(None, &line))
+            }
+            Err(error) => eprintln!("{error}"),
+        }
+    }
+    options.finish(session.vm(), 0);
+}
+
+/// Colors everything written through it yellow.
+struct Yellow<W: Write>(W);
 
-        vm.interpret(line.clone());
+impl<W: Write> Write for Yellow<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(b"\x1b[33m")?;
+        self.0.write_all(buf)?;
+        self.0.write_all(b"\x1b[0m")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
     }
 }
 
-fn run_file(vm: &mut VM, path: &str) {
+/// Runs a script, or a program compiled by `rlox compile` if `path` ends with
+/// `.loxc`, returning its exit status.
+fn run_file(vm: &mut VM, path: &str) -> i32 {
+    if path.ends_with(".loxc") {
+        let bytes = std::fs::read(path).unwrap_or_else(|_| panic!("Failed to read {path}"));
+        let function = match bytecode::deserialize(&bytes, vm.allocator) {
+            Ok(function) => function,
+            Err(message) => {
+                eprintln!("{path}: {message}");
+                return 65;
+            }
+        };
+        let result = report_crashes(
+            vm,
+            |vm, report| vm.write_crash_report(report),
+            |vm| vm.run_function(function),
+        );
+        if let Err(error) = &result {
+            eprintln!("{error}");
+        }
+        return exit_status(&result);
+    }
     let source = read_file(path);
-    let result = vm.interpret(source);
+    run_source(vm, path, source.as_str())
+}
 
+/// Runs `source`, attributing diagnostics to `name`, and returns its exit
+/// status; see `exit_status`.
+fn run_source(vm: &mut VM, name: &str, source: &str) -> i32 {
+    let result = report_crashes(
+        vm,
+        |vm, report| vm.write_crash_report(report),
+        |vm| vm.interpret_sources(&[(name, source)]),
+    );
+    if let Err(error) = &result {
+        eprintln!("{}", error.with_excerpts(Some(name), source));
+    }
+    exit_status(&result)
+}
+
+/// Runs `run` on `state`. If the VM panics partway through, writes a report
+/// of the panic and of the VM's state, as described by `describe`, to
+/// `.rlox-crash`, then exits with `CRASH_STATUS`. An internal error is a bug
+/// in rlox, and the report is what's needed to find it.
+fn report_crashes<S, T>(
+    state: &mut S,
+    describe: impl FnOnce(&mut S, &mut String),
+    run: impl FnOnce(&mut S) -> T,
+) -> T {
+    if let Ok(result) = panic::catch_unwind(AssertUnwindSafe(|| run(&mut *state))) {
+        return result;
+    }
+    let message = LAST_PANIC.lock().unwrap().take().unwrap_or_default();
+    let mut report = format!("rlox {} {message}\n\n", env!("CARGO_PKG_VERSION"));
+    if panic::catch_unwind(AssertUnwindSafe(|| describe(state, &mut report))).is_err() {
+        report.push_str("\n(Describing the VM panicked; the report ends here.)\n");
+    }
+    match std::fs::write(CRASH_REPORT, report) {
+        Ok(()) => eprintln!("Internal error; wrote a crash report to {CRASH_REPORT}."),
+        Err(error) => eprintln!("Internal error; failed to write {CRASH_REPORT}: {error}"),
+    }
+    exit(CRASH_STATUS);
+}
+
+/// The exit status for the result of running a script: 65 for a compile
+/// error, 70 for a runtime error or exceeded limit, and `INTERRUPTED_STATUS`
+/// when stopped by Ctrl-C.
+fn exit_status(result: &Result<(), LoxError>) -> i32 {
     match result {
-        InterpretResult::Ok => (),
-        InterpretResult::CompileError => exit(65),
-        InterpretResult::RuntimeError => exit(70),
+        Ok(()) => 0,
+        Err(LoxError::Compile(_)) => 65,
+        Err(LoxError::Runtime(_) | LoxError::LimitExceeded(_)) => 70,
+        Err(LoxError::Interrupted(_)) => INTERRUPTED_STATUS,
+    }
+}
+
+/// `rlox run [-D NAME[=VALUE]]... [--shared-globals | --jobs N] path...
+/// [-- args...]`: runs scripts with compile-time constants defined, passing
+/// each the arguments after `--`. Values are read as
+/// booleans, nil, or numbers where possible and as strings otherwise; a bare
+/// name is defined as true. Each script gets a fresh VM unless
+/// `--shared-globals` is given, in which case they run in order in one VM,
+/// seeing each other's globals. The first script to fail stops the run,
+/// except with `--jobs`; see `run_jobs`.
+fn run(options: &Options, args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: rlox run [-D NAME[=VALUE]]... [--shared-globals | --jobs N] [path]... [-- args...]"
+        );
+        exit(64);
+    };
+
+    let mut defines = Vec::new();
+    let mut shared_globals = false;
+    let mut jobs = None;
+    let mut paths = Vec::new();
+    let (args, script_args) = match args.iter().position(|arg| arg == "--") {
+        Some(separator) => (&args[..separator], &args[separator + 1..]),
+        None => (args, &[][..]),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-D" {
+            let Some(define) = args.next() else { usage() };
+            defines.push(define.split_once('=').unwrap_or((define, "true")));
+        } else if arg == "--shared-globals" {
+            shared_globals = true;
+        } else if arg == "--jobs" {
+            match args.next().map(|jobs| jobs.parse::<usize>()) {
+                Some(Ok(count)) if count > 0 => jobs = Some(count),
+                _ => usage(),
+            }
+        } else {
+            paths.push(arg.as_str());
+        }
+    }
+    if paths.is_empty() || (shared_globals && jobs.is_some()) {
+        usage();
+    }
+    if let Some(jobs) = jobs {
+        run_jobs(options, &defines, script_args, &paths, jobs);
+        return;
+    }
+
+    if shared_globals {
+        let mut allocator = options.allocator();
+        let mut vm = options.vm(&mut allocator);
+        define_constants(&mut vm, &defines);
+        set_script_args(&mut vm, script_args);
+        for path in paths {
+            let status = run_file(&mut vm, path);
+            if status != 0 {
+                options.finish(&mut vm, status);
+            }
+        }
+        options.finish(&mut vm, 0);
+    } else {
+        for path in paths {
+            let mut allocator = options.allocator();
+            let mut vm = options.vm(&mut allocator);
+            define_constants(&mut vm, &defines);
+            set_script_args(&mut vm, script_args);
+            let status = run_file(&mut vm, path);
+            options.finish(&mut vm, status);
+        }
+    }
+}
+
+/// The output of a script run by `run_jobs`, held until it can be printed in
+/// order.
+struct JobResult {
+    output: Vec<u8>,
+    errors: String,
+    status: i32,
+}
+
+/// Collects the output of `print` statements so it can be printed later.
+#[derive(Clone, Default)]
+struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs each script on its own VM across `jobs` threads. Once all have
+/// finished, prints each script's output and errors in the order given, then
+/// exits with the status of the first script that failed, if any.
+fn run_jobs(
+    options: &Options,
+    defines: &[(&str, &str)],
+    script_args: &[String],
+    paths: &[&str],
+    jobs: usize,
+) {
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<JobResult>>> = paths.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(paths.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else { break };
+                let result = run_job(options, defines, script_args, path);
+                *results[index].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    let mut status = 0;
+    for result in results {
+        let result = result.into_inner().unwrap().expect("Every job has run");
+        std::io::stdout().write_all(&result.output).unwrap();
+        eprint!("{}", result.errors);
+        if status == 0 {
+            status = result.status;
+        }
+    }
+    exit(status);
+}
+
+fn run_job(
+    options: &Options,
+    defines: &[(&str, &str)],
+    script_args: &[String],
+    path: &str,
+) -> JobResult {
+    let output = OutputBuffer::default();
+    let mut allocator = options.allocator();
+    let mut vm = options.vm(&mut allocator);
+    vm.set_output(Box::new(output.clone()));
+    define_constants(&mut vm, defines);
+    set_script_args(&mut vm, script_args);
+
+    let source = read_file(path);
+    let result = vm.interpret_sources(&[(path, source.as_str())]);
+    let mut errors = match &result {
+        Ok(()) => String::new(),
+        Err(error) => format!("{error}\n"),
+    };
+    if options.vm_stats {
+        errors.push_str(&format!("{}\n", vm.gc_metrics()));
+    }
+    JobResult {
+        output: output.0.take(),
+        errors,
+        status: exit_status(&result),
+    }
+}
+
+/// `rlox test path...`: runs every `.lox` file in the given directories, and
+/// any files given directly, each on its own VM, checking each against the
+/// expectations in its comments, in the forms `rlox::expectations` describes.
+/// A script passes if it prints exactly the `// expect:` lines and fails with
+/// exactly the errors it expects, so failing an `assert` fails it.
+///
+/// Prints each script's result, with how those that fail went wrong, then
+/// the totals, exiting with 1 if any failed.
+fn test(options: &Options, args: &[String]) {
+    if args.is_empty() {
+        eprintln!("Usage: rlox test [path]...");
+        exit(64);
+    }
+    let mut paths = Vec::new();
+    for arg in args {
+        collect_scripts(Path::new(arg), &mut paths);
+    }
+
+    let mut failed = 0;
+    for path in paths.iter() {
+        let path = path.to_string_lossy();
+        let output = OutputBuffer::default();
+        let mut allocator = options.allocator();
+        let mut vm = options.vm(&mut allocator);
+        vm.set_output(Box::new(output.clone()));
+        let source = read_file(&path);
+        let result = vm.interpret_sources(&[(&path, source.as_str())]);
+        let printed = String::from_utf8_lossy(&output.0.take()).into_owned();
+        let actual = Outcome::actual(&printed, &result);
+        let differences = actual.differences(&Outcome::expected(&source));
+        if differences.is_empty() {
+            println!("PASS {path}");
+        } else {
+            failed += 1;
+            println!("FAIL {path}");
+            for line in differences {
+                println!("    {line}");
+            }
+        }
+    }
+
+    println!("{} passed, {failed} failed", paths.len() - failed);
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+/// Adds `path` to `scripts` if it's a file, or every `.lox` file under it, in
+/// name order, if it's a directory.
+fn collect_scripts(path: &Path, scripts: &mut Vec<PathBuf>) {
+    if !path.is_dir() {
+        scripts.push(path.to_owned());
+        return;
+    }
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+        .unwrap_or_else(|_| panic!("Failed to read directory {}", path.display()))
+        .map(|entry| entry.expect("Failed to read directory entry").path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_scripts(&entry, scripts);
+        } else if entry
+            .extension()
+            .is_some_and(|extension| extension == "lox")
+        {
+            scripts.push(entry);
+        }
+    }
+}
+
+fn define_constants(vm: &mut VM, defines: &[(&str, &str)]) {
+    for &(name, value) in defines {
+        let value = match value {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "nil" => Value::Nil,
+            _ => match value.parse::<f64>() {
+                Ok(number) => Value::Number(number),
+                Err(_) => Value::ObjString(vm.allocator.heap_alloc(ObjString::new(value))),
+            },
+        };
+        vm.define_constant(name, value);
+    }
+}
+
+/// `rlox compile path [-o output]`: compiles a script to a `.loxc` file, by
+/// default next to it, that `rlox run` can execute without re-parsing.
+fn compile(options: &Options, args: &[String]) {
+    let (path, output) = match args {
+        [path] => (path, Path::new(path).with_extension("loxc")),
+        [path, flag, output] if flag == "-o" => (path, PathBuf::from(output)),
+        _ => {
+            eprintln!("Usage: rlox compile [path] [-o output]");
+            exit(64);
+        }
+    };
+
+    let mut allocator = options.allocator();
+    let function = compile_file(&mut allocator, path, options.debug_info, options.optimize);
+    if let Err(error) = std::fs::write(&output, bytecode::serialize(function)) {
+        eprintln!("Failed to write {}: {error}", output.display());
+        exit(74);
+    }
+}
+
+/// `rlox strip path [-o output]`: removes the debug info from a `.loxc` file,
+/// by default in place, as if it had been compiled with `--strip`.
+fn strip(args: &[String]) {
+    let (path, output) = match args {
+        [path] => (path, PathBuf::from(path)),
+        [path, flag, output] if flag == "-o" => (path, PathBuf::from(output)),
+        _ => {
+            eprintln!("Usage: rlox strip [path] [-o output]");
+            exit(64);
+        }
+    };
+
+    let mut allocator = memory::Allocator::new();
+    let bytes = std::fs::read(path).unwrap_or_else(|_| panic!("Failed to read {path}"));
+    let function = bytecode::deserialize(&bytes, &mut allocator).unwrap_or_else(|message| {
+        eprintln!("{path}: {message}");
+        exit(65);
+    });
+    bytecode::strip_debug_info(function);
+    if let Err(error) = std::fs::write(&output, bytecode::serialize(function)) {
+        eprintln!("Failed to write {}: {error}", output.display());
+        exit(74);
+    }
+}
+
+fn metrics(args: &[String]) {
+    let (path, max_complexity) = match args {
+        [path] => (path, None),
+        [path, flag, max] if flag == "--max-complexity" => match max.parse::<usize>() {
+            Ok(max) => (path, Some(max)),
+            Err(_) => {
+                eprintln!("Invalid value for --max-complexity: {max}");
+                exit(64);
+            }
+        },
+        _ => {
+            eprintln!("Usage: rlox metrics [path] [--max-complexity N]");
+            exit(64);
+        }
+    };
+
+    let mut allocator = memory::Allocator::new();
+    let function = compile_file(&mut allocator, path, true, false);
+
+    let metrics = metrics::collect_metrics(function);
+    metrics::print_metrics(&metrics);
+
+    if let Some(max_complexity) = max_complexity {
+        let over_budget: Vec<_> = metrics
+            .iter()
+            .filter(|function| function.complexity > max_complexity)
+            .collect();
+        for function in over_budget.iter() {
+            eprintln!(
+                "{} has complexity {} (max {max_complexity})",
+                function.name, function.complexity
+            );
+        }
+        if !over_budget.is_empty() {
+            exit(1);
+        }
+    }
+}
+
+/// `rlox disasm path`: prints the disassembly of every function in a script
+/// or compiled `.loxc` file. `rlox disasm --diff old new` instead compares two
+/// scripts' bytecode, exiting with 1 if it differs.
+fn disasm(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: rlox disasm [path] | rlox disasm --diff [old path] [new path]");
+        exit(64);
+    };
+    if let [path] = args {
+        let mut allocator = memory::Allocator::new();
+        let function = if path.ends_with(".loxc") {
+            let bytes = std::fs::read(path).unwrap_or_else(|_| panic!("Failed to read {path}"));
+            bytecode::deserialize(&bytes, &mut allocator).unwrap_or_else(|message| {
+                eprintln!("{path}: {message}");
+                exit(65);
+            })
+        } else {
+            compile_file(&mut allocator, path, true, false)
+        };
+        print!("{}", debug::disassemble_program_to_string(function));
+        return;
+    }
+    let [flag, old_path, new_path] = args else {
+        usage()
+    };
+    if flag != "--diff" {
+        usage()
+    }
+
+    let mut allocator = memory::Allocator::new();
+    let old_function = compile_file(&mut allocator, old_path, true, false);
+    let new_function = compile_file(&mut allocator, new_path, true, false);
+
+    let (diff, changed) = chunk_diff::diff_programs(old_function, new_function);
+    print!("{diff}");
+    if changed {
+        exit(1);
+    }
+}
+
+/// `rlox tokens path`: prints the tokens the scanner reads from a script, one
+/// per line with its line number, type, and lexeme. Scan errors are printed
+/// in place of the tokens they interrupt, and exit with 65 once the whole
+/// file is scanned.
+fn tokens(args: &[String]) {
+    let [path] = args else {
+        eprintln!("Usage: rlox tokens [path]");
+        exit(64);
+    };
+    let source = read_file(path);
+    let mut scanner = Scanner::with_file(path, source.as_str());
+    let mut line = 0;
+    let mut failed = false;
+    loop {
+        let token = scanner.scan_token();
+        let token_line = match &token {
+            Ok(token) => token.line,
+            Err(_) => scanner.line,
+        };
+        if token_line == line {
+            print!("   | ");
+        } else {
+            line = token_line;
+            print!("{line:4} ");
+        }
+        match token {
+            Ok(token) => {
+                println!("{:<12} '{}'", token.token_type.to_string(), token.source);
+                if token.token_type == TokenType::Eof {
+                    break;
+                }
+            }
+            Err(error) => {
+                println!("{:<12} '{}'", "Error", error);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(65);
+    }
+}
+
+/// `rlox check path...`: compiles scripts without running them, reporting
+/// every compile error, and every warning unless given `--no-warnings`, and
+/// exiting with 65 if there were any errors.
+fn check(options: &Options, args: &[String]) {
+    if args.is_empty() {
+        eprintln!("Usage: rlox check [path]...");
+        exit(64);
+    }
+    let mut failed = false;
+    for path in args {
+        let source = read_file(path);
+        let mut allocator = memory::Allocator::new();
+        let mut identifiers = HashMap::new();
+        let mut compiler = compiler::Compiler::new(
+            "",
+            &mut allocator,
+            &mut identifiers,
+            compiler::CompilerLimits::default(),
+        );
+        compiler.prepare();
+        let result = compiler.compile_sources(&[(path, source.as_str())], false);
+        if options.warnings {
+            for warning in compiler.take_warnings() {
+                eprintln!("{warning}");
+                if let Some(excerpt) = warning.range().excerpt(&source, "") {
+                    eprintln!("{excerpt}");
+                }
+            }
+        }
+        if let Err(errors) = result {
+            let error = LoxError::Compile(errors);
+            eprintln!("{}", error.with_excerpts(Some(path.as_str()), &source));
+            failed = true;
+        }
+    }
+    if failed {
+        exit(65);
+    }
+}
+
+/// `rlox gc-torture [--seed N] [--runs N]`: checks the garbage collector
+/// against random programs; see `gc_torture`. Prints the first failing
+/// program, which `--seed` reproduces, and exits with 1.
+fn gc_torture(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: rlox gc-torture [--seed N] [--runs N]");
+        exit(64);
+    };
+    let mut seed = 0;
+    let mut runs = 100;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next().map(|value| value.parse::<u64>()) {
+            Some(Ok(value)) => value,
+            _ => usage(),
+        };
+        match arg.as_str() {
+            "--seed" => seed = value,
+            "--runs" => runs = value,
+            _ => usage(),
+        }
+    }
+
+    match gc_torture::torture(seed, runs) {
+        Ok(()) => println!("{runs} programs passed"),
+        Err((seed, source, message)) => {
+            eprintln!("\nProgram with seed {seed} failed: {message}\n\n{source}");
+            exit(1);
+        }
+    }
+}
+
+fn compile_file(
+    allocator: &mut memory::Allocator,
+    path: &str,
+    debug_info: bool,
+    optimize: bool,
+) -> Gc<ObjFunction> {
+    let source = read_file(path);
+    let mut identifiers = HashMap::new();
+    let mut compiler = compiler::Compiler::new(
+        "",
+        allocator,
+        &mut identifiers,
+        compiler::CompilerLimits::default(),
+    );
+    compiler.set_debug_info(debug_info);
+    compiler.set_optimize(optimize);
+    compiler.prepare();
+    match compiler.compile_sources(&[(path, source.as_str())], false) {
+        Ok(function) => function,
+        Err(errors) => {
+            let error = LoxError::Compile(errors);
+            eprintln!("{}", error.with_excerpts(Some(path), &source));
+            exit(65);
+        }
     }
 }
 
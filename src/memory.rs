@@ -1,46 +1,578 @@
+//! The heap. Every object a script can refer to (strings, functions,
+//! closures, upvalues, natives, lists, and maps) is allocated by an
+//! `Allocator` and linked into its object list, and the allocator alone frees
+//! it: when a collection finds it unreachable, or when the allocator is
+//! dropped.
+//!
+//! Objects refer to each other only through raw pointers, which don't own
+//! what they point to. A function owns its chunk, and the chunk owns its
+//! constant table, but the strings and functions in that table are separate
+//! objects that any number of chunks, lists, or variables may share. Dropping
+//! an object therefore frees what it owns outright, like a string's
+//! characters or a chunk's code, and never touches the objects it refers to,
+//! so objects can be freed in any order without freeing a shared one twice.
+
 use std::alloc::Layout;
+#[cfg(debug_assertions)]
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+#[cfg(debug_assertions)]
+use std::collections::VecDeque;
+use std::fmt::Display;
+#[cfg(debug_assertions)]
+use std::panic::Location;
+use std::time::Duration;
+
+/// The type of a heap object, recorded in its header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ObjType {
+    String,
+    Function,
+    Closure,
+    Upvalue,
+    Native,
+    List,
+    Map,
+    Bytes,
+}
 
-pub trait GC {
-    fn next(&self) -> Option<*mut dyn GC>;
-    fn set_next(&mut self, next: Option<*mut dyn GC>);
-    fn layout(&self) -> Layout;
+impl ObjType {
+    /// The type's name, as scripts see it.
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjType::String => "string",
+            ObjType::Function | ObjType::Closure => "function",
+            ObjType::Upvalue => "upvalue",
+            ObjType::Native => "native function",
+            ObjType::List => "list",
+            ObjType::Map => "map",
+            ObjType::Bytes => "bytes",
+        }
+    }
+}
+
+/// What the allocator and collector keep on every heap object, as the first
+/// field of each object type: its type, whether the current collection has
+/// reached it, and the next object in the allocator's object list.
+pub struct ObjHeader {
+    pub obj_type: ObjType,
+    pub is_marked: bool,
+    pub next: Option<Gc<dyn GC>>,
+    /// The memory the object owns, as last counted by the allocator; see
+    /// `GC::owned_bytes`
+    pub owned_bytes: usize,
+}
+
+impl ObjHeader {
+    pub fn new(obj_type: ObjType) -> ObjHeader {
+        ObjHeader {
+            obj_type,
+            is_marked: false,
+            next: None,
+            owned_bytes: 0,
+        }
+    }
+
+    /// Prints the object with this header through `print`, unless it's
+    /// already being printed further out, as it is when a collection
+    /// contains itself; then writes `placeholder` instead of recursing
+    /// forever.
+    pub(crate) fn print_once(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        placeholder: &str,
+        print: impl FnOnce(&mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+    ) -> std::fmt::Result {
+        let header = self as *const ObjHeader;
+        if PRINTING.with(|printing| printing.borrow().contains(&header)) {
+            return write!(f, "{placeholder}");
+        }
+        PRINTING.with(|printing| printing.borrow_mut().push(header));
+        let result = print(f);
+        PRINTING.with(|printing| printing.borrow_mut().pop());
+        result
+    }
+}
+
+thread_local! {
+    // The headers of the objects being printed, outermost first
+    static PRINTING: RefCell<Vec<*const ObjHeader>> = const { RefCell::new(Vec::new()) };
+}
+
+pub trait GC: Display {
+    fn header(&self) -> &ObjHeader;
+    fn header_mut(&mut self) -> &mut ObjHeader;
+    /// The heap objects this object refers to directly.
+    fn references(&self) -> Vec<Gc<dyn GC>>;
+
+    fn layout(&self) -> Layout {
+        Layout::for_value(self)
+    }
+
+    /// Roughly how much heap memory the object owns besides itself, like a
+    /// string's characters or a list's items, so it counts against the
+    /// heap's size.
+    fn owned_bytes(&self) -> usize {
+        0
+    }
+
+    /// The object's type, as scripts see it.
+    fn kind(&self) -> &'static str {
+        self.header().obj_type.name()
+    }
+
+    /// A short description of the object, for tools that inspect the heap.
+    fn label(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// When, where, and as what an object was allocated. Debug builds stamp
+/// every object, so a dereference can check that the object is still live and
+/// a leak report can say where the survivors came from.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct Stamp {
+    generation: u64,
+    kind: &'static str,
+    site: &'static Location<'static>,
+    // The script line running when the object was allocated, if any was
+    script_line: Option<usize>,
+}
+
+// Stamps by address: `LIVE` for objects that are allocated, and `FREED` for
+// addresses whose object was freed and that haven't been reused since
+#[cfg(debug_assertions)]
+thread_local! {
+    static GENERATION: Cell<u64> = const { Cell::new(0) };
+    static SCRIPT_LINE: Cell<Option<usize>> = const { Cell::new(None) };
+    static LIVE: RefCell<HashMap<usize, Stamp>> = RefCell::new(HashMap::new());
+    static FREED: RefCell<HashMap<usize, Stamp>> = RefCell::new(HashMap::new());
+}
+
+/// How many freed objects debug builds hold on to before deallocating them.
+#[cfg(debug_assertions)]
+const QUARANTINE_LEN: usize = 4096;
+
+/// Freed objects not yet deallocated. Holding on to them keeps their
+/// addresses from being reused right away, which would make a stale pointer
+/// to one look like a pointer to the new object.
+#[cfg(debug_assertions)]
+struct Quarantine(VecDeque<(*mut u8, Layout)>);
+
+#[cfg(debug_assertions)]
+impl Drop for Quarantine {
+    fn drop(&mut self) {
+        for (ptr, layout) in self.0.drain(..) {
+            unsafe { std::alloc::dealloc(ptr, layout) };
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    static QUARANTINE: RefCell<Quarantine> = const { RefCell::new(Quarantine(VecDeque::new())) };
+}
+
+/// Poisons a freed object's memory and quarantines it, deallocating the
+/// object that's been quarantined longest once the quarantine is full.
+#[cfg(debug_assertions)]
+unsafe fn quarantine(ptr: *mut u8, layout: Layout) {
+    std::ptr::write_bytes(ptr, 0xde, layout.size());
+    QUARANTINE.with(|quarantine| {
+        let mut quarantine = quarantine.borrow_mut();
+        quarantine.0.push_back((ptr, layout));
+        if quarantine.0.len() > QUARANTINE_LEN {
+            let (ptr, layout) = quarantine.0.pop_front().unwrap();
+            std::alloc::dealloc(ptr, layout);
+        }
+    });
+}
+
+#[cfg(debug_assertions)]
+fn stamp(address: usize, kind: &'static str, site: &'static Location<'static>) {
+    let generation = GENERATION.with(|generation| {
+        generation.set(generation.get() + 1);
+        generation.get()
+    });
+    let stamp = Stamp {
+        generation,
+        kind,
+        site,
+        script_line: SCRIPT_LINE.with(Cell::get),
+    };
+    FREED.with(|freed| freed.borrow_mut().remove(&address));
+    LIVE.with(|live| live.borrow_mut().insert(address, stamp));
+}
+
+#[cfg(debug_assertions)]
+fn unstamp(address: usize) {
+    if let Some(stamp) = LIVE.with(|live| live.borrow_mut().remove(&address)) {
+        FREED.with(|freed| freed.borrow_mut().insert(address, stamp));
+    }
+}
+
+/// Attributes the objects allocated from now on to `line` of the running
+/// script, or to none. Only debug builds record it.
+pub(crate) fn set_script_line(line: Option<usize>) {
+    #[cfg(debug_assertions)]
+    SCRIPT_LINE.with(|script_line| script_line.set(line));
+    #[cfg(not(debug_assertions))]
+    let _ = line;
+}
+
+/// A handle to a heap object of type `T`. Handles are `Copy` and don't own
+/// their object: the allocator frees it once a collection finds no handle
+/// to it on the VM's roots. Reading or writing the object goes through `get`
+/// and `get_mut`, which debug builds check against the objects still
+/// allocated, panicking with where the object was allocated if it's been
+/// freed, so a collector bug fails where the object is used rather than
+/// somewhere later. Two handles are equal when they refer to the same object.
+pub struct Gc<T: ?Sized>(*mut T);
+
+impl<T: ?Sized> Gc<T> {
+    /// The object's address, for identifying it rather than reaching it.
+    pub fn as_ptr(self) -> *mut T {
+        self.0
+    }
+
+    #[track_caller]
+    fn check_live(self) {
+        #[cfg(debug_assertions)]
+        {
+            let address = self.0 as *const () as usize;
+            if !LIVE.with(|live| live.borrow().contains_key(&address)) {
+                match FREED.with(|freed| freed.borrow().get(&address).copied()) {
+                    Some(stamp) => panic!(
+                        "Use of freed {} #{} allocated at {}",
+                        stamp.kind, stamp.generation, stamp.site
+                    ),
+                    None => panic!("Use of {:p}, which was never allocated", self.0),
+                }
+            }
+        }
+    }
+
+    /// The object. Like every handle to it, the reference is only valid until
+    /// the collection that frees the object.
+    #[track_caller]
+    pub fn get<'a>(self) -> &'a T {
+        self.check_live();
+        unsafe { &*self.0 }
+    }
+
+    /// The object, to change. Callers mustn't hold two references to one
+    /// object at once.
+    #[track_caller]
+    pub fn get_mut<'a>(self) -> &'a mut T {
+        self.check_live();
+        unsafe { &mut *self.0 }
+    }
+}
+
+impl<T: GC + 'static> Gc<T> {
+    /// A handle to the object as any heap object, for the collector.
+    pub fn erase(self) -> Gc<dyn GC> {
+        Gc(self.0 as *mut dyn GC)
+    }
+}
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self.0, other.0)
+    }
+}
+
+impl<T: ?Sized> Eq for Gc<T> {}
+
+impl<T: ?Sized> std::hash::Hash for Gc<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (self.0 as *const () as usize).hash(state)
+    }
+}
+
+impl<T: ?Sized> std::fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gc({:p})", self.0)
+    }
+}
+
+/// Running totals for the garbage collections done on an allocator's heap,
+/// by the compiler or the VM.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GcMetrics {
+    pub collections: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    /// Every byte of objects allocated, including those since freed.
+    pub bytes_allocated: usize,
+    pub bytes_reclaimed: usize,
+}
+
+impl Display for GcMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "gc collections: {}", self.collections)?;
+        writeln!(f, "gc total pause: {:?}", self.total_pause)?;
+        writeln!(f, "gc max pause: {:?}", self.max_pause)?;
+        writeln!(f, "gc bytes allocated: {}", self.bytes_allocated)?;
+        write!(f, "gc bytes reclaimed: {}", self.bytes_reclaimed)
+    }
 }
 
 pub struct Allocator {
-    head_object: Option<*mut dyn GC>,
+    head_object: Option<Gc<dyn GC>>,
+    bytes_allocated: usize,
+    metrics: GcMetrics,
+    // Set by the `gc` native for the VM to collect once it returns
+    collection_requested: bool,
+    // The layout of every live object by address, if allocations are tracked
+    tracked: Option<HashMap<usize, Layout>>,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Allocator::new()
+    }
 }
 
 impl Allocator {
     pub fn new() -> Allocator {
-        Allocator { head_object: None }
+        Allocator {
+            head_object: None,
+            bytes_allocated: 0,
+            metrics: GcMetrics::default(),
+            collection_requested: false,
+            tracked: None,
+        }
+    }
+
+    /// Creates an allocator that records every allocation and checks each
+    /// free against it, panicking on a double free, a free of memory it never
+    /// allocated, or a free with the wrong layout. Slower, but turns memory
+    /// errors in the unsafe code into immediate panics, including under Miri.
+    pub fn with_tracking() -> Allocator {
+        Allocator {
+            tracked: Some(HashMap::new()),
+            ..Allocator::new()
+        }
+    }
+
+    /// The size of the objects currently allocated, counting the memory they
+    /// own, like a string's characters, as of when they were allocated or
+    /// last grew.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Statistics on the objects allocated and the collections done so far.
+    pub fn stats(&self) -> GcMetrics {
+        self.metrics
+    }
+
+    /// Asks for garbage to be collected once the running native returns.
+    /// Natives can't collect themselves, since only the VM knows which
+    /// objects are still in use.
+    pub fn request_collection(&mut self) {
+        self.collection_requested = true;
+    }
+
+    /// Whether a collection was asked for since this was last called.
+    pub(crate) fn take_collection_request(&mut self) -> bool {
+        std::mem::take(&mut self.collection_requested)
+    }
+
+    pub(crate) fn record_collection(&mut self, pause: Duration, bytes_reclaimed: usize) {
+        self.metrics.collections += 1;
+        self.record_pause(pause);
+        self.metrics.bytes_reclaimed += bytes_reclaimed;
+    }
+
+    /// Records a pause for part of a collection, when it's done a slice at a
+    /// time.
+    pub(crate) fn record_pause(&mut self, pause: Duration) {
+        self.metrics.total_pause += pause;
+        self.metrics.max_pause = self.metrics.max_pause.max(pause);
     }
 
-    pub fn heap_alloc<T>(&mut self, mut obj: T) -> *mut T
+    #[track_caller]
+    pub fn heap_alloc<T>(&mut self, mut obj: T) -> Gc<T>
     where
         T: GC + std::fmt::Display + 'static,
     {
-        obj.set_next(self.head_object);
+        obj.header_mut().next = self.head_object;
+        let owned = obj.owned_bytes();
+        obj.header_mut().owned_bytes = owned;
         let layout = Layout::new::<T>();
         unsafe {
             let ptr = std::alloc::alloc(layout) as *mut T;
             if ptr.is_null() {
                 std::alloc::handle_alloc_error(layout);
             }
-            *ptr = obj;
-            self.head_object = Some(ptr);
-            ptr
+            ptr.write(obj);
+            if let Some(tracked) = &mut self.tracked {
+                if tracked.insert(ptr as usize, layout).is_some() {
+                    panic!("Allocated {ptr:p} while it was still live");
+                }
+            }
+            #[cfg(debug_assertions)]
+            stamp(ptr as usize, (*ptr).kind(), Location::caller());
+            self.bytes_allocated += layout.size() + owned;
+            self.metrics.bytes_allocated += layout.size() + owned;
+            let object = Gc(ptr);
+            self.head_object = Some(object.erase());
+            object
         }
     }
 
-    pub fn free_objects(&mut self) {
+    /// Counts any change in the memory `object` owns since it was allocated
+    /// or this was last called for it, as after a list grows.
+    pub fn track_growth<T: GC>(&mut self, object: Gc<T>) {
+        let object = object.get_mut();
+        let owned = object.owned_bytes();
+        let counted = std::mem::replace(&mut object.header_mut().owned_bytes, owned);
+        if owned > counted {
+            self.bytes_allocated += owned - counted;
+            self.metrics.bytes_allocated += owned - counted;
+        } else {
+            self.bytes_allocated -= counted - owned;
+        }
+    }
+
+    /// Every object currently allocated, most recently allocated first.
+    pub fn objects(&self) -> Vec<Gc<dyn GC>> {
+        let mut objects = Vec::new();
+        let mut next = self.head_object;
+        while let Some(current) = next {
+            objects.push(current);
+            next = current.get().header().next;
+        }
+        objects
+    }
+
+    /// Lists the objects currently allocated grouped by where they were
+    /// allocated, in Rust and in the script, largest group first, with the
+    /// range of allocation numbers in each group to tell old objects from
+    /// new. Allocation sites are only recorded in debug builds.
+    pub fn leak_report(&self) -> String {
+        #[cfg(debug_assertions)]
+        {
+            let mut groups: HashMap<_, (usize, u64, u64)> = HashMap::new();
+            for object in self.objects() {
+                let address = object.as_ptr() as *const () as usize;
+                let Some(stamp) = LIVE.with(|live| live.borrow().get(&address).copied()) else {
+                    continue;
+                };
+                let site = (stamp.kind, stamp.site, stamp.script_line);
+                let group = groups
+                    .entry(site)
+                    .or_insert((0, stamp.generation, stamp.generation));
+                group.0 += 1;
+                group.1 = group.1.min(stamp.generation);
+                group.2 = group.2.max(stamp.generation);
+            }
+            let mut groups: Vec<_> = groups.into_iter().collect();
+            groups.sort_by(|(a_site, a), (b_site, b)| {
+                b.0.cmp(&a.0)
+                    .then(a.1.cmp(&b.1))
+                    .then(a_site.0.cmp(b_site.0))
+            });
+
+            let mut report = format!("{} objects survive:\n", self.objects().len());
+            for ((kind, site, script_line), (count, first, last)) in groups {
+                let script_line = match script_line {
+                    Some(line) => format!("script line {line}"),
+                    None => "no script".to_owned(),
+                };
+                report.push_str(&format!(
+                    "{count:6} {kind} at {site}, {script_line} (allocations #{first}-#{last})\n"
+                ));
+            }
+            report
+        }
+        #[cfg(not(debug_assertions))]
+        "Allocation sites are only recorded in debug builds.\n".to_owned()
+    }
+
+    /// Frees every object that isn't marked and unmarks the rest, ready for
+    /// the next collection. Returns the number of bytes freed.
+    pub(crate) fn sweep(&mut self) -> usize {
+        let mut bytes_reclaimed = 0;
+        let mut previous: Option<Gc<dyn GC>> = None;
         let mut next = self.head_object;
+        while let Some(current) = next {
+            let header = current.get_mut().header_mut();
+            next = header.next;
+            if header.is_marked {
+                header.is_marked = false;
+                previous = Some(current);
+                continue;
+            }
+            match previous {
+                Some(previous) => previous.get_mut().header_mut().next = next,
+                None => self.head_object = next,
+            }
+            bytes_reclaimed += unsafe { self.free(current) };
+        }
+        self.bytes_allocated -= bytes_reclaimed;
+        bytes_reclaimed
+    }
+
+    /// Frees every object, leaving the allocator empty. Any `Value` still
+    /// referring to one of them is left dangling.
+    pub fn free_objects(&mut self) {
+        let mut next = self.head_object.take();
         while let Some(current_head) = next {
-            unsafe {
-                next = (*current_head).next();
-                std::ptr::drop_in_place(current_head);
-                std::alloc::dealloc(current_head as *mut u8, (*current_head).layout());
+            next = current_head.get().header().next;
+            unsafe { self.free(current_head) };
+        }
+        self.bytes_allocated = 0;
+        if let Some(tracked) = &self.tracked {
+            if !tracked.is_empty() {
+                panic!(
+                    "{} allocations were lost from the object list",
+                    tracked.len()
+                );
+            }
+        }
+    }
+
+    /// Drops and deallocates `object`, which must already be unlinked from
+    /// the object list, returning its size.
+    unsafe fn free(&mut self, object: Gc<dyn GC>) -> usize {
+        let object = object.as_ptr();
+        // A freed object can't be read, so check the address before the layout
+        let recorded = match &mut self.tracked {
+            Some(tracked) => match tracked.remove(&(object as *const () as usize)) {
+                Some(layout) => Some(layout),
+                None => panic!("Freed {object:p}, which isn't allocated"),
+            },
+            None => None,
+        };
+        // Read the layout before dropping the object it's read from
+        let layout = (*object).layout();
+        let owned = (*object).header().owned_bytes;
+        if let Some(recorded) = recorded {
+            if recorded != layout {
+                panic!("Freed {object:p} with layout {layout:?}, but it was allocated with {recorded:?}");
             }
         }
+        std::ptr::drop_in_place(object);
+        #[cfg(debug_assertions)]
+        {
+            unstamp(object as *const () as usize);
+            quarantine(object as *mut u8, layout);
+        }
+        #[cfg(not(debug_assertions))]
+        std::alloc::dealloc(object as *mut u8, layout);
+        layout.size() + owned
     }
 }
 
@@ -0,0 +1,201 @@
+//! The methods of built-in types, called as `value.name(args)`. A method is a
+//! builtin like the natives, passed its receiver as its first argument, so
+//! natives that already take the receiver first, like `len`, serve as
+//! methods unchanged.
+
+use crate::memory::Allocator;
+use crate::natives::{self, Builtin};
+use crate::object_list::ObjList;
+use crate::object_native::NativeError;
+use crate::object_string::ObjString;
+use crate::value::Value;
+
+/// The methods of strings: each one's name, arity not counting the
+/// receiver, and implementation. Positions and lengths count characters, as
+/// `len` does.
+pub const STRING_METHODS: [(&str, usize, Builtin); 12] = [
+    ("len", 0, natives::len),
+    ("byteLength", 0, natives::byte_length),
+    ("slice", 2, slice),
+    ("split", 1, split),
+    ("trim", 0, trim),
+    ("toUpper", 0, to_upper),
+    ("toLower", 0, to_lower),
+    ("contains", 1, contains),
+    ("startsWith", 1, starts_with),
+    ("endsWith", 1, ends_with),
+    ("indexOf", 1, index_of),
+    ("replace", 2, replace),
+];
+
+/// The methods of numbers.
+pub const NUMBER_METHODS: [(&str, usize, Builtin); 10] = [
+    ("floor", 0, floor),
+    ("ceil", 0, ceil),
+    ("round", 0, round),
+    ("trunc", 0, trunc),
+    ("abs", 0, abs),
+    ("sqrt", 0, natives::sqrt),
+    ("toFixed", 1, natives::to_fixed),
+    ("isNan", 0, is_nan),
+    ("isFinite", 0, is_finite),
+    ("isInteger", 0, is_integer),
+];
+
+/// The method `name` of `receiver`'s type, with its arity, if it has one.
+pub fn method(receiver: &Value, name: &str) -> Option<(usize, Builtin)> {
+    let methods: &[(&str, usize, Builtin)] = match receiver {
+        Value::ObjString(_) => &STRING_METHODS,
+        Value::Number(_) => &NUMBER_METHODS,
+        _ => return None,
+    };
+    methods
+        .iter()
+        .find(|(method, _, _)| *method == name)
+        .map(|(_, arity, function)| (*arity, *function))
+}
+
+fn number(args: &[Value]) -> f64 {
+    match args[0] {
+        Value::Number(number) => number,
+        _ => unreachable!("Number methods are only found for numbers"),
+    }
+}
+
+fn floor(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(number(args).floor()))
+}
+
+fn ceil(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(number(args).ceil()))
+}
+
+/// Rounds half away from zero, so `2.5.round()` is 3 and `(-2.5).round()`
+/// is -3.
+fn round(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(number(args).round()))
+}
+
+fn trunc(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(number(args).trunc()))
+}
+
+fn abs(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Number(number(args).abs()))
+}
+
+fn is_nan(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Bool(number(args).is_nan()))
+}
+
+fn is_finite(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Bool(number(args).is_finite()))
+}
+
+fn is_integer(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Bool(number(args).fract() == 0.0))
+}
+
+fn receiver(args: &[Value]) -> &str {
+    match &args[0] {
+        Value::ObjString(string) => &string.get().str,
+        _ => unreachable!("String methods are only found for strings"),
+    }
+}
+
+/// The string argument after the receiver, at `index`.
+fn string_arg<'a>(method: &str, args: &'a [Value], index: usize) -> Result<&'a str, NativeError> {
+    match &args[index] {
+        Value::ObjString(string) => Ok(&string.get().str),
+        _ => Err(format!("{method}() expects a string.").into()),
+    }
+}
+
+fn new_string(allocator: &mut Allocator, string: &str) -> Value {
+    Value::ObjString(allocator.heap_alloc(ObjString::new(string)))
+}
+
+/// The characters from `start` up to `end`.
+fn slice(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let string = receiver(args);
+    let len = string.chars().count();
+    let bound = |value: &Value| match *value {
+        Value::Number(number) if number.fract() == 0.0 && (0.0..=len as f64).contains(&number) => {
+            Ok(number as usize)
+        }
+        _ => Err(NativeError::from(format!(
+            "slice() bounds must be whole numbers from 0 to {len}."
+        ))),
+    };
+    let (start, end) = (bound(&args[1])?, bound(&args[2])?);
+    if start > end {
+        return Err("slice() start must not be after its end.".into());
+    }
+    let sliced: String = string.chars().skip(start).take(end - start).collect();
+    Ok(new_string(allocator, &sliced))
+}
+
+/// A list of the pieces of the string between each `separator`, or of its
+/// characters if the separator is empty.
+fn split(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let string = receiver(args);
+    let separator = string_arg("split", args, 1)?;
+    let pieces: Vec<String> = if separator.is_empty() {
+        string.chars().map(String::from).collect()
+    } else {
+        string.split(separator).map(String::from).collect()
+    };
+    let items = pieces
+        .iter()
+        .map(|piece| new_string(allocator, piece))
+        .collect();
+    Ok(Value::ObjList(allocator.heap_alloc(ObjList::new(items))))
+}
+
+fn trim(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(new_string(allocator, receiver(args).trim()))
+}
+
+fn to_upper(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(new_string(allocator, &receiver(args).to_uppercase()))
+}
+
+fn to_lower(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(new_string(allocator, &receiver(args).to_lowercase()))
+}
+
+fn contains(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let needle = string_arg("contains", args, 1)?;
+    Ok(Value::Bool(receiver(args).contains(needle)))
+}
+
+fn starts_with(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let prefix = string_arg("startsWith", args, 1)?;
+    Ok(Value::Bool(receiver(args).starts_with(prefix)))
+}
+
+fn ends_with(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let suffix = string_arg("endsWith", args, 1)?;
+    Ok(Value::Bool(receiver(args).ends_with(suffix)))
+}
+
+/// The position of the first occurrence of `needle`, or -1 if there isn't
+/// one.
+fn index_of(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let string = receiver(args);
+    let needle = string_arg("indexOf", args, 1)?;
+    Ok(Value::Number(match string.find(needle) {
+        Some(byte) => string[..byte].chars().count() as f64,
+        None => -1.0,
+    }))
+}
+
+/// The string with every occurrence of `from` replaced with `to`.
+fn replace(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let from = string_arg("replace", args, 1)?;
+    let to = string_arg("replace", args, 2)?;
+    if from.is_empty() {
+        return Err("replace() can't replace an empty string.".into());
+    }
+    Ok(new_string(allocator, &receiver(args).replace(from, to)))
+}
@@ -0,0 +1,92 @@
+use crate::chunk::Opcode;
+use crate::memory::Gc;
+use crate::object_function::ObjFunction;
+
+pub struct FunctionMetrics {
+    pub name: String,
+    pub instructions: usize,
+    pub branches: usize,
+    pub complexity: usize,
+    pub max_depth: usize,
+}
+
+/// Collects metrics for `function` and every function nested in its constant table.
+pub fn collect_metrics(function: Gc<ObjFunction>) -> Vec<FunctionMetrics> {
+    ObjFunction::collect_functions(function)
+        .into_iter()
+        .map(function_metrics)
+        .collect()
+}
+
+fn function_metrics(function: Gc<ObjFunction>) -> FunctionMetrics {
+    let function = function.get();
+    let chunk = &function.chunk;
+
+    let mut instructions = 0;
+    let mut branches = 0;
+    let mut decisions = 0;
+    // Each forward jump covers the bytecode it skips over; nesting depth at an
+    // instruction is the number of those regions it sits inside.
+    let mut regions = Vec::new();
+    let mut offsets = Vec::new();
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        instructions += 1;
+        offsets.push(offset);
+        match Opcode::try_from(chunk.code[offset]) {
+            Ok(
+                opcode @ (Opcode::JumpIfFalse
+                | Opcode::JumpIfTrue
+                | Opcode::JumpIfNotNil
+                | Opcode::Jump),
+            ) => {
+                branches += 1;
+                if opcode != Opcode::Jump {
+                    decisions += 1;
+                }
+                let jump = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+                regions.push((offset, offset + 3 + jump));
+            }
+            Ok(Opcode::Loop) => branches += 1,
+            _ => {}
+        }
+        offset += chunk.instruction_len(offset);
+    }
+
+    let max_depth = offsets
+        .iter()
+        .map(|&offset| {
+            regions
+                .iter()
+                .filter(|(start, end)| *start < offset && offset < *end)
+                .count()
+        })
+        .max()
+        .unwrap_or(0);
+
+    FunctionMetrics {
+        name: function.to_string(),
+        instructions,
+        branches,
+        complexity: decisions + 1,
+        max_depth,
+    }
+}
+
+pub fn print_metrics(metrics: &[FunctionMetrics]) {
+    println!(
+        "{:<24} {:>12} {:>8} {:>10} {:>6}",
+        "function", "instructions", "branches", "complexity", "depth"
+    );
+    for function in metrics {
+        println!(
+            "{:<24} {:>12} {:>8} {:>10} {:>6}",
+            function.name,
+            function.instructions,
+            function.branches,
+            function.complexity,
+            function.max_depth
+        );
+    }
+}
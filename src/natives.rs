@@ -0,0 +1,587 @@
+use crate::memory::Allocator;
+use crate::object_bytes::ObjBytes;
+use crate::object_list::ObjList;
+use crate::object_map::{MapKey, ObjMap};
+use crate::object_native::NativeError;
+use crate::object_string::ObjString;
+use crate::value::{self, Value};
+use std::cell::RefCell;
+use std::io::BufRead;
+use std::rc::Rc;
+
+pub type Builtin = fn(&mut Allocator, &[Value]) -> Result<Value, NativeError>;
+
+/// The natives every VM starts with: each one's global name, arity, and
+/// implementation. Natives that build new objects allocate them through the
+/// allocator they're passed.
+pub const BUILTINS: [(&str, usize, Builtin); 26] = [
+    ("clock", 0, clock),
+    ("push", 2, push),
+    ("pop", 1, pop),
+    ("len", 1, len),
+    ("byteLength", 1, byte_length),
+    ("keys", 1, keys),
+    ("values", 1, values),
+    ("has", 2, has),
+    ("delete", 2, delete),
+    ("type", 1, type_of),
+    ("arity", 1, arity),
+    ("name", 1, name),
+    ("toNumber", 1, to_number),
+    ("toString", 1, to_string),
+    ("str", 1, to_string),
+    ("formatNumber", 2, format_number),
+    ("toFixed", 2, to_fixed),
+    ("sqrt", 1, sqrt),
+    ("ord", 1, ord),
+    ("chr", 1, chr),
+    ("slice", 3, slice),
+    ("encode", 2, encode),
+    ("decode", 2, decode),
+    ("assert", 2, assert),
+    ("gc", 0, gc),
+    ("gcStats", 0, gc_stats),
+];
+
+/// Builtins whose result depends only on their arguments and is never a
+/// mutable object, so a call with literal arguments can be evaluated once
+/// while compiling rather than every time it runs.
+pub const PURE: [&str; 12] = [
+    "len",
+    "byteLength",
+    "type",
+    "toNumber",
+    "toString",
+    "str",
+    "formatNumber",
+    "toFixed",
+    "decode",
+    "sqrt",
+    "ord",
+    "chr",
+];
+
+/// The implementation of the pure builtin `name`, if there is one.
+pub fn pure_builtin(name: &str) -> Option<(usize, Builtin)> {
+    if !PURE.contains(&name) {
+        return None;
+    }
+    BUILTINS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .map(|(_, arity, function)| (*arity, *function))
+}
+
+fn clock(_: &mut Allocator, _: &[Value]) -> Result<Value, NativeError> {
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    Ok(Value::Number(time as f64))
+}
+
+fn push(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::ObjList(list) => {
+            list.get_mut().items.push(args[1].clone());
+            allocator.track_growth(*list);
+            Ok(Value::Nil)
+        }
+        _ => Err("push() expects a list as its first argument.".into()),
+    }
+}
+
+fn pop(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::ObjList(list) => {
+            let list = list.get_mut();
+            list.items
+                .pop()
+                .ok_or_else(|| "Can't pop from an empty list.".into())
+        }
+        _ => Err("pop() expects a list.".into()),
+    }
+}
+
+/// The number of items in a list, entries in a map, or bytes in bytes. A
+/// string's length counts its Unicode code points, which are also what
+/// `for-in` steps through, so "é" has length 1 however it's encoded; see
+/// `byteLength` for its size in UTF-8.
+pub(crate) fn len(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::ObjList(list) => {
+            let list = list.get();
+            Ok(Value::Number(list.items.len() as f64))
+        }
+        Value::ObjString(string) => {
+            let string = string.get();
+            Ok(Value::Number(string.str.chars().count() as f64))
+        }
+        Value::ObjMap(map) => {
+            let map = map.get();
+            Ok(Value::Number(map.len() as f64))
+        }
+        Value::ObjBytes(bytes) => {
+            let bytes = bytes.get();
+            Ok(Value::Number(bytes.bytes.len() as f64))
+        }
+        _ => Err("len() expects a list, map, string or bytes.".into()),
+    }
+}
+
+/// Copies the items of a list, or the contents of bytes, from `start` up to
+/// but not including `end`.
+fn slice(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let len = match &args[0] {
+        Value::ObjList(list) => list.get().items.len(),
+        Value::ObjBytes(bytes) => bytes.get().bytes.len(),
+        _ => return Err("slice() expects a list or bytes.".into()),
+    };
+    let bound = |value: &Value| match *value {
+        Value::Number(number) if number.fract() == 0.0 && (0.0..=len as f64).contains(&number) => {
+            Ok(number as usize)
+        }
+        _ => Err(NativeError::from(format!(
+            "slice() bounds must be whole numbers from 0 to {len}."
+        ))),
+    };
+    let (start, end) = (bound(&args[1])?, bound(&args[2])?);
+    if start > end {
+        return Err("slice() start must not be after its end.".into());
+    }
+    Ok(match &args[0] {
+        Value::ObjList(list) => {
+            let items = list.get().items[start..end].to_vec();
+            Value::ObjList(allocator.heap_alloc(ObjList::new(items)))
+        }
+        Value::ObjBytes(bytes) => {
+            let contents = bytes.get().bytes[start..end].to_vec();
+            Value::ObjBytes(allocator.heap_alloc(ObjBytes::new(contents)))
+        }
+        _ => unreachable!(),
+    })
+}
+
+/// The text encodings `encode` and `decode` support.
+#[derive(Clone, Copy)]
+enum Encoding {
+    Utf8,
+    // Each byte is the code point of the same number
+    Latin1,
+    Ascii,
+}
+
+impl Encoding {
+    fn from_value(native: &str, value: &Value) -> Result<Encoding, NativeError> {
+        let name = match value {
+            Value::ObjString(name) => name.get().str.to_lowercase(),
+            _ => return Err(format!("{native}() expects an encoding name.").into()),
+        };
+        match name.as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "ascii" => Ok(Encoding::Ascii),
+            _ => Err(
+                format!("Unknown encoding '{name}'; expect 'utf-8', 'latin-1' or 'ascii'.").into(),
+            ),
+        }
+    }
+}
+
+/// Encodes a string as bytes, failing on characters the encoding can't
+/// represent.
+fn encode(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let Value::ObjString(string) = &args[0] else {
+        return Err("encode() expects a string.".into());
+    };
+    let string = &string.get().str;
+    let limit = match Encoding::from_value("encode", &args[1])? {
+        Encoding::Utf8 => None,
+        Encoding::Latin1 => Some(0xff),
+        Encoding::Ascii => Some(0x7f),
+    };
+    let bytes = match limit {
+        None => string.as_bytes().to_vec(),
+        Some(limit) => string
+            .chars()
+            .map(|c| {
+                u8::try_from(c)
+                    .ok()
+                    .filter(|byte| *byte <= limit)
+                    .ok_or_else(|| {
+                        NativeError::from(format!("Can't encode '{c}' in that encoding."))
+                    })
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(Value::ObjBytes(allocator.heap_alloc(ObjBytes::new(bytes))))
+}
+
+/// Decodes bytes into a string, failing on bytes that aren't valid in the
+/// encoding.
+fn decode(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let Value::ObjBytes(bytes) = &args[0] else {
+        return Err("decode() expects bytes.".into());
+    };
+    let bytes = &bytes.get().bytes;
+    let string = match Encoding::from_value("decode", &args[1])? {
+        Encoding::Utf8 => std::str::from_utf8(bytes)
+            .map_err(|error| format!("Invalid UTF-8 at byte {}.", error.valid_up_to()))?
+            .to_owned(),
+        Encoding::Latin1 => bytes.iter().map(|byte| *byte as char).collect(),
+        Encoding::Ascii => match bytes.iter().position(|byte| !byte.is_ascii()) {
+            Some(position) => return Err(format!("Invalid ASCII at byte {position}.").into()),
+            None => bytes.iter().map(|byte| *byte as char).collect(),
+        },
+    };
+    Ok(Value::ObjString(
+        allocator.heap_alloc(ObjString::new(&string)),
+    ))
+}
+
+/// The number of bytes in a string's UTF-8 encoding.
+pub(crate) fn byte_length(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::ObjString(string) => {
+            let string = string.get();
+            Ok(Value::Number(string.str.len() as f64))
+        }
+        _ => Err("byteLength() expects a string.".into()),
+    }
+}
+
+fn keys(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let map = map_arg("keys", &args[0])?;
+    let keys = map.entries().iter().map(|(key, _)| key.clone()).collect();
+    Ok(Value::ObjList(allocator.heap_alloc(ObjList::new(keys))))
+}
+
+fn values(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let map = map_arg("values", &args[0])?;
+    let values = map
+        .entries()
+        .iter()
+        .map(|(_, value)| value.clone())
+        .collect();
+    Ok(Value::ObjList(allocator.heap_alloc(ObjList::new(values))))
+}
+
+fn has(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let map = map_arg("has", &args[0])?;
+    let key = MapKey::from_value(&args[1])?;
+    Ok(Value::Bool(map.contains_key(&key)))
+}
+
+fn delete(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let map = map_arg("delete", &args[0])?;
+    if map.is_frozen() {
+        return Err("Can't change a namespace.".into());
+    }
+    let key = MapKey::from_value(&args[1])?;
+    Ok(Value::Bool(map.remove(&key).is_some()))
+}
+
+fn type_of(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let name = match &args[0] {
+        // Scripts call natives like any other function
+        Value::ObjNative(_) => "function",
+        value => value.type_name(),
+    };
+    Ok(Value::ObjString(allocator.heap_alloc(ObjString::new(name))))
+}
+
+/// How many parameters a function declares, counting those with default
+/// values, so code taking a callback can check it before calling it. Nil for
+/// a native that takes any number of arguments.
+fn arity(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::ObjClosure(closure) => Ok(Value::Number(closure.get().function.get().arity as f64)),
+        Value::ObjNative(native) => Ok(native
+            .get()
+            .arity
+            .map_or(Value::Nil, |arity| Value::Number(arity as f64))),
+        _ => Err("arity() expects a function.".into()),
+    }
+}
+
+/// The name a function was declared with.
+fn name(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let name = match &args[0] {
+        Value::ObjClosure(closure) => match &closure.get().function.get().name {
+            Some(name) => name.str.clone(),
+            None => return Ok(Value::Nil),
+        },
+        Value::ObjNative(native) => native.get().name.clone(),
+        _ => return Err("name() expects a function.".into()),
+    };
+    Ok(Value::ObjString(
+        allocator.heap_alloc(ObjString::new(&name)),
+    ))
+}
+
+/// Converts a string holding a number, like "12" or "-0.5", to that number,
+/// or to nil if it doesn't hold one, so scripts can check input before doing
+/// arithmetic on it.
+fn to_number(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Number(number) => Ok(Value::Number(*number)),
+        Value::ObjString(string) => Ok(parse_number(&string.get().str)),
+        _ => Err("toNumber() expects a string or number.".into()),
+    }
+}
+
+/// Formats a number as `print` would, with `separator` between each group of
+/// three digits before the decimal point: `formatNumber(1234.5, ",")` is
+/// "1,234.5".
+fn format_number(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let (Value::Number(number), Value::ObjString(separator)) = (&args[0], &args[1]) else {
+        return Err("formatNumber() expects a number and a separator string.".into());
+    };
+    let separator = &separator.get().str;
+    let formatted = value::format_number(*number);
+    let (sign, unsigned) = match formatted.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", formatted.as_str()),
+    };
+    let (integer, fraction) = match unsigned.find('.') {
+        Some(point) => unsigned.split_at(point),
+        None => (unsigned, ""),
+    };
+    // "inf" and "NaN" have no digits to group
+    let grouped = if integer.bytes().all(|byte| byte.is_ascii_digit()) {
+        let mut grouped = String::new();
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                grouped.push_str(separator);
+            }
+            grouped.push(digit);
+        }
+        grouped
+    } else {
+        integer.to_owned()
+    };
+    let result = format!("{sign}{grouped}{fraction}");
+    Ok(Value::ObjString(
+        allocator.heap_alloc(ObjString::new(&result)),
+    ))
+}
+
+pub(crate) fn sqrt(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Number(number) => Ok(Value::Number(number.sqrt())),
+        _ => Err("sqrt() expects a number.".into()),
+    }
+}
+
+/// The code point of a one-character string.
+fn ord(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    if let Value::ObjString(string) = &args[0] {
+        let mut chars = string.get().str.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(Value::Number(c as u32 as f64));
+        }
+    }
+    Err("ord() expects a one-character string.".into())
+}
+
+/// The one-character string for a code point.
+fn chr(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let c = match &args[0] {
+        Value::Number(number) if number.fract() == 0.0 && *number >= 0.0 => {
+            char::from_u32(*number as u32).filter(|c| *c as u32 as f64 == *number)
+        }
+        _ => None,
+    };
+    match c {
+        Some(c) => Ok(Value::ObjString(
+            allocator.heap_alloc(ObjString::new(c.encode_utf8(&mut [0; 4]))),
+        )),
+        None => Err("chr() expects a Unicode code point.".into()),
+    }
+}
+
+/// Parses a number the way Lox writes one, always with `.` as the decimal
+/// point whatever the system locale.
+fn parse_number(string: &str) -> Value {
+    string
+        .trim()
+        .parse::<f64>()
+        .ok()
+        // Rust also accepts "inf" and "NaN", which Lox can't write
+        .filter(|number| number.is_finite())
+        .map_or(Value::Nil, Value::Number)
+}
+
+/// The language features this version of rlox supports beyond the Lox of
+/// Crafting Interpreters, as reported by `features()`.
+pub const LANGUAGE_FEATURES: &[&str] = &[
+    "lists",
+    "maps",
+    "for-in",
+    "exceptions",
+    "compile-time-constants",
+    "bytes",
+    "const",
+    "default-parameters",
+    "nil-coalescing",
+    "string-methods",
+];
+
+/// The global `Number`, a namespace map of numeric constants, so scripts
+/// don't have to spell them as literals.
+pub fn number_namespace(allocator: &mut Allocator) -> Value {
+    let mut map = ObjMap::new();
+    let constants = [
+        ("MAX", f64::MAX),
+        ("MIN", f64::MIN),
+        ("EPSILON", f64::EPSILON),
+        ("INFINITY", f64::INFINITY),
+        ("NAN", f64::NAN),
+        ("PI", std::f64::consts::PI),
+        ("E", std::f64::consts::E),
+    ];
+    for (name, number) in constants {
+        let key = allocator.heap_alloc(ObjString::new(name));
+        map.insert(
+            MapKey::String(name.to_owned()),
+            Value::ObjString(key),
+            Value::Number(number),
+        );
+    }
+    map.freeze();
+    Value::ObjMap(allocator.heap_alloc(map))
+}
+
+/// A map from each supported language feature and native function's name to
+/// "language" or "native", so scripts can check for one with `has`.
+pub fn features(native_names: &[String], allocator: &mut Allocator) -> Value {
+    let mut map = ObjMap::new();
+    let language = LANGUAGE_FEATURES.iter().map(|name| (*name, "language"));
+    let natives = native_names.iter().map(|name| (name.as_str(), "native"));
+    for (name, kind) in language.chain(natives) {
+        let key = allocator.heap_alloc(ObjString::new(name));
+        let value = allocator.heap_alloc(ObjString::new(kind));
+        map.insert(
+            MapKey::String(name.to_owned()),
+            Value::ObjString(key),
+            Value::ObjString(value),
+        );
+    }
+    Value::ObjMap(allocator.heap_alloc(map))
+}
+
+/// Collects garbage as soon as the call returns.
+fn gc(allocator: &mut Allocator, _: &[Value]) -> Result<Value, NativeError> {
+    allocator.request_collection();
+    Ok(Value::Nil)
+}
+
+/// The collector's statistics as a map: the number of `collections`, the
+/// bytes of objects ever allocated and since freed, the bytes the heap holds
+/// now, and the total and longest pauses for collection in milliseconds.
+fn gc_stats(allocator: &mut Allocator, _: &[Value]) -> Result<Value, NativeError> {
+    let stats = allocator.stats();
+    let entries = [
+        ("collections", stats.collections as f64),
+        ("bytesAllocated", stats.bytes_allocated as f64),
+        ("bytesFreed", stats.bytes_reclaimed as f64),
+        ("heapSize", allocator.bytes_allocated() as f64),
+        ("totalPause", stats.total_pause.as_secs_f64() * 1000.0),
+        ("maxPause", stats.max_pause.as_secs_f64() * 1000.0),
+    ];
+    let mut map = ObjMap::new();
+    for (name, value) in entries {
+        let key = allocator.heap_alloc(ObjString::new(name));
+        map.insert(
+            MapKey::String(name.to_owned()),
+            Value::ObjString(key),
+            Value::Number(value),
+        );
+    }
+    Ok(Value::ObjMap(allocator.heap_alloc(map)))
+}
+
+/// Where `readLine` and `readNumber` read from, shared between the natives
+/// and the VM so the host can swap it out.
+pub type Input = Rc<RefCell<Box<dyn BufRead>>>;
+
+/// Reads a line from `input` without its line ending, or nil at the end of
+/// the input.
+pub fn read_line(
+    input: &Input,
+    allocator: &mut Allocator,
+    _: &[Value],
+) -> Result<Value, NativeError> {
+    match next_line(input)? {
+        Some(line) => Ok(Value::ObjString(
+            allocator.heap_alloc(ObjString::new(&line)),
+        )),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// Reads a line from `input` as a number, or nil at the end of the input or
+/// if the line isn't a number.
+pub fn read_number(input: &Input, _: &mut Allocator, _: &[Value]) -> Result<Value, NativeError> {
+    Ok(next_line(input)?.map_or(Value::Nil, |line| parse_number(&line)))
+}
+
+fn next_line(input: &Input) -> Result<Option<String>, NativeError> {
+    let mut line = String::new();
+    let read = input
+        .borrow_mut()
+        .read_line(&mut line)
+        .map_err(|error| NativeError::new(format!("Failed to read input: {error}")))?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+/// A value as `print` would show it. Also named `str`, for converting a
+/// value to concatenate it with a string.
+fn to_string(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    if let Value::ObjString(_) = &args[0] {
+        return Ok(args[0].clone());
+    }
+    let string = args[0].to_string();
+    Ok(Value::ObjString(
+        allocator.heap_alloc(ObjString::new(&string)),
+    ))
+}
+
+/// Formats a number with exactly `digits` digits after the decimal point,
+/// rounding if it has more: `toFixed(3.14159, 2)` is "3.14".
+pub(crate) fn to_fixed(allocator: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    let (Value::Number(number), Value::Number(digits)) = (&args[0], &args[1]) else {
+        return Err("toFixed() expects a number and a number of digits.".into());
+    };
+    if digits.fract() != 0.0 || !(0.0..=100.0).contains(digits) {
+        return Err("toFixed() expects a whole number of digits from 0 to 100.".into());
+    }
+    let fixed = format!("{number:.*}", *digits as usize);
+    Ok(Value::ObjString(
+        allocator.heap_alloc(ObjString::new(&fixed)),
+    ))
+}
+
+/// Raises a runtime error with `message` if `condition` is falsey.
+fn assert(_: &mut Allocator, args: &[Value]) -> Result<Value, NativeError> {
+    if args[0].is_falsey() {
+        return Err(format!("Assertion failed: {}", args[1]).into());
+    }
+    Ok(Value::Nil)
+}
+
+fn map_arg<'a>(name: &str, value: &Value) -> Result<&'a mut ObjMap, NativeError> {
+    match value {
+        Value::ObjMap(map) => Ok(map.get_mut()),
+        _ => Err(format!("{name}() expects a map as its first argument.").into()),
+    }
+}
@@ -0,0 +1,98 @@
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
+use std::fmt::Display;
+
+/// A mutable buffer of raw bytes, for data that isn't valid UTF-8 and so
+/// can't be held in a string.
+#[repr(C)]
+pub struct ObjBytes {
+    pub header: ObjHeader,
+    pub bytes: Vec<u8>,
+}
+
+impl ObjBytes {
+    pub fn new(bytes: Vec<u8>) -> ObjBytes {
+        ObjBytes {
+            header: ObjHeader::new(ObjType::Bytes),
+            bytes,
+        }
+    }
+
+    /// Decodes the contents of a `b"..."` literal, without its quotes.
+    /// Besides `\xNN` for any byte, it accepts the escapes `\n`, `\r`, `\t`,
+    /// `\0`, `\\`, and `\"`.
+    pub fn parse_literal(literal: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(literal.len());
+        let mut rest = literal.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            rest = tail;
+            if byte != b'\\' {
+                bytes.push(byte);
+                continue;
+            }
+            let Some((&escape, tail)) = rest.split_first() else {
+                return Err("Unterminated escape in bytes literal.".to_owned());
+            };
+            rest = tail;
+            bytes.push(match escape {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'0' => b'\0',
+                b'\\' => b'\\',
+                b'"' => b'"',
+                b'x' => {
+                    let digits = rest
+                        .get(..2)
+                        .and_then(|digits| std::str::from_utf8(digits).ok())
+                        .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        .ok_or("Expect two hex digits after '\\x' in bytes literal.")?;
+                    rest = &rest[2..];
+                    digits
+                }
+                _ => return Err("Invalid escape in bytes literal.".to_owned()),
+            });
+        }
+        Ok(bytes)
+    }
+}
+
+impl GC for ObjBytes {
+    fn header(&self) -> &ObjHeader {
+        &self.header
+    }
+
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
+    }
+
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        Vec::new()
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    fn label(&self) -> String {
+        format!("bytes of {}", self.bytes.len())
+    }
+}
+
+/// Writes the bytes as a literal that would produce them, like `b"ab\xff"`.
+impl Display for ObjBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "b\"")?;
+        for byte in self.bytes.iter() {
+            match byte {
+                b'\n' => write!(f, "\\n")?,
+                b'\r' => write!(f, "\\r")?,
+                b'\t' => write!(f, "\\t")?,
+                b'\\' => write!(f, "\\\\")?,
+                b'"' => write!(f, "\\\"")?,
+                b' '..=b'~' => write!(f, "{}", *byte as char)?,
+                _ => write!(f, "\\x{byte:02x}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
@@ -1,4 +1,4 @@
-use crate::memory::GC;
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
 use crate::object_function::ObjFunction;
 use crate::object_upvalue::ObjUpvalue;
 use std::fmt::Display;
@@ -15,45 +15,55 @@ impl Upvalue {
     }
 }
 
+#[repr(C)]
 pub struct ObjClosure {
-    pub function: *const ObjFunction,
-    pub upvalues: Vec<*mut ObjUpvalue>,
+    pub header: ObjHeader,
+    pub function: Gc<ObjFunction>,
+    /// Added as the VM captures them. A frame-bound function's closure takes
+    /// none, reading its captures from the caller's frame instead.
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
     pub upvalue_count: usize,
-    pub is_marked: bool,
-    next: Option<*mut dyn GC>,
 }
 
 impl ObjClosure {
-    pub fn new(function: *const ObjFunction) -> ObjClosure {
-        let upvalue_count = unsafe { (*function).upvalue_count };
-        let upvalues: Vec<*mut ObjUpvalue> =
-            Vec::from_iter((0..upvalue_count).map(|_| std::ptr::null_mut()));
+    pub fn new(function: Gc<ObjFunction>) -> ObjClosure {
+        let upvalue_count = function.get().upvalue_count;
         ObjClosure {
+            header: ObjHeader::new(ObjType::Closure),
             function,
-            upvalues,
+            upvalues: Vec::with_capacity(upvalue_count),
             upvalue_count,
-            is_marked: false,
-            next: None,
         }
     }
 }
 
 impl GC for ObjClosure {
-    fn next(&self) -> Option<*mut dyn GC> {
-        self.next
+    fn header(&self) -> &ObjHeader {
+        &self.header
     }
 
-    fn set_next(&mut self, next: Option<*mut dyn GC>) {
-        self.next = next;
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
     }
 
-    fn layout(&self) -> std::alloc::Layout {
-        std::alloc::Layout::new::<Self>()
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        let mut references = vec![self.function.erase()];
+        references.extend(self.upvalues.iter().map(|upvalue| upvalue.erase()));
+        references
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.upvalues.capacity() * std::mem::size_of::<Gc<ObjUpvalue>>()
+    }
+
+    // Tell closures apart from the functions they wrap
+    fn label(&self) -> String {
+        format!("closure {self}")
     }
 }
 
 impl Display for ObjClosure {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        unsafe { (*self.function).fmt(f) }
+        self.function.get().fmt(f)
     }
 }
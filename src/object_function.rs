@@ -1,7 +1,9 @@
 use std::fmt::Display;
 
+use crate::chunk::Chunk;
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
 use crate::object_string::ObjString;
-use crate::{chunk::Chunk, memory::GC};
+use crate::value::Value;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum FunctionType {
@@ -9,42 +11,89 @@ pub enum FunctionType {
     Script,
 }
 
+#[repr(C)]
 pub struct ObjFunction {
+    pub header: ObjHeader,
     pub function_type: FunctionType,
     pub arity: u8,
+    /// Freed along with the function; see `Chunk::constants` for what isn't.
     pub chunk: Chunk,
     pub name: Option<ObjString>,
     pub upvalue_count: usize,
-    pub is_marked: bool,
-    next: Option<*mut dyn GC>,
+    /// Set when the function never escapes the frame that declares it, which
+    /// only ever calls it directly: it then reads and writes the variables it
+    /// captures in that frame's stack slots, and its closures have no
+    /// upvalues.
+    pub frame_bound: bool,
 }
 
 impl GC for ObjFunction {
-    fn next(&self) -> Option<*mut dyn GC> {
-        self.next
+    fn header(&self) -> &ObjHeader {
+        &self.header
     }
 
-    fn set_next(&mut self, next: Option<*mut dyn GC>) {
-        self.next = next;
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
     }
 
-    fn layout(&self) -> std::alloc::Layout {
-        std::alloc::Layout::new::<Self>()
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        self.chunk
+            .constants
+            .iter()
+            .filter_map(Value::as_object)
+            .collect()
     }
 }
 
 impl ObjFunction {
     pub fn new(function_type: FunctionType, name: Option<ObjString>) -> ObjFunction {
         ObjFunction {
+            header: ObjHeader::new(ObjType::Function),
             function_type,
             arity: 0,
             chunk: Chunk::new(),
             name,
             upvalue_count: 0,
-            is_marked: false,
-            next: None,
+            frame_bound: false,
         }
     }
+
+    /// The fewest arguments the function can be called with, since
+    /// parameters with default values can be left out.
+    pub fn min_arity(&self) -> usize {
+        self.arity as usize - self.chunk.entry_points.len().saturating_sub(1)
+    }
+
+    /// Where a call passing `arg_count` arguments starts running, or `None`
+    /// if the function can't be passed that many.
+    pub fn entry_point(&self, arg_count: usize) -> Option<usize> {
+        let entry_points = &self.chunk.entry_points;
+        if entry_points.is_empty() {
+            return (arg_count == self.arity as usize).then_some(0);
+        }
+        let missing = (self.arity as usize).checked_sub(arg_count)?;
+        let entry = entry_points.len().checked_sub(missing + 1)?;
+        Some(entry_points[entry])
+    }
+
+    /// Returns `function` followed by every function nested in its constant
+    /// table, including those of closures compiled as constants, depth first.
+    pub fn collect_functions(function: Gc<ObjFunction>) -> Vec<Gc<ObjFunction>> {
+        let mut functions = vec![function];
+        let constants = &function.get().chunk.constants;
+        for constant in constants.iter() {
+            match constant {
+                Value::ObjFunction(nested) => {
+                    functions.extend(ObjFunction::collect_functions(*nested))
+                }
+                Value::ObjClosure(closure) => {
+                    functions.extend(ObjFunction::collect_functions(closure.get().function))
+                }
+                _ => {}
+            }
+        }
+        functions
+    }
 }
 
 impl Display for ObjFunction {
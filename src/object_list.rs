@@ -0,0 +1,56 @@
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
+use crate::value::Value;
+use std::fmt::Display;
+
+#[repr(C)]
+pub struct ObjList {
+    pub header: ObjHeader,
+    pub items: Vec<Value>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> ObjList {
+        ObjList {
+            header: ObjHeader::new(ObjType::List),
+            items,
+        }
+    }
+}
+
+impl GC for ObjList {
+    fn header(&self) -> &ObjHeader {
+        &self.header
+    }
+
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
+    }
+
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        self.items.iter().filter_map(Value::as_object).collect()
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.items.capacity() * std::mem::size_of::<Value>()
+    }
+
+    // The contents may be long, or refer back to this object
+    fn label(&self) -> String {
+        format!("list of {}", self.items.len())
+    }
+}
+
+impl Display for ObjList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.header.print_once(f, "[...]", |f| {
+            write!(f, "[")?;
+            for (i, item) in self.items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                item.fmt(f)?;
+            }
+            write!(f, "]")
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// The hashable form of a map key. Only strings and numbers can be keys.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Number(u64),
+    String(String),
+}
+
+impl MapKey {
+    pub fn from_value(value: &Value) -> Result<MapKey, String> {
+        match value {
+            // Normalize -0 so it finds the same entry as 0
+            Value::Number(number) if *number == 0.0 => Ok(MapKey::Number(0.0f64.to_bits())),
+            Value::Number(number) => Ok(MapKey::Number(number.to_bits())),
+            Value::ObjString(obj_str) => Ok(MapKey::String(obj_str.get().str.clone())),
+            _ => Err("Map keys must be strings or numbers.".to_string()),
+        }
+    }
+}
+
+#[repr(C)]
+pub struct ObjMap {
+    pub header: ObjHeader,
+    // Entries are kept in insertion order, with `indices` pointing into them
+    entries: Vec<(Value, Value)>,
+    indices: HashMap<MapKey, usize>,
+    // A frozen map is a namespace, like `Number`: scripts read its entries
+    // as properties, and can't change it
+    frozen: bool,
+}
+
+impl Default for ObjMap {
+    fn default() -> Self {
+        ObjMap::new()
+    }
+}
+
+impl ObjMap {
+    pub fn new() -> ObjMap {
+        ObjMap {
+            header: ObjHeader::new(ObjType::Map),
+            entries: Vec::new(),
+            indices: HashMap::new(),
+            frozen: false,
+        }
+    }
+
+    /// Makes the map a namespace. The VM refuses to change it after this, but
+    /// Rust code still can.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.indices.get(key).map(|&index| &self.entries[index].1)
+    }
+
+    pub fn contains_key(&self, key: &MapKey) -> bool {
+        self.indices.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: MapKey, key_value: Value, value: Value) {
+        match self.indices.get(&key) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.indices.insert(key, self.entries.len());
+                self.entries.push((key_value, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let index = self.indices.remove(key)?;
+        let (_, value) = self.entries.remove(index);
+        // The later entries each moved down a place to keep their order
+        for later in self.indices.values_mut() {
+            if *later > index {
+                *later -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl GC for ObjMap {
+    fn header(&self) -> &ObjHeader {
+        &self.header
+    }
+
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
+    }
+
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        self.entries
+            .iter()
+            .flat_map(|(key, value)| [key, value])
+            .filter_map(Value::as_object)
+            .collect()
+    }
+
+    // Each entry is held twice, once in order and once in the lookup table,
+    // which also holds a copy of a string key's characters
+    fn owned_bytes(&self) -> usize {
+        let keys: usize = self
+            .indices
+            .keys()
+            .map(|key| match key {
+                MapKey::String(string) => string.capacity(),
+                MapKey::Number(_) => 0,
+            })
+            .sum();
+        self.entries.capacity() * std::mem::size_of::<(Value, Value)>()
+            + self.indices.capacity() * std::mem::size_of::<(MapKey, usize)>()
+            + keys
+    }
+
+    // The contents may be long, or refer back to this object
+    fn label(&self) -> String {
+        format!("map of {}", self.entries.len())
+    }
+}
+
+impl Display for ObjMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.header.print_once(f, "{...}", |f| {
+            write!(f, "{{")?;
+            for (i, (key, value)) in self.entries.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{key}: {value}")?;
+            }
+            write!(f, "}}")
+        })
+    }
+}
@@ -1,37 +1,75 @@
-use crate::memory::GC;
+use crate::memory::{Allocator, Gc, ObjHeader, ObjType, GC};
+use crate::value::Value;
 use std::fmt::Display;
 
-pub enum NativeFunction {
-    Clock,
+/// The signature natives are stored with. Natives that build new objects
+/// allocate them through the allocator they're passed.
+pub type NativeFn = dyn Fn(&mut Allocator, &[Value]) -> Result<Value, NativeError>;
+
+/// An error returned by a native function, raised in the script as a runtime
+/// error with `message`.
+#[derive(Debug)]
+pub struct NativeError {
+    pub message: String,
+}
+
+impl NativeError {
+    pub fn new(message: impl Into<String>) -> NativeError {
+        NativeError {
+            message: message.into(),
+        }
+    }
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::new(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        NativeError::new(message)
+    }
+}
+
+impl Display for NativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.message.fmt(f)
+    }
 }
 
+#[repr(C)]
 pub struct ObjNative {
-    pub native_function: NativeFunction,
-    pub is_marked: bool,
-    next: Option<*mut dyn GC>,
+    pub header: ObjHeader,
+    pub name: String,
+    // Natives without an arity check their argument count themselves
+    pub arity: Option<usize>,
+    pub function: Box<NativeFn>,
 }
 
 impl ObjNative {
-    pub fn new(native_function: NativeFunction) -> ObjNative {
+    pub fn new(name: &str, arity: Option<usize>, function: Box<NativeFn>) -> ObjNative {
         ObjNative {
-            native_function,
-            is_marked: false,
-            next: None,
+            header: ObjHeader::new(ObjType::Native),
+            name: name.to_owned(),
+            arity,
+            function,
         }
     }
 }
 
 impl GC for ObjNative {
-    fn next(&self) -> Option<*mut dyn GC> {
-        self.next
+    fn header(&self) -> &ObjHeader {
+        &self.header
     }
 
-    fn set_next(&mut self, next: Option<*mut dyn GC>) {
-        self.next = next;
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
     }
 
-    fn layout(&self) -> std::alloc::Layout {
-        std::alloc::Layout::new::<Self>()
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        Vec::new()
     }
 }
 
@@ -1,36 +1,39 @@
-use crate::memory::GC;
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
 use std::fmt::Display;
 use std::hash::Hash;
 
-pub(crate) struct ObjString {
+#[repr(C)]
+pub struct ObjString {
+    pub header: ObjHeader,
     pub str: String,
-    pub is_marked: bool,
     hash: u32,
-    next: Option<*mut dyn GC>,
 }
 
 impl GC for ObjString {
-    fn next(&self) -> Option<*mut dyn GC> {
-        self.next
+    fn header(&self) -> &ObjHeader {
+        &self.header
     }
 
-    fn set_next(&mut self, next: Option<*mut dyn GC>) {
-        self.next = next;
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
     }
 
-    fn layout(&self) -> std::alloc::Layout {
-        std::alloc::Layout::new::<Self>()
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        Vec::new()
+    }
+
+    fn owned_bytes(&self) -> usize {
+        self.str.capacity()
     }
 }
 
 impl ObjString {
-    pub(crate) fn new(string: &str) -> ObjString {
+    pub fn new(string: &str) -> ObjString {
         let hash = ObjString::hash_string(string);
         ObjString {
+            header: ObjHeader::new(ObjType::String),
             str: string.to_owned(),
-            is_marked: false,
             hash,
-            next: None,
         }
     }
 
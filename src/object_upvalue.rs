@@ -1,38 +1,38 @@
 use std::fmt::Display;
 
-use crate::{memory::GC, value::Value};
+use crate::memory::{Gc, ObjHeader, ObjType, GC};
+use crate::value::Value;
 
+#[repr(C)]
 pub struct ObjUpvalue {
+    pub header: ObjHeader,
     pub location: usize,
-    pub next_upvalue: Option<*mut ObjUpvalue>,
+    pub next_upvalue: Option<Gc<ObjUpvalue>>,
     pub closed: Option<Value>,
-    pub is_marked: bool,
-    next: Option<*mut dyn GC>,
 }
 
 impl ObjUpvalue {
     pub fn new(location: usize) -> ObjUpvalue {
         ObjUpvalue {
+            header: ObjHeader::new(ObjType::Upvalue),
             location,
             next_upvalue: None,
             closed: None,
-            is_marked: false,
-            next: None,
         }
     }
 }
 
 impl GC for ObjUpvalue {
-    fn next(&self) -> Option<*mut dyn GC> {
-        self.next
+    fn header(&self) -> &ObjHeader {
+        &self.header
     }
 
-    fn set_next(&mut self, next: Option<*mut dyn GC>) {
-        self.next = next;
+    fn header_mut(&mut self) -> &mut ObjHeader {
+        &mut self.header
     }
 
-    fn layout(&self) -> std::alloc::Layout {
-        std::alloc::Layout::new::<Self>()
+    fn references(&self) -> Vec<Gc<dyn GC>> {
+        self.closed.iter().filter_map(Value::as_object).collect()
     }
 }
 
@@ -0,0 +1,219 @@
+//! The optional peephole pass run over each chunk once it's compiled, enabled
+//! with `-O`. It threads jumps that land on other jumps through to where
+//! they end up, and removes instruction pairs that do nothing:
+//!
+//! - `Not Not` after an instruction that already produces a boolean.
+//! - A literal or variable read followed by `Pop`.
+//! - The `Nil Return` ending a function that already returned.
+//!
+//! Removing code shifts what follows it, so every jump, line, span, file run,
+//! local scope, and entry point is remapped to the new offsets.
+
+use crate::chunk::{Chunk, Opcode};
+use std::collections::HashSet;
+
+/// Optimizes `chunk` in place until no pattern applies.
+pub fn optimize(chunk: &mut Chunk) {
+    while optimize_once(chunk) {}
+}
+
+fn opcode(chunk: &Chunk, offset: usize) -> Option<Opcode> {
+    Opcode::try_from(chunk.code[offset]).ok()
+}
+
+fn jump_operand(chunk: &Chunk, offset: usize) -> usize {
+    u16::from_be_bytes([chunk.code[offset + 1], chunk.code[offset + 2]]) as usize
+}
+
+/// Where the jump at `offset` goes, if it is one, and whether it's forward.
+fn jump_target(chunk: &Chunk, offset: usize) -> Option<(usize, bool)> {
+    match opcode(chunk, offset)? {
+        Opcode::Jump
+        | Opcode::JumpIfFalse
+        | Opcode::JumpIfTrue
+        | Opcode::JumpIfNotNil
+        | Opcode::PushHandler => Some((offset + 3 + jump_operand(chunk, offset), true)),
+        Opcode::Loop => Some((offset + 3 - jump_operand(chunk, offset), false)),
+        _ => None,
+    }
+}
+
+fn produces_bool(opcode: Option<Opcode>) -> bool {
+    matches!(
+        opcode,
+        Some(
+            Opcode::Equal
+                | Opcode::Greater
+                | Opcode::Less
+                | Opcode::Not
+                | Opcode::True
+                | Opcode::False
+        )
+    )
+}
+
+fn optimize_once(chunk: &mut Chunk) -> bool {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offsets.push(offset);
+        offset += chunk.instruction_len(offset);
+    }
+
+    let threaded = thread_jumps(chunk, &offsets);
+    // A call can start at an entry point as well as a jump land there
+    let targets: HashSet<usize> = offsets
+        .iter()
+        .filter_map(|&offset| jump_target(chunk, offset))
+        .map(|(target, _)| target)
+        .chain(chunk.entry_points.iter().copied())
+        .collect();
+
+    let mut removed = vec![false; offsets.len()];
+    let mut i = 0;
+    while i + 1 < offsets.len() {
+        let (this, next) = (offsets[i], offsets[i + 1]);
+        let previous = i.checked_sub(1).and_then(|i| opcode(chunk, offsets[i]));
+        // Code can only jump to the first instruction of a pair when the pair
+        // is a no-op wherever control comes from
+        let removable = !targets.contains(&next)
+            && match (opcode(chunk, this), opcode(chunk, next)) {
+                (Some(Opcode::Not), Some(Opcode::Not)) => {
+                    !targets.contains(&this) && produces_bool(previous)
+                }
+                (
+                    Some(
+                        Opcode::Constant
+                        | Opcode::CopyConstant
+                        | Opcode::True
+                        | Opcode::False
+                        | Opcode::Nil
+                        | Opcode::GetLocal
+                        | Opcode::GetUpvalue,
+                    ),
+                    Some(Opcode::Pop),
+                ) => true,
+                (Some(Opcode::Nil), Some(Opcode::Return)) => {
+                    !targets.contains(&this) && previous == Some(Opcode::Return)
+                }
+                _ => false,
+            };
+        if removable {
+            removed[i] = true;
+            removed[i + 1] = true;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+
+    if !removed.contains(&true) {
+        return threaded;
+    }
+    remove_instructions(chunk, &offsets, &removed);
+    true
+}
+
+/// Points jumps that land on a jump which is sure to be taken, or sure not to
+/// be, straight at where control ends up. Returns whether any changed.
+fn thread_jumps(chunk: &mut Chunk, offsets: &[usize]) -> bool {
+    let mut changed = false;
+    for &offset in offsets {
+        let Some(
+            jump @ (Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfTrue | Opcode::JumpIfNotNil),
+        ) = opcode(chunk, offset)
+        else {
+            continue;
+        };
+        let original = offset + 3 + jump_operand(chunk, offset);
+        let mut target = original;
+        // Every step moves forward, so this ends
+        while target < chunk.code.len() {
+            target = match (jump, opcode(chunk, target)) {
+                (_, Some(Opcode::Jump))
+                | (Opcode::JumpIfFalse, Some(Opcode::JumpIfFalse))
+                | (Opcode::JumpIfTrue, Some(Opcode::JumpIfTrue))
+                | (Opcode::JumpIfNotNil, Some(Opcode::JumpIfNotNil)) => {
+                    target + 3 + jump_operand(chunk, target)
+                }
+                // The value that took this jump falls through the next one
+                (Opcode::JumpIfFalse, Some(Opcode::JumpIfTrue))
+                | (Opcode::JumpIfTrue, Some(Opcode::JumpIfFalse)) => target + 3,
+                _ => break,
+            };
+        }
+        let Ok(operand) = u16::try_from(target - offset - 3) else {
+            continue;
+        };
+        if target != original {
+            chunk.code[offset + 1..offset + 3].copy_from_slice(&operand.to_be_bytes());
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Rebuilds `chunk` without the instructions at `offsets` marked `removed`.
+fn remove_instructions(chunk: &mut Chunk, offsets: &[usize], removed: &[bool]) {
+    // The new offset of each instruction, or for a removed one of the next
+    // instruction kept, with the end of the code last
+    let mut new_offsets = Vec::with_capacity(offsets.len() + 1);
+    let mut code = Vec::new();
+    let mut lines = Vec::new();
+    let mut columns = Vec::new();
+    let mut jumps = Vec::new();
+    for (i, &offset) in offsets.iter().enumerate() {
+        new_offsets.push(code.len());
+        if removed[i] {
+            continue;
+        }
+        if let Some((target, forward)) = jump_target(chunk, offset) {
+            jumps.push((code.len(), target, forward));
+        }
+        let end = offset + chunk.instruction_len(offset);
+        code.extend_from_slice(&chunk.code[offset..end]);
+        lines.extend_from_slice(&chunk.lines[offset..end]);
+        columns.extend_from_slice(&chunk.columns[offset..end]);
+    }
+    new_offsets.push(code.len());
+    let remap = |old: usize| new_offsets[offsets.partition_point(|&offset| offset < old)];
+
+    for (offset, target, forward) in jumps {
+        let target = remap(target);
+        // Removing code only brings a jump and its target closer together
+        let operand = if forward {
+            target - offset - 3
+        } else {
+            offset + 3 - target
+        } as u16;
+        code[offset + 1..offset + 3].copy_from_slice(&operand.to_be_bytes());
+    }
+
+    chunk.code = code;
+    chunk.lines = lines;
+    chunk.columns = columns;
+    chunk.spans = std::mem::take(&mut chunk.spans)
+        .into_iter()
+        .filter_map(|(offset, span)| match offsets.binary_search(&offset) {
+            Ok(i) if !removed[i] => Some((new_offsets[i], span)),
+            _ => None,
+        })
+        .collect();
+    let mut files: Vec<(usize, String)> = Vec::new();
+    for (start, file) in std::mem::take(&mut chunk.files) {
+        let start = remap(start);
+        // A run whose code was all removed gives way to the one after it
+        if files.last().is_some_and(|(last, _)| *last == start) {
+            files.pop();
+        }
+        files.push((start, file));
+    }
+    chunk.files = files;
+    for local in chunk.locals.iter_mut() {
+        local.start = remap(local.start);
+        local.end = remap(local.end);
+    }
+    for entry_point in chunk.entry_points.iter_mut() {
+        *entry_point = remap(*entry_point);
+    }
+}
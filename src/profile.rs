@@ -0,0 +1,58 @@
+//! Where a VM's time goes, split into the phases of running a script, for
+//! finding out whether a slow script is slow to scan, to compile, to collect
+//! garbage for, or to run. Nothing is measured unless it's asked for with
+//! `VM::set_self_profile`, and nothing leaves the machine.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Running totals for every script a VM has compiled and run since profiling
+/// was turned on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Profile {
+    /// Time spent turning source into tokens.
+    pub scanning: Duration,
+    /// Time spent compiling, not counting scanning.
+    pub compiling: Duration,
+    /// Time spent collecting garbage while scripts ran.
+    pub gc: Duration,
+    /// Time spent running scripts, not counting collections.
+    pub execution: Duration,
+    pub tokens: usize,
+    pub collections: usize,
+}
+
+impl Profile {
+    pub fn total(&self) -> Duration {
+        self.scanning + self.compiling + self.gc + self.execution
+    }
+
+    /// The profile as a JSON object, with times in seconds.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n  \"phases\": {");
+        let phases = [
+            ("scanning", self.scanning),
+            ("compiling", self.compiling),
+            ("gc", self.gc),
+            ("execution", self.execution),
+        ];
+        for (i, (name, time)) in phases.iter().enumerate() {
+            write!(
+                out,
+                "{}\n    \"{name}\": {}",
+                if i > 0 { "," } else { "" },
+                time.as_secs_f64()
+            )
+            .unwrap();
+        }
+        write!(
+            out,
+            "\n  }},\n  \"total\": {},\n  \"tokens\": {},\n  \"collections\": {}\n}}\n",
+            self.total().as_secs_f64(),
+            self.tokens,
+            self.collections
+        )
+        .unwrap();
+        out
+    }
+}
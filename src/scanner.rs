@@ -1,9 +1,14 @@
+use crate::edit_distance::edit_distance;
 use derive_more::Display;
 use std::fmt::Display;
 
 pub struct Scanner<'a> {
     pub source: &'a str,
+    /// The name of the file being scanned, if it came from one.
+    pub file: Option<&'a str>,
+    /// The byte offset of the start of the token being scanned
     pub start: usize,
+    /// The byte offset of the next character to scan
     pub current: usize,
     pub line: usize,
 }
@@ -15,6 +20,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -22,6 +30,10 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
     // One or two character tokens.
     Bang,
     BangEqual,
@@ -31,36 +43,82 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    LessLess,
+    GreaterGreater,
+    QuestionQuestion,
     // Literals.
     Identifier,
     String,
+    Bytes,
     Number,
     // Keywords.
     And,
+    Catch,
     Class,
+    Const,
     Else,
     False,
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
     Eof,
 }
 
+/// Every reserved word and the token it scans to.
+pub const KEYWORDS: [(&str, TokenType); 21] = [
+    ("and", TokenType::And),
+    ("catch", TokenType::Catch),
+    ("class", TokenType::Class),
+    ("const", TokenType::Const),
+    ("else", TokenType::Else),
+    ("false", TokenType::False),
+    ("for", TokenType::For),
+    ("fun", TokenType::Fun),
+    ("if", TokenType::If),
+    ("in", TokenType::In),
+    ("nil", TokenType::Nil),
+    ("or", TokenType::Or),
+    ("print", TokenType::Print),
+    ("return", TokenType::Return),
+    ("super", TokenType::Super),
+    ("this", TokenType::This),
+    ("throw", TokenType::Throw),
+    ("true", TokenType::True),
+    ("try", TokenType::Try),
+    ("var", TokenType::Var),
+    ("while", TokenType::While),
+];
+
+/// Words other languages use where Lox expects one of its own keywords.
+const KEYWORD_ALIASES: [(&str, &str); 5] = [
+    ("function", "fun"),
+    ("func", "fun"),
+    ("def", "fun"),
+    ("let", "var"),
+    ("elif", "else"),
+];
+
 #[derive(Clone, Copy)]
 pub struct Token<'a> {
     pub token_type: TokenType,
     pub source: &'a str,
+    pub file: Option<&'a str>,
     pub line: usize,
+    /// The column the token starts at, counting characters from 1
+    pub column: usize,
 }
 
 impl<'a> PartialEq for Token<'a> {
@@ -72,6 +130,7 @@ impl<'a> PartialEq for Token<'a> {
 pub enum ScanError {
     UnexpectedCharacter,
     UnterminatedString,
+    UnterminatedComment,
 }
 
 impl Display for ScanError {
@@ -79,22 +138,119 @@ impl Display for ScanError {
         match self {
             ScanError::UnexpectedCharacter => write!(f, "Unexpected character"),
             ScanError::UnterminatedString => write!(f, "Unterminated string"),
+            ScanError::UnterminatedComment => write!(f, "Unterminated block comment"),
         }
     }
 }
 
+impl TokenType {
+    /// How an error message names the token, e.g. `';'`, `'while'` or
+    /// `a number`.
+    pub fn describe(&self) -> String {
+        let text = match self {
+            TokenType::LeftParen => "(",
+            TokenType::RightParen => ")",
+            TokenType::LeftBrace => "{",
+            TokenType::RightBrace => "}",
+            TokenType::LeftBracket => "[",
+            TokenType::RightBracket => "]",
+            TokenType::Colon => ":",
+            TokenType::Comma => ",",
+            TokenType::Dot => ".",
+            TokenType::Minus => "-",
+            TokenType::Plus => "+",
+            TokenType::Semicolon => ";",
+            TokenType::Slash => "/",
+            TokenType::Star => "*",
+            TokenType::Ampersand => "&",
+            TokenType::Pipe => "|",
+            TokenType::Caret => "^",
+            TokenType::Tilde => "~",
+            TokenType::Bang => "!",
+            TokenType::BangEqual => "!=",
+            TokenType::Equal => "=",
+            TokenType::EqualEqual => "==",
+            TokenType::Greater => ">",
+            TokenType::GreaterEqual => ">=",
+            TokenType::Less => "<",
+            TokenType::LessEqual => "<=",
+            TokenType::LessLess => "<<",
+            TokenType::GreaterGreater => ">>",
+            TokenType::QuestionQuestion => "??",
+            TokenType::Identifier => return "an identifier".to_owned(),
+            TokenType::String => return "a string".to_owned(),
+            TokenType::Bytes => return "a byte string".to_owned(),
+            TokenType::Number => return "a number".to_owned(),
+            TokenType::Eof => return "the end of the source".to_owned(),
+            keyword => {
+                let (word, _) = KEYWORDS.iter().find(|(_, token)| token == keyword).unwrap();
+                word
+            }
+        };
+        format!("'{text}'")
+    }
+
+    /// Whether a keyword can begin a statement, which is where a misspelling
+    /// of it would leave the parser confused.
+    fn starts_statement(&self) -> bool {
+        matches!(
+            self,
+            TokenType::Class
+                | TokenType::Const
+                | TokenType::Else
+                | TokenType::For
+                | TokenType::Fun
+                | TokenType::If
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try
+                | TokenType::Var
+                | TokenType::While
+        )
+    }
+}
+
+/// If `identifier` looks like a misspelled statement keyword (one edit away from
+/// it, or from a common alias for it in another language), returns that keyword.
+pub fn misspelled_keyword(identifier: &str) -> Option<&'static str> {
+    if identifier.len() < 2 {
+        return None;
+    }
+    let identifier = identifier.to_lowercase();
+    let keywords = KEYWORDS
+        .iter()
+        .filter(|(_, token_type)| token_type.starts_statement())
+        .map(|(keyword, _)| (*keyword, *keyword));
+    KEYWORD_ALIASES
+        .iter()
+        .copied()
+        .chain(keywords)
+        .find(|(word, _)| edit_distance(&identifier, word) <= 1)
+        .map(|(_, keyword)| keyword)
+}
+
 impl<'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Scanner<'a> {
         Scanner {
             source,
+            file: None,
             start: 0,
             current: 0,
             line: 1,
         }
     }
 
+    /// Creates a scanner whose tokens are attributed to the file `name`.
+    pub fn with_file(name: &'a str, source: &'a str) -> Scanner<'a> {
+        Scanner {
+            file: Some(name),
+            ..Scanner::new(source)
+        }
+    }
+
     pub fn scan_token(&mut self) -> Result<Token<'a>, ScanError> {
-        self.skip_whitespace();
+        self.skip_whitespace()?;
         self.start = self.current;
 
         if self.is_at_end() {
@@ -115,13 +271,20 @@ impl<'a> Scanner<'a> {
             ')' => return self.make_token(TokenType::RightParen),
             '{' => return self.make_token(TokenType::LeftBrace),
             '}' => return self.make_token(TokenType::RightBrace),
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ';' => return self.make_token(TokenType::Semicolon),
+            ':' => return self.make_token(TokenType::Colon),
             ',' => return self.make_token(TokenType::Comma),
             '.' => return self.make_token(TokenType::Dot),
             '-' => return self.make_token(TokenType::Minus),
             '+' => return self.make_token(TokenType::Plus),
             '/' => return self.make_token(TokenType::Slash),
             '*' => return self.make_token(TokenType::Star),
+            '&' => return self.make_token(TokenType::Ampersand),
+            '|' => return self.make_token(TokenType::Pipe),
+            '^' => return self.make_token(TokenType::Caret),
+            '~' => return self.make_token(TokenType::Tilde),
             '!' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::BangEqual);
@@ -139,6 +302,8 @@ impl<'a> Scanner<'a> {
             '<' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::LessEqual);
+                } else if self.match_char('<') {
+                    return self.make_token(TokenType::LessLess);
                 } else {
                     return self.make_token(TokenType::Less);
                 }
@@ -146,10 +311,13 @@ impl<'a> Scanner<'a> {
             '>' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::GreaterEqual);
+                } else if self.match_char('>') {
+                    return self.make_token(TokenType::GreaterGreater);
                 } else {
                     return self.make_token(TokenType::Greater);
                 }
             }
+            '?' if self.match_char('?') => return self.make_token(TokenType::QuestionQuestion),
             '"' => return self.string(),
             _ => (),
         }
@@ -173,44 +341,107 @@ impl<'a> Scanner<'a> {
         self.make_token(TokenType::String)
     }
 
+    /// Scans the rest of a `b"..."` literal. Its escapes are decoded by the
+    /// compiler; here a backslash only keeps the next character from closing
+    /// the literal.
+    fn bytes(&mut self) -> Result<Token<'a>, ScanError> {
+        while self.peek() != '"' && !self.is_at_end() {
+            match self.advance() {
+                '\\' if self.peek() == '\n' => {
+                    self.advance();
+                    self.line += 1;
+                }
+                '\\' if !self.is_at_end() => {
+                    self.advance();
+                }
+                '\n' => self.line += 1,
+                _ => (),
+            }
+        }
+
+        if self.is_at_end() {
+            return Err(ScanError::UnterminatedString);
+        }
+
+        self.advance();
+        self.make_token(TokenType::Bytes)
+    }
+
     fn identifier(&mut self) -> Result<Token<'a>, ScanError> {
+        if self.current - self.start == 1
+            && &self.source[self.start..self.current] == "b"
+            && self.peek() == '"'
+        {
+            self.advance();
+            return self.bytes();
+        }
         while Scanner::is_alpha(self.peek()) || Scanner::is_digit(self.peek()) {
             self.advance();
         }
-        return self.make_token(self.identifier_type());
+        self.make_token(self.identifier_type())
     }
 
     fn identifier_type(&self) -> TokenType {
-        let c = self.source.chars().nth(self.start).unwrap();
-        match c {
-            'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
-            'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
-            'o' => self.check_keyword(1, 1, "r", TokenType::Or),
-            'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
-            'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, 4, "hile", TokenType::While),
-            'f' => {
+        // Keywords are ASCII, so their letters can be compared byte by byte
+        let bytes = self.source.as_bytes();
+        match bytes[self.start] {
+            b'a' => self.check_keyword(1, 2, "nd", TokenType::And),
+            b'c' => {
+                if self.current - self.start > 1 {
+                    match bytes[self.start + 1] {
+                        b'a' => self.check_keyword(2, 3, "tch", TokenType::Catch),
+                        b'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                        b'o' => self.check_keyword(2, 3, "nst", TokenType::Const),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
+            b'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
+            b'i' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'a' => self.check_keyword(2, 3, "lse", TokenType::False),
-                        'o' => self.check_keyword(2, 1, "r", TokenType::For),
-                        'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
+                    match bytes[self.start + 1] {
+                        b'f' => self.check_keyword(2, 0, "", TokenType::If),
+                        b'n' => self.check_keyword(2, 0, "", TokenType::In),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            't' => {
+            b'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
+            b'o' => self.check_keyword(1, 1, "r", TokenType::Or),
+            b'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
+            b'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
+            b's' => self.check_keyword(1, 4, "uper", TokenType::Super),
+            b'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
+            b'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            b'f' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                        'r' => self.check_keyword(2, 2, "ue", TokenType::True),
+                    match bytes[self.start + 1] {
+                        b'a' => self.check_keyword(2, 3, "lse", TokenType::False),
+                        b'o' => self.check_keyword(2, 1, "r", TokenType::For),
+                        b'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
+            b't' => {
+                if self.current - self.start > 1 {
+                    match bytes[self.start + 1] {
+                        b'h' => match bytes.get(self.start + 2) {
+                            Some(b'i') => self.check_keyword(3, 1, "s", TokenType::This),
+                            Some(b'r') => self.check_keyword(3, 2, "ow", TokenType::Throw),
+                            _ => TokenType::Identifier,
+                        },
+                        b'r' => match bytes.get(self.start + 2) {
+                            Some(b'u') => self.check_keyword(3, 1, "e", TokenType::True),
+                            Some(b'y') => self.check_keyword(3, 0, "", TokenType::Try),
+                            _ => TokenType::Identifier,
+                        },
                         _ => TokenType::Identifier,
                     }
                 } else {
@@ -248,30 +479,31 @@ impl<'a> Scanner<'a> {
             }
         }
 
-        return self.make_token(TokenType::Number);
+        self.make_token(TokenType::Number)
     }
 
+    // Offsets are in bytes, always on a character boundary, so looking at
+    // the next character only decodes that one rather than walking the
+    // source from the start
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let c = self.peek();
+        self.current += c.len_utf8();
+        c
     }
 
     fn peek(&self) -> char {
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn match_char(&mut self, expected: char) -> bool {
         if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.current += expected.len_utf8();
         true
     }
 
@@ -287,10 +519,10 @@ impl<'a> Scanner<'a> {
         c.is_ascii_digit()
     }
 
-    fn skip_whitespace(&mut self) {
+    fn skip_whitespace(&mut self) -> Result<(), ScanError> {
         loop {
             if self.is_at_end() {
-                return;
+                return Ok(());
             }
             let c = self.peek();
             match c {
@@ -301,25 +533,56 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
+                '/' => match self.peek_next() {
+                    '/' => {
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
-                    } else {
-                        break;
                     }
-                }
+                    '*' => self.block_comment()?,
+                    _ => break,
+                },
                 _ => break,
             }
         }
+        Ok(())
+    }
+
+    fn block_comment(&mut self) -> Result<(), ScanError> {
+        // Consume the opening `/*`
+        self.advance();
+        self.advance();
+
+        // Block comments nest, so track how many are still open
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Err(ScanError::UnterminatedComment);
+            }
+            match self.advance() {
+                '\n' => self.line += 1,
+                '/' if self.match_char('*') => depth += 1,
+                '*' if self.match_char('/') => depth -= 1,
+                _ => (),
+            }
+        }
+        Ok(())
     }
 
     fn make_token(&self, token_type: TokenType) -> Result<Token<'a>, ScanError> {
         Ok(Token {
             token_type,
             source: &self.source[self.start..self.current],
+            file: self.file,
             line: self.line,
+            column: self.column(),
         })
     }
+
+    /// The column the token being scanned starts at, counting characters
+    /// from 1.
+    pub fn column(&self) -> usize {
+        let line_start = self.source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        self.source[line_start..self.start].chars().count() + 1
+    }
 }
@@ -0,0 +1,38 @@
+use crate::error::LoxError;
+use crate::memory::Allocator;
+use crate::value::Value;
+use crate::vm::VM;
+
+/// An interactive session for hosts that embed a REPL. Globals, functions,
+/// and interned strings persist from one `eval` to the next.
+pub struct Session<'a> {
+    vm: VM<'a>,
+}
+
+impl<'a> Session<'a> {
+    /// Starts a session on a new VM with the standard library loaded.
+    pub fn new(allocator: &'a mut Allocator) -> Session<'a> {
+        let mut vm = VM::new(allocator, false, false);
+        vm.load_stdlib()
+            .expect("The standard library should load on a new VM");
+        Session::from_vm(vm)
+    }
+
+    /// Starts a session on an already configured VM, e.g. one from
+    /// `VmBuilder` with a prelude loaded.
+    pub fn from_vm(vm: VM<'a>) -> Session<'a> {
+        Session { vm }
+    }
+
+    /// Evaluates a line of input. If it ends with an expression statement,
+    /// whose ';' is optional, returns that expression's value. A line that
+    /// fails leaves the session ready for the next; see `VM::reset`.
+    pub fn eval(&mut self, line: &str) -> Result<Option<Value>, LoxError> {
+        self.vm.eval(line)
+    }
+
+    /// The session's VM, for registering natives or reading globals.
+    pub fn vm(&mut self) -> &mut VM<'a> {
+        &mut self.vm
+    }
+}
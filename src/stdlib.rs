@@ -0,0 +1,12 @@
+//! The standard library: functions written in Lox, in `stdlib/`, that VMs
+//! define before running any script. The source is embedded in the crate
+//! when it's built, and compiled by each VM that loads it with
+//! `VM::load_stdlib`, as `VmBuilder` and `Session` do unless told not to,
+//! and the `rlox` binary does unless run with `--no-stdlib`.
+
+/// Each file of the standard library, named by its path in the repository,
+/// which runtime errors in it are reported against.
+pub const SOURCES: [(&str, &str); 2] = [
+    ("stdlib/lists.lox", include_str!("../stdlib/lists.lox")),
+    ("stdlib/strings.lox", include_str!("../stdlib/strings.lox")),
+];
@@ -1,21 +1,132 @@
+use crate::chunk::Opcode;
+use crate::memory::{Allocator, Gc, GC};
+use crate::object_bytes::ObjBytes;
 use crate::object_closure::ObjClosure;
 use crate::object_function::ObjFunction;
+use crate::object_list::ObjList;
+use crate::object_map::ObjMap;
 use crate::object_native::ObjNative;
 use crate::object_string::ObjString;
 use std::fmt::Display;
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
-    ObjString(*mut ObjString),
-    ObjFunction(*mut ObjFunction),
-    ObjNative(*mut ObjNative),
-    ObjClosure(*mut ObjClosure),
+    ObjString(Gc<ObjString>),
+    ObjFunction(Gc<ObjFunction>),
+    ObjNative(Gc<ObjNative>),
+    ObjClosure(Gc<ObjClosure>),
+    ObjList(Gc<ObjList>),
+    ObjMap(Gc<ObjMap>),
+    ObjBytes(Gc<ObjBytes>),
+}
+
+/// Defines an accessor for the object a variant points to, returning it if the
+/// value is that variant, and an unchecked one for when the VM already knows
+/// it is.
+macro_rules! object_accessors {
+    ($name:ident, $unchecked:ident, $variant:ident, $type:ty, $what:literal) => {
+        #[doc = concat!("The ", $what, " this value refers to, if it's one.")]
+        pub fn $name(&self) -> Option<Gc<$type>> {
+            match self {
+                Value::$variant(object) => Some(*object),
+                _ => None,
+            }
+        }
+
+        #[doc = concat!("The ", $what, " this value refers to, without checking")]
+        #[doc = "in release builds that it's one."]
+        ///
+        /// # Safety
+        ///
+        #[doc = concat!("The value must be a ", $what, ".")]
+        pub unsafe fn $unchecked(&self) -> Gc<$type> {
+            match self {
+                Value::$variant(object) => *object,
+                _ if cfg!(debug_assertions) => panic!("Expected a {}", $what),
+                _ => unsafe { std::hint::unreachable_unchecked() },
+            }
+        }
+    };
 }
 
 impl Value {
+    object_accessors!(
+        as_obj_string,
+        as_obj_string_unchecked,
+        ObjString,
+        ObjString,
+        "string"
+    );
+    object_accessors!(
+        as_function,
+        as_function_unchecked,
+        ObjFunction,
+        ObjFunction,
+        "function"
+    );
+    object_accessors!(
+        as_closure,
+        as_closure_unchecked,
+        ObjClosure,
+        ObjClosure,
+        "closure"
+    );
+    object_accessors!(
+        as_native,
+        as_native_unchecked,
+        ObjNative,
+        ObjNative,
+        "native"
+    );
+    object_accessors!(as_list, as_list_unchecked, ObjList, ObjList, "list");
+    object_accessors!(as_map, as_map_unchecked, ObjMap, ObjMap, "map");
+    object_accessors!(
+        as_bytes,
+        as_bytes_unchecked,
+        ObjBytes,
+        ObjBytes,
+        "bytes object"
+    );
+
+    /// The number this value holds, if it's a number.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The number this value holds, without checking in release builds that
+    /// it's a number.
+    ///
+    /// # Safety
+    ///
+    /// The value must be a number.
+    pub unsafe fn as_number_unchecked(&self) -> f64 {
+        match self {
+            Value::Number(number) => *number,
+            _ if cfg!(debug_assertions) => panic!("Expected a number"),
+            _ => unsafe { std::hint::unreachable_unchecked() },
+        }
+    }
+
+    /// The boolean this value holds, if it's a boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(bool) => Some(*bool),
+            _ => None,
+        }
+    }
+
+    /// The text of this value, if it's a string. Valid only while the string
+    /// is, so until the next collection if nothing else refers to it.
+    pub fn as_string(&self) -> Option<&str> {
+        self.as_obj_string().map(|string| string.get().str.as_str())
+    }
+
     pub fn to_bool_value(bool: bool) -> Value {
         Value::Bool(bool)
     }
@@ -24,9 +135,105 @@ impl Value {
         Value::Number(number)
     }
 
+    /// The heap object this value refers to, if it isn't a plain value.
+    pub(crate) fn as_object(&self) -> Option<Gc<dyn GC>> {
+        match self {
+            Value::Bool(_) | Value::Nil | Value::Number(_) => None,
+            Value::ObjString(obj_string) => Some(obj_string.erase()),
+            Value::ObjFunction(obj_function) => Some(obj_function.erase()),
+            Value::ObjNative(obj_native) => Some(obj_native.erase()),
+            Value::ObjClosure(obj_closure) => Some(obj_closure.erase()),
+            Value::ObjList(obj_list) => Some(obj_list.erase()),
+            Value::ObjMap(obj_map) => Some(obj_map.erase()),
+            Value::ObjBytes(obj_bytes) => Some(obj_bytes.erase()),
+        }
+    }
+
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::Bool(false))
     }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::ObjString(_) => "string",
+            Value::ObjFunction(_) | Value::ObjClosure(_) => "function",
+            Value::ObjNative(_) => "native function",
+            Value::ObjList(_) => "list",
+            Value::ObjMap(_) => "map",
+            Value::ObjBytes(_) => "bytes",
+        }
+    }
+
+    /// Describes the value's type and contents for error messages, e.g. `a string ("abc")`.
+    pub fn describe(&self) -> String {
+        match self {
+            Value::Nil => "nil".to_string(),
+            Value::ObjString(_) => format!("a string (\"{self}\")"),
+            _ => format!("a {} ({self})", self.type_name()),
+        }
+    }
+}
+
+/// Formats a number as `print` shows it: rounded to 15 significant digits,
+/// so the noise in a sum like `0.1 + 0.2` doesn't show, with no trailing
+/// zeros and so no `.0` on an integer. Numbers of 1e15 or more, or less than
+/// 1e-5, are written in scientific notation, as `1e+21`. Rust's formatting
+/// ignores the locale, so this is always `.`-decimal with no grouping,
+/// whatever the system's settings.
+pub fn format_number(number: f64) -> String {
+    // Infinities, NaN and zeros, whose sign is kept, print as Rust has them
+    if !number.is_finite() || number == 0.0 {
+        return number.to_string();
+    }
+    // The exponent after rounding, which may carry into the next power of ten
+    let scientific = format!("{number:.14e}");
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-5..15).contains(&exponent) {
+        let decimals = (14 - exponent) as usize;
+        trim_zeros(&format!("{number:.decimals$}")).to_owned()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{sign}{}", trim_zeros(mantissa), exponent.abs())
+    }
+}
+
+/// Truncates a number to a 32-bit integer for the bitwise operators, as
+/// JavaScript does: the fraction is dropped and the rest wraps around modulo
+/// 2^32. NaN and the infinities become 0.
+pub fn to_int32(number: f64) -> i32 {
+    if !number.is_finite() {
+        return 0;
+    }
+    number.trunc().rem_euclid(4294967296.0) as u32 as i32
+}
+
+/// Applies the bitwise operator `opcode` to two numbers, truncating each to a
+/// 32-bit integer first. Shifts use only the low five bits of their right
+/// operand, and `>>` keeps the sign.
+pub fn bitwise(opcode: Opcode, a: f64, b: f64) -> f64 {
+    let (a, b) = (to_int32(a), to_int32(b));
+    let result = match opcode {
+        Opcode::BitAnd => a & b,
+        Opcode::BitOr => a | b,
+        Opcode::BitXor => a ^ b,
+        Opcode::ShiftLeft => a.wrapping_shl(b as u32),
+        Opcode::ShiftRight => a.wrapping_shr(b as u32),
+        _ => unreachable!("{opcode} isn't a binary bitwise operator"),
+    };
+    result as f64
+}
+
+/// `number` without the trailing zeros of its fraction, or the point if
+/// that's all that's left.
+fn trim_zeros(number: &str) -> &str {
+    if !number.contains('.') {
+        return number;
+    }
+    number.trim_end_matches('0').trim_end_matches('.')
 }
 
 impl Display for Value {
@@ -34,11 +241,122 @@ impl Display for Value {
         match self {
             Value::Bool(bool) => bool.fmt(f),
             Value::Nil => write!(f, "nil"),
-            Value::Number(number) => number.fmt(f),
-            Value::ObjString(obj_str) => unsafe { (**obj_str).fmt(f) },
-            Value::ObjFunction(obj_func) => unsafe { (**obj_func).fmt(f) },
-            Value::ObjNative(obj_native) => unsafe { (**obj_native).fmt(f) },
-            Value::ObjClosure(obj_closure) => unsafe { (**obj_closure).fmt(f) },
+            Value::Number(number) => f.write_str(&format_number(*number)),
+            Value::ObjString(obj_str) => obj_str.get().fmt(f),
+            Value::ObjFunction(obj_func) => obj_func.get().fmt(f),
+            Value::ObjNative(obj_native) => obj_native.get().fmt(f),
+            Value::ObjClosure(obj_closure) => obj_closure.get().fmt(f),
+            Value::ObjList(obj_list) => obj_list.get().fmt(f),
+            Value::ObjMap(obj_map) => obj_map.get().fmt(f),
+            Value::ObjBytes(obj_bytes) => obj_bytes.get().fmt(f),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(bool: bool) -> Self {
+        Value::Bool(bool)
+    }
+}
+
+fn type_mismatch(expected: &str, value: &Value) -> String {
+    format!("Expected a {expected} but got {}.", value.describe())
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(number) => Ok(number),
+            _ => Err(type_mismatch("number", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(bool) => Ok(bool),
+            _ => Err(type_mismatch("bool", &value)),
         }
     }
 }
+
+impl TryFrom<Value> for String {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::ObjString(obj_str) => Ok(obj_str.get().str.clone()),
+            _ => Err(type_mismatch("string", &value)),
+        }
+    }
+}
+
+impl<T> TryFrom<Value> for Vec<T>
+where
+    T: TryFrom<Value, Error = String>,
+{
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::ObjList(list) => {
+                let list = list.get();
+                list.items.iter().cloned().map(T::try_from).collect()
+            }
+            _ => Err(type_mismatch("list", &value)),
+        }
+    }
+}
+
+/// Converts a Rust value into a Lox value, allocating any strings or lists it
+/// needs on the heap.
+pub trait IntoValue {
+    fn into_value(self, allocator: &mut Allocator) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut Allocator) -> Value {
+        self
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut Allocator) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut Allocator) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, allocator: &mut Allocator) -> Value {
+        Value::ObjString(allocator.heap_alloc(ObjString::new(self)))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, allocator: &mut Allocator) -> Value {
+        self.as_str().into_value(allocator)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, allocator: &mut Allocator) -> Value {
+        let items = self
+            .into_iter()
+            .map(|item| item.into_value(allocator))
+            .collect();
+        Value::ObjList(allocator.heap_alloc(ObjList::new(items)))
+    }
+}
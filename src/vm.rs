@@ -1,51 +1,116 @@
 use crate::chunk::Opcode;
+use crate::collector::{CollectorStrategy, MarkSweep, Stress};
 use crate::compiler;
 use crate::debug;
-use crate::memory::Allocator;
-use crate::memory::GC;
+use crate::edit_distance;
+use crate::error::{
+    CompileError, CompileWarning, LoxError, RuntimeError, SourceRange, StackFrame, StackTrace,
+    Warnings,
+};
+use crate::globals::Globals;
+use crate::heap_graph::HeapGraph;
+use crate::memory::{self, Allocator, Gc, GcMetrics, GC};
+use crate::methods;
+use crate::natives;
+use crate::object_bytes::ObjBytes;
 use crate::object_closure::ObjClosure;
-use crate::object_native::NativeFunction;
-use crate::object_native::ObjNative;
+use crate::object_function::ObjFunction;
+use crate::object_list::ObjList;
+use crate::object_map::{MapKey, ObjMap};
+use crate::object_native::{NativeError, NativeFn, ObjNative};
 use crate::object_string::ObjString;
 use crate::object_upvalue::ObjUpvalue;
-use crate::value::Value;
+use crate::profile::Profile;
+use crate::stdlib;
+use crate::value::{self, IntoValue, Value};
 use core::panic;
-use std::collections::HashMap;
-use tinyvec::ArrayVec;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const FRAMES_MAX: usize = 64;
-const STACK_MAX: usize = FRAMES_MAX * 8;
+/// How deeply calls may nest unless set with `VM::set_max_frames`.
+pub const FRAMES_MAX: usize = 64;
+/// How many stack slots to allow per call frame, when sizing the stack for a
+/// call depth.
+pub const SLOTS_PER_FRAME: usize = 8;
+/// How many values the stack holds unless set with `VM::set_stack_max`.
+pub const STACK_MAX: usize = FRAMES_MAX * SLOTS_PER_FRAME;
 
 pub struct VM<'a> {
-    pub stack: [Value; STACK_MAX],
+    pub stack: Vec<Value>,
     pub stack_top: usize,
-    pub globals: HashMap<String, Value>,
+    pub globals: Globals,
+    // Identifier names interned by the compiler, kept across `interpret` calls
+    // so every line of a REPL session shares one string per name
+    identifiers: HashMap<String, Gc<ObjString>>,
+    // Compile-time constants applied to every compilation
+    defines: HashMap<String, Value>,
+    // The pure builtins as first defined, so calls to them can be folded
+    // while their globals still hold them
+    pure_natives: HashMap<String, Gc<ObjNative>>,
     pub allocator: &'a mut Allocator,
-    pub frames: ArrayVec<[CallFrame; FRAMES_MAX]>,
-    open_upvalues: Option<*mut ObjUpvalue>,
-    debug_stress_gc: bool,
+    pub frames: Vec<CallFrame>,
+    max_frames: usize,
+    handlers: Vec<Handler>,
+    open_upvalues: Option<Gc<ObjUpvalue>>,
+    // Where `print` writes
+    output: Box<dyn Write>,
+    // Where `readLine` and `readNumber` read
+    input: natives::Input,
+    // Where compile warnings, and any a native reports, are written
+    warnings: Warnings,
+    // Decides when to collect garbage
+    collector: Box<dyn CollectorStrategy>,
+    // The objects marked but not yet traced, while a collection is marking a
+    // slice at a time
+    marking: Option<Vec<Gc<dyn GC>>>,
+    // Instructions run since the host last called in, and how many may be
+    instructions: u64,
+    instruction_limit: Option<u64>,
+    // How many bytes the heap may hold once garbage is collected
+    max_allocation_bytes: Option<usize>,
+    // Set from outside to stop the running script
+    interrupt: InterruptHandle,
+    // Why `run` stopped, when it wasn't for a runtime error
+    stopped: Option<Stop>,
+    // Where the time has gone, if it's being measured
+    profile: Option<Profile>,
     debug_log_gc: bool,
+    debug_verify_heap: bool,
+    debug_info: bool,
+    optimize: bool,
+    debug_print_code: bool,
+    debug_trace_execution: bool,
 }
 
 pub struct CallFrame {
-    pub closure: *mut ObjClosure,
+    pub closure: Gc<ObjClosure>,
     pub ip: usize,
     pub first_slot: usize,
+    // The closure's code, cached when the frame is pushed so reading an
+    // instruction doesn't go through the closure and its function each time.
+    // The closure keeps the code alive, and code isn't changed once it runs.
+    code: *const [u8],
 }
 
-impl Default for CallFrame {
-    fn default() -> Self {
+impl CallFrame {
+    fn new(closure: Gc<ObjClosure>, first_slot: usize) -> CallFrame {
+        let code = closure.get().function.get().chunk.code.as_slice() as *const [u8];
         CallFrame {
-            closure: std::ptr::null_mut(),
+            closure,
             ip: 0,
-            first_slot: 0,
+            first_slot,
+            code,
         }
     }
-}
 
-impl CallFrame {
     pub fn read_byte(&mut self) -> u8 {
-        let byte = unsafe { (*(*self.closure).function).chunk.code[self.ip] };
+        // Still bounds-checked, since loaded bytecode isn't verified
+        let byte = unsafe { (*self.code)[self.ip] };
         self.ip += 1;
         byte
     }
@@ -56,313 +121,1190 @@ impl CallFrame {
 
     pub fn read_constant(&mut self) -> Value {
         let constant = self.read_byte() as usize;
-        unsafe { (*(*self.closure).function).chunk.constants[constant].clone() }
+        self.closure.get().function.get().chunk.constants[constant].clone()
     }
 
-    fn read_string(&mut self) -> &str {
-        let constant = self.read_constant();
-        match constant {
-            Value::ObjString(obj_str) => unsafe { &(*obj_str).str },
-            _ => panic!("Not a string"),
-        }
+    fn read_string(&mut self) -> Gc<ObjString> {
+        self.read_constant().as_obj_string().expect("Not a string")
+    }
+}
+
+/// Stops a VM's running script from another thread; see
+/// `VM::interrupt_handle`.
+#[derive(Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Asks the VM to stop at its next instruction. If it isn't running, the
+    /// next script it runs stops straight away.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// The flag `interrupt` sets, for registering with a signal handler.
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.0
     }
 }
 
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+/// Why `run` stopped a script other than for a runtime error.
+enum Stop {
+    Limit,
+    Interrupt,
+}
+
+/// An active `try` block: where to resume if an exception is thrown inside it.
+struct Handler {
+    // Number of call frames live when the handler was installed
+    frame_count: usize,
+    // Stack height to unwind to before pushing the exception
+    stack_top: usize,
+    // Offset of the catch block in the installing frame's chunk
+    catch_ip: usize,
+}
+
+/// Raises a runtime error from inside `run`, resuming at the innermost
+/// `catch` block if there is one and halting otherwise.
+macro_rules! runtime_error {
+    ($vm:expr, $message:expr) => {
+        match $vm.runtime_error($message) {
+            Ok(()) => continue,
+            Err(error) => return Err(error),
+        }
+    };
+}
+
+/// Decodes the opcode `byte`, or returns `None` if it isn't one.
+#[cfg(not(feature = "unchecked-dispatch"))]
+#[inline(always)]
+fn decode(byte: u8) -> Option<Opcode> {
+    Opcode::try_from(byte).ok()
+}
+
+/// Decodes the opcode `byte` without checking it is one, which is only sound
+/// for code this crate compiled: loaded bytecode isn't verified.
+#[cfg(feature = "unchecked-dispatch")]
+#[inline(always)]
+fn decode(byte: u8) -> Option<Opcode> {
+    Some(unsafe { Opcode::from_u8_unchecked(byte) })
 }
 
 macro_rules! binary_op {
     ($struct:expr, $op:tt, $value_converter:tt) => {
-        let (Value::Number(_), Value::Number(_)) = ($struct.peek(0), $struct.peek(1)) else {
-            $struct.runtime_error("Operands must be numbers.");
-            return InterpretResult::RuntimeError;
-        };
-        let Value::Number(b) = $struct.pop_stack() else {
-            return InterpretResult::RuntimeError;
-        };
-        let Value::Number(a) = $struct.pop_stack() else {
-            return InterpretResult::RuntimeError;
+        let (Value::Number(b), Value::Number(a)) = ($struct.peek(0), $struct.peek(1)) else {
+            let message = $struct.operand_error("Operands must be numbers");
+            runtime_error!($struct, message.as_str());
         };
+        $struct.stack_top -= 2;
         $struct.push_stack($value_converter(a $op b));
     };
 }
 
 impl<'a> VM<'a> {
-    pub fn new(allocator: &mut Allocator, debug_stress_gc: bool, debug_log_gc: bool) -> VM {
-        const VALUE_ARRAY_REPEAT_VALUE: Value = Value::Number(0.0);
-        VM {
-            stack: [VALUE_ARRAY_REPEAT_VALUE; STACK_MAX],
+    pub fn new(allocator: &mut Allocator, debug_stress_gc: bool, debug_log_gc: bool) -> VM<'_> {
+        let mut vm = VM {
+            stack: vec![Value::Nil; STACK_MAX],
             stack_top: 0,
-            globals: HashMap::new(),
+            globals: Globals::new(),
+            identifiers: HashMap::new(),
+            defines: HashMap::new(),
+            pure_natives: HashMap::new(),
             allocator,
-            frames: ArrayVec::new(),
+            frames: Vec::new(),
+            max_frames: FRAMES_MAX,
+            handlers: Vec::new(),
             open_upvalues: None,
-            debug_stress_gc,
+            output: Box::new(std::io::stdout()),
+            input: Rc::new(RefCell::new(Box::new(BufReader::new(std::io::stdin())))),
+            warnings: Warnings::new(Box::new(std::io::stderr())),
+            collector: if debug_stress_gc {
+                Box::new(Stress)
+            } else {
+                Box::new(MarkSweep::default())
+            },
+            marking: None,
+            instructions: 0,
+            instruction_limit: None,
+            max_allocation_bytes: None,
+            interrupt: InterruptHandle::default(),
+            stopped: None,
+            profile: None,
             debug_log_gc,
+            debug_verify_heap: false,
+            debug_info: true,
+            optimize: false,
+            debug_print_code: false,
+            debug_trace_execution: false,
+        };
+        for (name, arity, function) in natives::BUILTINS {
+            let native = vm.define_native(name, Some(arity), Box::new(function));
+            if natives::PURE.contains(&name) {
+                vm.pure_natives.insert(name.to_owned(), native);
+            }
+        }
+        let input = vm.input.clone();
+        vm.define_native(
+            "readLine",
+            Some(0),
+            Box::new(move |allocator: &mut Allocator, args: &[Value]| {
+                natives::read_line(&input, allocator, args)
+            }),
+        );
+        let input = vm.input.clone();
+        vm.define_native(
+            "readNumber",
+            Some(0),
+            Box::new(move |allocator: &mut Allocator, args: &[Value]| {
+                natives::read_number(&input, allocator, args)
+            }),
+        );
+        let mut native_names: Vec<String> =
+            vm.globals.iter().map(|(name, _)| name.to_owned()).collect();
+        native_names.push("features".to_owned());
+        native_names.sort();
+        vm.define_native(
+            "features",
+            Some(0),
+            Box::new(move |allocator: &mut Allocator, _: &[Value]| {
+                Ok(natives::features(&native_names, allocator))
+            }),
+        );
+        let number = natives::number_namespace(vm.allocator);
+        let name = vm.intern("Number");
+        let slot = vm.globals.slot(name);
+        vm.globals.define_const_slot(slot, number);
+        let version = vm.heap_alloc(ObjString::new(env!("CARGO_PKG_VERSION")));
+        let name = vm.intern("__VERSION__");
+        vm.globals.define(name, Value::ObjString(version));
+        vm
+    }
+
+    /// Makes a Rust function callable from scripts as the global `name`. The
+    /// function is passed the call's arguments and checks their count and
+    /// types itself; an error it returns is raised as a runtime error.
+    pub fn register_native<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, NativeError> + 'static,
+    {
+        self.define_native(
+            name,
+            None,
+            Box::new(move |_: &mut Allocator, args: &[Value]| function(args)),
+        );
+    }
+
+    /// Controls whether code compiled by `interpret` carries debug info; see
+    /// `Compiler::set_debug_info`.
+    pub fn set_debug_info(&mut self, debug_info: bool) {
+        self.debug_info = debug_info;
+    }
+
+    /// Controls whether code compiled by `interpret` is optimized; see
+    /// `Compiler::set_optimize`.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Starts measuring the time spent in each phase of compiling and running
+    /// scripts, for `profile`, or stops and discards the measurements. Off by
+    /// default, since timing each token slows scanning down.
+    pub fn set_self_profile(&mut self, self_profile: bool) {
+        self.profile = self_profile.then(Profile::default);
+    }
+
+    /// Where the time has gone since `set_self_profile` turned measuring on.
+    pub fn profile(&self) -> Option<Profile> {
+        self.profile
+    }
+
+    /// Controls whether code compiled by `interpret` is disassembled to stdout.
+    pub fn set_print_code(&mut self, debug_print_code: bool) {
+        self.debug_print_code = debug_print_code;
+    }
+
+    /// Controls whether each instruction is printed, along with the stack, as
+    /// it executes.
+    pub fn set_trace_execution(&mut self, debug_trace_execution: bool) {
+        self.debug_trace_execution = debug_trace_execution;
+    }
+
+    /// Sends the output of `print` statements to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Makes `readLine` and `readNumber` read from `input` instead of stdin.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        *self.input.borrow_mut() = input;
+    }
+
+    /// Sends warnings to `warnings` instead of stderr. Pass `std::io::sink()`
+    /// to silence them.
+    pub fn set_warnings(&mut self, warnings: Box<dyn Write>) {
+        self.warnings.set_writer(warnings);
+    }
+
+    /// The VM's warning channel, for a host's natives to capture and report
+    /// to, such as when called in a deprecated way.
+    pub fn warnings(&self) -> Warnings {
+        self.warnings.clone()
+    }
+
+    /// Checks the heap after every collection, panicking if a reachable
+    /// object was freed or a survivor left marked. Slow; meant for testing
+    /// changes to the collector.
+    pub fn set_verify_heap(&mut self, verify_heap: bool) {
+        self.debug_verify_heap = verify_heap;
+    }
+
+    /// A snapshot of the objects on the heap, with the global variables as
+    /// its roots.
+    pub fn heap_graph(&self) -> HeapGraph {
+        let mut graph = HeapGraph::new(self.allocator);
+        let mut globals: Vec<_> = self.globals.iter().collect();
+        globals.sort_by_key(|(name, _)| *name);
+        for (name, value) in globals {
+            if let Some(object) = value.as_object() {
+                graph.add_root(name, object);
+            }
+        }
+        graph
+    }
+
+    /// Describes where the VM was for a crash report, after an internal error
+    /// stopped it partway through an instruction: its call frames, innermost
+    /// first, the value stack, and the current function's code with the
+    /// instruction that failed marked. The VM may be in a bad enough state for
+    /// describing it to panic too, so the report is written to as it's built
+    /// and holds everything up to that point.
+    pub fn write_crash_report(&self, report: &mut String) {
+        report.push_str("== frames ==\n");
+        for frame in self.frames.iter().rev() {
+            let function = frame.closure.get().function.get();
+            let location = match frame.ip.checked_sub(1) {
+                Some(offset) if offset < function.chunk.lines.len() => {
+                    function.chunk.location_at(offset)
+                }
+                _ => "no location".to_owned(),
+            };
+            report.push_str(&format!("{function} at ip {} ({location})\n", frame.ip));
+        }
+
+        report.push_str("\n== stack ==\n");
+        for (slot, value) in self.stack[..self.stack_top].iter().enumerate() {
+            report.push_str(&format!("{slot:4} [{}] {value}\n", value.type_name()));
+        }
+
+        if let Some(frame) = self.frames.last() {
+            let chunk = &frame.closure.get().function.get().chunk;
+            report.push_str("\n== code ==\n");
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let (instruction, next_offset) =
+                    debug::disassemble_instruction_to_string(chunk, offset);
+                // The instruction being executed holds the last byte read
+                let marker = if (offset..next_offset).contains(&frame.ip.wrapping_sub(1)) {
+                    "=>"
+                } else {
+                    "  "
+                };
+                report.push_str(&format!(
+                    "{marker} {offset:04} {:4} {instruction}",
+                    chunk.lines[offset]
+                ));
+                offset = next_offset;
+            }
         }
     }
 
-    pub fn interpret(&mut self, source: String) -> InterpretResult {
-        self.define_native("clock", NativeFunction::Clock);
+    /// Stops any script run by `interpret`, or function run by
+    /// `call_function`, once it has executed `limit` instructions, so a host
+    /// can run untrusted code without it looping forever. The count starts
+    /// over with each call into the VM.
+    pub fn set_instruction_limit(&mut self, limit: u64) {
+        self.instruction_limit = Some(limit);
+    }
+
+    /// Sets how many values the stack holds, `STACK_MAX` by default. A script
+    /// that needs more, with calls nested too deeply or too many temporaries,
+    /// fails with "Stack overflow." rather than growing the stack further.
+    pub fn set_stack_max(&mut self, slots: usize) {
+        assert!(
+            slots >= self.stack_top,
+            "Can't shrink the stack below the values on it"
+        );
+        self.stack.resize(slots, Value::Nil);
+    }
+
+    /// Sets how deeply calls may nest, `FRAMES_MAX` by default, before a call
+    /// fails with "Stack overflow.". Each call needs stack slots as well, so
+    /// allowing deeper recursion usually means raising `set_stack_max` too;
+    /// `SLOTS_PER_FRAME` slots per frame is typically enough.
+    pub fn set_max_frames(&mut self, depth: usize) {
+        self.max_frames = depth;
+    }
+
+    /// Stops a running script once the heap holds more than `bytes` even
+    /// after collecting garbage.
+    pub fn set_max_allocation_bytes(&mut self, bytes: usize) {
+        self.max_allocation_bytes = Some(bytes);
+    }
+
+    /// A handle another thread can use to stop whatever this VM is running at
+    /// its next instruction, such as on Ctrl-C or a timeout. The script stops
+    /// with `LoxError::Interrupted`, which a `try` block can't catch.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Collects garbage, then lists the objects that survive by where they
+    /// were allocated; see `Allocator::leak_report`.
+    pub fn leak_report(&mut self) -> String {
+        self.collect_garbage();
+        self.allocator.leak_report()
+    }
+
+    /// Replaces the strategy deciding when to collect garbage. A collection
+    /// frees every object not reachable from the VM's stack, globals, or
+    /// call frames, so a `Value` the host holds on to may be freed by the next
+    /// call into the VM unless it's stored somewhere the VM can see.
+    pub fn set_collector(&mut self, collector: Box<dyn CollectorStrategy>) {
+        self.collector = collector;
+    }
+
+    /// Defines a compile-time constant for code compiled by `interpret`; see
+    /// `Compiler::define_constant`.
+    pub fn define_constant(&mut self, name: &str, value: Value) {
+        self.defines.insert(name.to_owned(), value);
+    }
+
+    pub fn interpret(&mut self, source: String) -> Result<(), LoxError> {
+        let debug_print_code = self.debug_print_code;
+        let start = Instant::now();
+        let mut compiler = self.compiler(source.as_str());
+        let function = compiler.compile(debug_print_code);
+        let (scanning, tokens) = (compiler.scan_time(), compiler.token_count());
+        let warnings = compiler.take_warnings();
+        self.profile_compilation(start.elapsed(), scanning, tokens);
+        self.report_warnings(&warnings);
+        self.run_script(function).map(|_| ())
+    }
+
+    /// Defines the standard library's functions as globals; see `stdlib`.
+    pub fn load_stdlib(&mut self) -> Result<(), LoxError> {
+        self.interpret_sources(&stdlib::SOURCES)
+    }
+
+    /// Statistics on the garbage collections done so far on this VM's heap.
+    pub fn gc_metrics(&self) -> GcMetrics {
+        self.allocator.stats()
+    }
+
+    /// Runs a script function compiled ahead of time, such as one loaded from
+    /// a `.loxc` file by `bytecode::deserialize`.
+    pub fn run_function(&mut self, function: Gc<ObjFunction>) -> Result<(), LoxError> {
+        // A loaded program has its own copy of every string constant, names
+        // included, so swap in this VM's interned ones for its global
+        // instructions to find
+        for function in ObjFunction::collect_functions(function) {
+            let constants = &mut function.get_mut().chunk.constants;
+            for constant in constants.iter_mut() {
+                if let Value::ObjString(string) = constant {
+                    *string = self.intern(&string.get().str.clone());
+                }
+            }
+        }
+        self.run_script(Ok(function)).map(|_| ())
+    }
+
+    /// The VM's one string for the identifier `name`, shared with the code it
+    /// compiles. The intern table doesn't keep it alive, so it must be stored
+    /// somewhere the collector looks before the VM next allocates.
+    fn intern(&mut self, name: &str) -> Gc<ObjString> {
+        if let Some(&interned) = self.identifiers.get(name) {
+            return interned;
+        }
+        // Allocated without collecting, so callers needn't root what they're
+        // holding while interning
+        let interned = self.allocator.heap_alloc(ObjString::new(name));
+        self.identifiers.insert(name.to_owned(), interned);
+        // It may be stored in a function a marking collection has traced
+        if let Some(gray) = &mut self.marking {
+            VM::mark_object(interned.erase(), gray, self.debug_log_gc);
+        }
+        interned
+    }
+
+    fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(*self.identifiers.get(name)?)
+    }
+
+    /// Runs `source` as a REPL line, returning the value of its trailing
+    /// expression statement if it ends with one; see `Compiler::set_repl`.
+    pub(crate) fn eval(&mut self, source: &str) -> Result<Option<Value>, LoxError> {
+        let debug_print_code = self.debug_print_code;
+        let start = Instant::now();
+        let mut compiler = self.compiler(source);
+        compiler.set_repl(true);
+        let function = compiler.compile(debug_print_code);
+        let returns_expression = compiler.returns_expression();
+        let (scanning, tokens) = (compiler.scan_time(), compiler.token_count());
+        let warnings = compiler.take_warnings();
+        self.profile_compilation(start.elapsed(), scanning, tokens);
+        self.report_warnings(&warnings);
+        let result = self.run_script(function)?;
+        Ok(returns_expression.then_some(result))
+    }
+
+    /// Compiles named sources into one program, as with
+    /// `Compiler::compile_sources`, and runs it.
+    pub fn interpret_sources(&mut self, sources: &[(&str, &str)]) -> Result<(), LoxError> {
+        let debug_print_code = self.debug_print_code;
+        let start = Instant::now();
+        let mut compiler = self.compiler("");
+        let function = compiler.compile_sources(sources, debug_print_code);
+        let (scanning, tokens) = (compiler.scan_time(), compiler.token_count());
+        let warnings = compiler.take_warnings();
+        self.profile_compilation(start.elapsed(), scanning, tokens);
+        self.report_warnings(&warnings);
+        self.run_script(function).map(|_| ())
+    }
+
+    /// Adds a compilation that took `elapsed`, `scanning` of it in the
+    /// scanner, to the profile if one's being kept.
+    fn profile_compilation(
+        &mut self,
+        elapsed: Duration,
+        scanning: Option<Duration>,
+        tokens: usize,
+    ) {
+        if let Some(profile) = &mut self.profile {
+            let scanning = scanning.unwrap_or_default();
+            profile.scanning += scanning;
+            profile.compiling += elapsed.saturating_sub(scanning);
+            profile.tokens += tokens;
+        }
+    }
+
+    fn report_warnings(&self, warnings: &[CompileWarning]) {
+        for warning in warnings {
+            self.warnings.compile_warning(warning);
+        }
+    }
+
+    /// Creates a compiler for `source` set up with this VM's options.
+    fn compiler<'b>(&'b mut self, source: &'b str) -> compiler::Compiler<'b> {
+        let foldable = self
+            .pure_natives
+            .iter()
+            .filter(|(name, native)| self.global(name) == Some(&Value::ObjNative(**native)))
+            .map(|(name, _)| name.clone())
+            .collect();
         let mut compiler = compiler::Compiler::new(
-            source.as_str(),
+            source,
             self.allocator,
-            self.debug_stress_gc,
-            self.debug_log_gc,
+            &mut self.identifiers,
+            compiler::CompilerLimits::default(),
         );
+        compiler.set_globals(&mut self.globals);
+        compiler.set_debug_info(self.debug_info);
+        compiler.set_optimize(self.optimize);
+        compiler.set_foldable_natives(foldable);
+        compiler.set_time_scanning(self.profile.is_some());
+        for (name, value) in self.defines.iter() {
+            compiler.define_constant(name, value.clone());
+        }
         compiler.prepare();
-        match compiler.compile(true) {
-            Some(function) => {
-                self.push_stack(Value::ObjFunction(function));
-                let obj_closure = self.allocator.heap_alloc(ObjClosure::new(function));
-                self.pop_stack();
-                self.push_stack(Value::ObjClosure(obj_closure));
-                self.call(obj_closure, 0);
-            }
-            None => return InterpretResult::CompileError,
+        compiler
+    }
+
+    /// Runs a compiled script, returning the value it returns.
+    fn run_script(
+        &mut self,
+        function: Result<Gc<ObjFunction>, Vec<CompileError>>,
+    ) -> Result<Value, LoxError> {
+        let function = function.map_err(LoxError::Compile)?;
+        self.push_stack(Value::ObjFunction(function));
+        let obj_closure = self.allocator.heap_alloc(ObjClosure::new(function));
+        self.pop_stack();
+        self.push_stack(Value::ObjClosure(obj_closure));
+        self.call(obj_closure, 0)
+            .expect("The top-level script takes no arguments");
+
+        self.instructions = 0;
+        self.stopped = None;
+        let gc_before = self.allocator.stats();
+        let start = Instant::now();
+        let result = self.run(0);
+        if let Some(profile) = &mut self.profile {
+            let gc_after = self.allocator.stats();
+            let gc = gc_after.total_pause - gc_before.total_pause;
+            profile.gc += gc;
+            profile.execution += start.elapsed().saturating_sub(gc);
+            profile.collections += gc_after.collections - gc_before.collections;
+        }
+        result.map_err(|error| match self.stopped.take() {
+            Some(Stop::Limit) => LoxError::LimitExceeded(error),
+            Some(Stop::Interrupt) => LoxError::Interrupted(error),
+            None => LoxError::Runtime(error),
+        })?;
+        Ok(self.pop_stack())
+    }
+
+    /// Reads the global `name`, converted to a Rust type.
+    pub fn get_global<T>(&self, name: &str) -> Result<T, String>
+    where
+        T: TryFrom<Value, Error = String>,
+    {
+        match self.global(name) {
+            Some(value) => T::try_from(value.clone()),
+            None => Err(format!("Undefined variable '{name}'.")),
+        }
+    }
+
+    /// Defines or overwrites the global `name` with a Rust value.
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) {
+        let name = self.intern(name);
+        let value = value.into_value(self.allocator);
+        self.globals.define(name, value);
+    }
+
+    /// Calls the global function `name` with `args`, running it to completion.
+    /// Meant for invoking script-defined callbacks after `interpret` has run.
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        let Some(callee) = self.global(name).cloned() else {
+            return Err(RuntimeError::new(format!("Undefined function '{name}'.")));
         };
+        if self.stack_top + args.len() + 1 > self.stack.len() {
+            return Err(RuntimeError::new("Stack overflow."));
+        }
+
+        let base_frame_count = self.frames.len();
+        let base_stack_top = self.stack_top;
+        self.push_stack(callee.clone());
+        for arg in args {
+            self.push_stack(arg.clone());
+        }
+        if let Err(message) = self.call_value(callee, args.len()) {
+            self.stack_top = base_stack_top;
+            return Err(RuntimeError::new(message));
+        }
 
-        self.run(false)
+        // Natives have already left their result on the stack
+        if self.frames.len() > base_frame_count {
+            self.instructions = 0;
+            self.stopped = None;
+            self.run(base_frame_count)?;
+        }
+        Ok(self.pop_stack())
     }
 
-    pub fn run(&mut self, debug_trace_execution: bool) -> InterpretResult {
+    /// Executes bytecode until the call stack unwinds to `base_frame_count`
+    /// frames, leaving the last returned value on the stack.
+    fn run(&mut self, base_frame_count: usize) -> Result<(), RuntimeError> {
         loop {
             let byte = self.read_byte();
-            if let Ok(instruction) = Opcode::try_from(byte) {
-                if debug_trace_execution {
-                    print!("          ");
-                    for slot in self.stack[0..self.stack_top].iter() {
-                        print!("[ {slot} ]");
+            if let Some((stop, message)) = self.check_stop() {
+                self.stopped = Some(stop);
+                return Err(self.report_error(&message));
+            }
+            if self.marking.is_some() {
+                let slice = self.collector.mark_slice().unwrap_or(usize::MAX);
+                self.mark_slice(slice);
+            }
+            // No instruction grows the stack by more than one value
+            if self.stack_top == self.stack.len() {
+                runtime_error!(self, "Stack overflow.");
+            }
+            let Some(instruction) = decode(byte) else {
+                return Err(self.unknown_opcode(byte));
+            };
+            if self.debug_trace_execution {
+                let offset = self.current_ip() - 1;
+                let function = self.frames.last().unwrap().closure.get().function;
+                let trace = debug::trace_instruction_to_string(
+                    &self.stack[0..self.stack_top],
+                    &function.get().chunk,
+                    offset,
+                );
+                print!("{trace}");
+            }
+            match instruction {
+                Opcode::Constant => {
+                    let constant = self.read_constant();
+                    self.push_stack(constant);
+                }
+                Opcode::CopyConstant => {
+                    let Value::ObjBytes(bytes) = self.read_constant() else {
+                        unreachable!("Only bytes constants are copied");
+                    };
+                    let copy = self.heap_alloc(ObjBytes::new(bytes.get().bytes.clone()));
+                    self.push_stack(Value::ObjBytes(copy));
+                }
+                Opcode::Negate => {
+                    let Some(number_value) = self.peek(0).as_number() else {
+                        let message = self.operand_error("Operand must be a number");
+                        runtime_error!(self, message.as_str());
+                    };
+                    self.pop_stack();
+                    self.push_stack(Value::Number(-number_value));
+                }
+                Opcode::BitNot => {
+                    let Some(number_value) = self.peek(0).as_number() else {
+                        let message = self.operand_error("Operand must be a number");
+                        runtime_error!(self, message.as_str());
+                    };
+                    self.pop_stack();
+                    self.push_stack(Value::Number(!value::to_int32(number_value) as f64));
+                }
+                Opcode::BitAnd
+                | Opcode::BitOr
+                | Opcode::BitXor
+                | Opcode::ShiftLeft
+                | Opcode::ShiftRight => {
+                    let (Value::Number(b), Value::Number(a)) = (self.peek(0), self.peek(1)) else {
+                        let message = self.operand_error("Operands must be numbers");
+                        runtime_error!(self, message.as_str());
+                    };
+                    self.stack_top -= 2;
+                    self.push_stack(Value::Number(value::bitwise(instruction, a, b)));
+                }
+                Opcode::Return => {
+                    let result = self.pop_stack();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.first_slot);
+                    // Handlers installed by the returning function no longer apply
+                    while matches!(self.handlers.last(), Some(handler) if handler.frame_count > self.frames.len())
+                    {
+                        self.handlers.pop();
                     }
-                    println!();
-                    debug::disassemble_instruction(
-                        &instruction,
-                        unsafe { &(*(*(self.frames.last_mut().unwrap().closure)).function).chunk },
-                        self.current_ip() - 1,
-                    );
-                }
-                match instruction {
-                    Opcode::Constant => {
-                        let constant = self.read_constant();
-                        self.push_stack(constant);
+                    self.stack_top = frame.first_slot;
+                    self.push_stack(result);
+                    if self.frames.len() == base_frame_count {
+                        return Ok(());
                     }
-                    Opcode::Negate => {
-                        let value = self.peek(0);
-                        match value {
-                            Value::Number(number_value) => {
-                                self.push_stack(Value::Number(-number_value));
-                            }
-                            _ => {
-                                self.runtime_error("Operand must be a number.");
-                                return InterpretResult::RuntimeError;
-                            }
-                        }
+                }
+                Opcode::Nil => {
+                    self.push_stack(Value::Nil);
+                }
+                Opcode::True => {
+                    self.push_stack(Value::Bool(true));
+                }
+                Opcode::False => {
+                    self.push_stack(Value::Bool(false));
+                }
+                Opcode::Add => match (self.peek(1), self.peek(0)) {
+                    (Value::ObjString(_), Value::ObjString(_)) => {
+                        self.concatenate();
                     }
-                    Opcode::Return => {
-                        let result = self.pop_stack();
-                        let frame = self.frames.pop().unwrap();
-                        self.close_upvalues(frame.first_slot);
-                        if self.frames.is_empty() {
-                            self.pop_stack();
-                            return InterpretResult::Ok;
-                        }
-                        self.stack_top = frame.first_slot;
-                        self.push_stack(result);
+                    (Value::Number(_), Value::Number(_)) => {
+                        binary_op!(self, +, (Value::to_number_value));
                     }
-                    Opcode::Nil => {
-                        self.push_stack(Value::Nil);
+                    (a, b) => {
+                        let mut message =
+                            self.operand_error("Operands must be two numbers or two strings");
+                        // Adding a string to anything else is usually meant to
+                        // concatenate, which Lox doesn't do implicitly
+                        if matches!(a, Value::ObjString(_)) != matches!(b, Value::ObjString(_)) {
+                            message.push_str(" Convert the other operand with str() first.");
+                        }
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::True => {
-                        self.push_stack(Value::Bool(true));
+                },
+                Opcode::Subtract => {
+                    binary_op!(self, -, (Value::to_number_value));
+                }
+                Opcode::Multiply => {
+                    binary_op!(self, *, (Value::to_number_value));
+                }
+                Opcode::Divide => {
+                    binary_op!(self, /, (Value::to_number_value));
+                }
+                Opcode::Not => {
+                    let value = self.pop_stack();
+                    self.push_stack(Value::Bool(value.is_falsey()));
+                }
+                Opcode::Equal => {
+                    let (a, b) = (self.pop_stack(), self.pop_stack());
+                    // We should be interning string values for performance reasons
+                    // to avoid walking the length of both strings in `==`,
+                    // but that's a hassle, so I don't bother doing it here
+                    self.push_stack(Value::Bool(a == b));
+                }
+                Opcode::Greater => {
+                    binary_op!(self, >, (Value::to_bool_value));
+                }
+                Opcode::Less => {
+                    binary_op!(self, <, (Value::to_bool_value));
+                }
+                Opcode::Print => {
+                    let value = self.pop_stack();
+                    if let Err(error) = writeln!(self.output, "{value}") {
+                        let message = format!("Failed to print: {error}.");
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::False => {
-                        self.push_stack(Value::Bool(false));
+                }
+                Opcode::Pop => {
+                    self.pop_stack();
+                }
+                Opcode::DefineGlobal | Opcode::DefineGlobalConst => {
+                    let name = self.read_string();
+                    let slot = self.globals.slot(name);
+                    let is_const = instruction == Opcode::DefineGlobalConst;
+                    if let Err(message) = self.define_global(slot, is_const) {
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::Add => {
-                        if let (Value::ObjString(_), Value::ObjString(_)) =
-                            (self.peek(0), self.peek(1))
-                        {
-                            match self.concatenate() {
-                                Ok(_) => {}
-                                Err(err) => return err,
-                            }
-                        } else {
-                            binary_op!(self, +, (Value::to_number_value));
+                }
+                Opcode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(name) {
+                        Some(value) => self.push_stack(value.clone()),
+                        None => {
+                            let message = self.undefined_variable_message(&name.get().str, 2);
+                            runtime_error!(self, message.as_str());
                         }
                     }
-                    Opcode::Subtract => {
-                        binary_op!(self, -, (Value::to_number_value));
-                    }
-                    Opcode::Multiply => {
-                        binary_op!(self, *, (Value::to_number_value));
+                }
+                Opcode::SetGlobal => {
+                    let name = self.read_string();
+                    let slot = self.globals.slot(name);
+                    if self.globals.is_const(slot) {
+                        let message = format!("Can't assign to constant '{}'.", name.get().str);
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::Divide => {
-                        binary_op!(self, /, (Value::to_number_value));
+                    if !self.globals.assign_slot(slot, self.peek(0)) {
+                        let message = self.undefined_variable_message(&name.get().str, 2);
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::Not => {
-                        let value = self.pop_stack();
-                        self.push_stack(Value::Bool(value.is_falsey()));
+                }
+                Opcode::DefineGlobalSlot | Opcode::DefineGlobalConstSlot => {
+                    let slot = self.read_short() as usize;
+                    let is_const = instruction == Opcode::DefineGlobalConstSlot;
+                    if let Err(message) = self.define_global(slot, is_const) {
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::Equal => {
-                        let (a, b) = (self.pop_stack(), self.pop_stack());
-                        // We should be interning string values for performance reasons
-                        // to avoid walking the length of both strings in `==`,
-                        // but that's a hassle, so I don't bother doing it here
-                        self.push_stack(Value::Bool(a == b));
+                }
+                Opcode::GetGlobalSlot => {
+                    let slot = self.read_short() as usize;
+                    match self.globals.get_slot(slot) {
+                        Some(value) => self.push_stack(value.clone()),
+                        None => {
+                            let message =
+                                self.undefined_variable_message(self.globals.name(slot), 3);
+                            runtime_error!(self, message.as_str());
+                        }
                     }
-                    Opcode::Greater => {
-                        binary_op!(self, >, (Value::to_bool_value));
+                }
+                Opcode::SetGlobalSlot => {
+                    let slot = self.read_short() as usize;
+                    if self.globals.is_const(slot) {
+                        let message =
+                            format!("Can't assign to constant '{}'.", self.globals.name(slot));
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::Less => {
-                        binary_op!(self, <, (Value::to_bool_value));
+                    if !self.globals.assign_slot(slot, self.peek(0)) {
+                        let message = self.undefined_variable_message(self.globals.name(slot), 3);
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::Print => {
-                        let value = self.pop_stack();
-                        println!("{value}");
+                }
+                Opcode::GetLocal => {
+                    let slot = self.read_slot();
+                    self.push_stack(self.stack[slot].clone());
+                }
+                Opcode::SetLocal => {
+                    let slot = self.read_slot();
+                    self.stack[slot] = self.peek(0);
+                }
+                Opcode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    let is_falsey = self.peek(0).is_falsey();
+                    if is_falsey {
+                        self.inc_ip(offset as usize);
                     }
-                    Opcode::Pop => {
-                        self.pop_stack();
+                }
+                Opcode::JumpIfNotNil => {
+                    let offset = self.read_short();
+                    if !matches!(self.peek(0), Value::Nil) {
+                        self.inc_ip(offset as usize);
                     }
-                    Opcode::DefineGlobal => {
-                        let name = self.read_string().to_owned();
-                        self.globals.insert(name, self.peek(0));
-                        self.pop_stack();
+                }
+                Opcode::JumpIfTrue => {
+                    let offset = self.read_short();
+                    let is_falsey = self.peek(0).is_falsey();
+                    if !is_falsey {
+                        self.inc_ip(offset as usize);
                     }
-                    Opcode::GetGlobal => {
-                        let name = self.read_string().to_owned();
-                        match self.globals.get(&name) {
-                            Some(value) => self.push_stack(value.clone()),
-                            None => {
-                                self.runtime_error(format!("Undefined variable {name}.").as_str());
-                                return InterpretResult::RuntimeError;
-                            }
+                }
+                Opcode::Jump => {
+                    let offset = self.read_short();
+                    self.inc_ip(offset as usize);
+                }
+                Opcode::Loop => {
+                    let offset = self.read_short();
+                    self.dec_ip(offset as usize);
+                }
+                Opcode::GetProperty => {
+                    let name = self.read_string();
+                    let property = match self.peek(0) {
+                        Value::ObjMap(map) if map.get().is_frozen() => {
+                            let key = MapKey::String(name.get().str.clone());
+                            map.get().get(&key).cloned()
                         }
+                        _ => {
+                            let message = format!(
+                                "Only namespaces have properties, not {}.",
+                                self.peek(0).type_name()
+                            );
+                            runtime_error!(self, message.as_str());
+                        }
+                    };
+                    let Some(property) = property else {
+                        let message = format!("Undefined property '{}'.", name.get().str);
+                        runtime_error!(self, message.as_str());
+                    };
+                    self.pop_stack();
+                    self.push_stack(property);
+                }
+                Opcode::Invoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte() as usize;
+                    if let Err(message) = self.invoke(name, arg_count) {
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::SetGlobal => {
-                        let name = self.read_string().to_owned();
-                        match self.globals.insert(name.clone(), self.peek(0)) {
-                            Some(_) => {}
-                            None => {
-                                self.globals.remove(&name);
-                                self.runtime_error(
-                                    format!("Undefined variable {}.", name.clone()).as_str(),
-                                );
-                                return InterpretResult::RuntimeError;
+                }
+                Opcode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count);
+                    if let Err(message) = self.call_value(callee.clone(), arg_count) {
+                        match self.runtime_error(&message) {
+                            Ok(()) => continue,
+                            Err(mut error) => {
+                                // A native that fails is the innermost call
+                                if let Some(native) = callee.as_native() {
+                                    let name = &native.get().name;
+                                    error.stack_trace.push_native(name);
+                                }
+                                return Err(error);
                             }
                         }
                     }
-                    Opcode::GetLocal => {
-                        let slot = self.read_slot();
-                        self.push_stack(self.stack[slot].clone());
-                    }
-                    Opcode::SetLocal => {
-                        let slot = self.read_slot();
-                        self.push_stack(self.stack[slot].clone());
-                        self.stack[slot] = self.peek(0);
+                }
+                Opcode::Closure => {
+                    let obj_fun = self
+                        .read_constant()
+                        .as_function()
+                        .expect("Invalid constant for Opcode::Closure");
+                    let closure = self.heap_alloc(ObjClosure::new(obj_fun));
+                    self.push_stack(Value::ObjClosure(closure));
+                    let upvalue_count = closure.get().upvalue_count;
+                    // A frame-bound function reads its captures from this
+                    // frame when it's called, so none are taken here
+                    let frame_bound = obj_fun.get().frame_bound;
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte();
+                        let index = self.read_byte();
+                        let value = if frame_bound {
+                            continue;
+                        } else if is_local == 1 {
+                            let location =
+                                self.frames.last().unwrap().first_slot + (index as usize);
+                            self.capture_upvalue(location)
+                        } else {
+                            let enclosing = self.frames.last().unwrap().closure;
+                            enclosing.get().upvalues[index as usize]
+                        };
+                        closure.get_mut().upvalues.push(value);
                     }
-                    Opcode::JumpIfFalse => {
-                        let offset = self.read_short();
-                        let is_falsey = self.peek(0).is_falsey();
-                        if is_falsey {
-                            self.inc_ip(offset as usize);
+                }
+                Opcode::GetCallerLocal => {
+                    let slot = self.read_byte() as usize;
+                    let caller = &self.frames[self.frames.len() - 2];
+                    self.push_stack(self.stack[caller.first_slot + slot].clone());
+                }
+                Opcode::SetCallerLocal => {
+                    let slot = self.read_byte() as usize;
+                    let caller = &self.frames[self.frames.len() - 2];
+                    self.stack[caller.first_slot + slot] = self.peek(0);
+                }
+                Opcode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let closure = self.frames.last().unwrap().closure;
+                    let upvalue = closure.get().upvalues[slot].get();
+                    match upvalue.closed.clone() {
+                        Some(closed) => {
+                            self.push_stack(closed);
                         }
-                    }
-                    Opcode::Jump => {
-                        let offset = self.read_short();
-                        self.inc_ip(offset as usize);
-                    }
-                    Opcode::Loop => {
-                        let offset = self.read_short();
-                        self.dec_ip(offset as usize);
-                    }
-                    Opcode::Call => {
-                        let arg_count = self.read_byte() as usize;
-                        if !self.call_value(self.peek(arg_count), arg_count) {
-                            return InterpretResult::RuntimeError;
+                        None => {
+                            let value = self.stack[upvalue.location].clone();
+                            self.push_stack(value);
                         }
                     }
-                    Opcode::Closure => {
-                        let Value::ObjFunction(obj_fun) = self.read_constant() else {
-                            panic!("Invalid constant for Opcode::Closure");
-                        };
-                        let closure = self.heap_alloc(ObjClosure::new(obj_fun));
-                        self.push_stack(Value::ObjClosure(closure));
-                        let upvalue_count = unsafe { (*closure).upvalue_count };
-                        for i in 0..upvalue_count {
-                            let is_local = self.read_byte();
-                            let index = self.read_byte();
-                            let value = if is_local == 1 {
-                                let location =
-                                    self.frames.last().unwrap().first_slot + (index as usize);
-                                self.capture_upvalue(location)
-                            } else {
-                                unsafe {
-                                    (*self.frames.last().unwrap().closure).upvalues[index as usize]
-                                }
-                            };
-                            unsafe { (*closure).upvalues[i] = value }
+                }
+                Opcode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let value = self.peek(0);
+                    let closure = self.frames.last().unwrap().closure;
+                    let upvalue = closure.get().upvalues[slot].get_mut();
+                    match upvalue.closed {
+                        Some(_) => {
+                            self.write_barrier(&value);
+                            upvalue.closed = Some(value);
+                        }
+                        None => {
+                            self.stack[upvalue.location] = value;
                         }
                     }
-                    Opcode::GetUpvalue => {
-                        let slot = self.read_byte() as usize;
-                        unsafe {
-                            let closure = self.frames.last().unwrap().closure.clone();
-                            let upvalue = (*closure).upvalues[slot].clone();
-                            match (*upvalue).closed.clone() {
-                                Some(closed) => {
-                                    self.push_stack(closed);
-                                }
-                                None => {
-                                    let location = (*upvalue).location;
-                                    let value = self.stack[location].clone();
-                                    self.push_stack(value);
-                                }
-                            }
+                }
+                Opcode::CloseUpvalue => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop_stack();
+                }
+                Opcode::BuildList => {
+                    let item_count = self.read_byte() as usize;
+                    let items = self.stack[self.stack_top - item_count..self.stack_top].to_vec();
+                    let list = self.heap_alloc(ObjList::new(items));
+                    self.stack_top -= item_count;
+                    self.push_stack(Value::ObjList(list));
+                }
+                Opcode::IndexGet => {
+                    let item = match self.index_get(self.peek(1), self.peek(0)) {
+                        Ok(item) => item,
+                        Err(message) => {
+                            runtime_error!(self, message.as_str());
                         }
+                    };
+                    self.stack_top -= 2;
+                    self.push_stack(item);
+                }
+                Opcode::IndexSet => {
+                    let value = self.pop_stack();
+                    if let Err(message) = self.index_set(self.peek(1), self.peek(0), value.clone())
+                    {
+                        runtime_error!(self, message.as_str());
                     }
-                    Opcode::SetUpvalue => {
-                        let slot = self.read_byte() as usize;
-                        let value = self.peek(0);
-                        unsafe {
-                            let closure = self.frames.last().unwrap().closure.clone();
-                            let upvalue = (*closure).upvalues[slot].clone();
-                            match (*upvalue).closed.clone() {
-                                Some(_) => {
-                                    (*upvalue).closed = Some(value);
-                                }
-                                None => {
-                                    let location = (*upvalue).location;
-                                    self.stack[location] = value;
-                                }
+                    self.stack_top -= 2;
+                    self.push_stack(value);
+                }
+                Opcode::BuildMap => {
+                    let entry_count = self.read_byte() as usize;
+                    let map = self.heap_alloc(ObjMap::new());
+                    let entries_start = self.stack_top - entry_count * 2;
+                    for i in 0..entry_count {
+                        let key_value = self.stack[entries_start + i * 2].clone();
+                        let value = self.stack[entries_start + i * 2 + 1].clone();
+                        let key = match MapKey::from_value(&key_value) {
+                            Ok(key) => key,
+                            Err(message) => {
+                                runtime_error!(self, message.as_str());
                             }
-                        }
+                        };
+                        map.get_mut().insert(key, key_value, value);
                     }
-                    Opcode::CloseUpvalue => {
-                        self.close_upvalues(self.stack_top - 1);
-                        self.pop_stack();
+                    self.allocator.track_growth(map);
+                    self.stack_top = entries_start;
+                    self.push_stack(Value::ObjMap(map));
+                }
+                Opcode::Throw => {
+                    let exception = self.pop_stack();
+                    if !self.throw_value(exception.clone()) {
+                        let message = format!("Uncaught exception: {exception}");
+                        return Err(self.report_error(message.as_str()));
                     }
                 }
+                Opcode::PushHandler => {
+                    let offset = self.read_short() as usize;
+                    let catch_ip = self.current_ip() + offset;
+                    self.handlers.push(Handler {
+                        frame_count: self.frames.len(),
+                        stack_top: self.stack_top,
+                        catch_ip,
+                    });
+                }
+                Opcode::PopHandler => {
+                    self.handlers.pop();
+                }
+                Opcode::IterNext => {
+                    // The loop variable lives in `slot`, followed by the
+                    // collection being iterated and the iteration cursor
+                    let slot = self.read_slot();
+                    let next = match self
+                        .iter_next(self.stack[slot + 1].clone(), self.stack[slot + 2].clone())
+                    {
+                        Ok(next) => next,
+                        Err(message) => {
+                            runtime_error!(self, message.as_str());
+                        }
+                    };
+                    match next {
+                        Some((item, cursor)) => {
+                            self.stack[slot] = item;
+                            self.stack[slot + 2] = Value::Number(cursor as f64);
+                            self.push_stack(Value::Bool(true));
+                        }
+                        None => self.push_stack(Value::Bool(false)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports a byte that isn't an opcode, which only corrupt or unverified
+    /// bytecode has, out of the way of the dispatch loop.
+    #[cold]
+    fn unknown_opcode(&mut self, byte: u8) -> RuntimeError {
+        self.report_error(&format!("Unknown opcode {byte}."))
+    }
+
+    fn index_get(&self, container: Value, index: Value) -> Result<Value, String> {
+        match container {
+            Value::ObjList(list) => {
+                let list = list.get();
+                let index = VM::list_index(list, &index)?;
+                Ok(list.items[index].clone())
+            }
+            Value::ObjMap(map) => {
+                let map = map.get();
+                let key = MapKey::from_value(&index)?;
+                match map.get(&key) {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(format!("Key '{index}' not found in map.")),
+                }
+            }
+            Value::ObjBytes(bytes) => {
+                let bytes = &bytes.get().bytes;
+                let index = VM::bytes_index(bytes, &index)?;
+                Ok(Value::Number(bytes[index] as f64))
+            }
+            _ => Err("Can only index into lists, maps and bytes.".to_string()),
+        }
+    }
+
+    /// Advances a `for-in` loop, returning the next item and the cursor to
+    /// resume from, or `None` once the collection is exhausted. Lists and
+    /// bytes are walked by index, maps by key in insertion order, and strings
+    /// by character, with the cursor holding a byte offset.
+    fn iter_next(
+        &mut self,
+        collection: Value,
+        cursor: Value,
+    ) -> Result<Option<(Value, usize)>, String> {
+        let cursor = cursor.as_number().expect("Invalid for-in cursor") as usize;
+        match collection {
+            Value::ObjList(list) => {
+                let list = list.get();
+                Ok(list
+                    .items
+                    .get(cursor)
+                    .map(|item| (item.clone(), cursor + 1)))
+            }
+            Value::ObjMap(map) => {
+                let map = map.get();
+                Ok(map
+                    .entries()
+                    .get(cursor)
+                    .map(|(key, _)| (key.clone(), cursor + 1)))
+            }
+            Value::ObjBytes(bytes) => {
+                let bytes = &bytes.get().bytes;
+                Ok(bytes
+                    .get(cursor)
+                    .map(|byte| (Value::Number(*byte as f64), cursor + 1)))
+            }
+            Value::ObjString(obj_str) => {
+                let string = &obj_str.get().str;
+                let Some(c) = string[cursor..].chars().next() else {
+                    return Ok(None);
+                };
+                let item = self.heap_alloc(ObjString::new(c.encode_utf8(&mut [0; 4])));
+                Ok(Some((Value::ObjString(item), cursor + c.len_utf8())))
+            }
+            _ => Err(format!(
+                "Can only iterate over lists, maps, strings and bytes, not {}.",
+                collection.describe()
+            )),
+        }
+    }
+
+    fn index_set(&mut self, container: Value, index: Value, value: Value) -> Result<(), String> {
+        match container {
+            Value::ObjList(list) => {
+                let list = list.get_mut();
+                let index = VM::list_index(list, &index)?;
+                self.write_barrier(&value);
+                list.items[index] = value;
+                Ok(())
+            }
+            Value::ObjMap(map) if map.get().is_frozen() => {
+                Err("Can't change a namespace.".to_string())
+            }
+            Value::ObjMap(map) => {
+                let key = MapKey::from_value(&index)?;
+                self.write_barrier(&index);
+                self.write_barrier(&value);
+                map.get_mut().insert(key, index, value);
+                self.allocator.track_growth(map);
+                Ok(())
             }
+            Value::ObjBytes(bytes) => {
+                let bytes = &mut bytes.get_mut().bytes;
+                let index = VM::bytes_index(bytes, &index)?;
+                bytes[index] = match value {
+                    Value::Number(byte) if byte.fract() == 0.0 && (0.0..256.0).contains(&byte) => {
+                        byte as u8
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Can't store {} in bytes; expect a number from 0 to 255.",
+                            value.describe()
+                        ))
+                    }
+                };
+                Ok(())
+            }
+            _ => Err("Can only index into lists, maps and bytes.".to_string()),
+        }
+    }
+
+    fn bytes_index(bytes: &[u8], index: &Value) -> Result<usize, String> {
+        let Value::Number(index) = *index else {
+            return Err("Bytes index must be a number.".to_string());
+        };
+        let len = bytes.len();
+        if index.fract() != 0.0 || index < 0.0 || index >= len as f64 {
+            return Err(format!(
+                "Bytes index {index} out of range for bytes of length {len}."
+            ));
+        }
+        Ok(index as usize)
+    }
+
+    fn list_index(list: &ObjList, index: &Value) -> Result<usize, String> {
+        let Value::Number(index) = *index else {
+            return Err("List index must be a number.".to_string());
+        };
+        let len = list.items.len();
+        if index.fract() != 0.0 || index < 0.0 || index >= len as f64 {
+            return Err(format!(
+                "List index {index} out of range for list of length {len}."
+            ));
         }
+        Ok(index as usize)
     }
 
-    fn capture_upvalue(&mut self, location: usize) -> *mut ObjUpvalue {
+    fn capture_upvalue(&mut self, location: usize) -> Gc<ObjUpvalue> {
         // Search for an existing upvalue for this location
-        let mut prev_upvalue: Option<*mut ObjUpvalue> = None;
+        let mut prev_upvalue: Option<Gc<ObjUpvalue>> = None;
         let mut upvalue = self.open_upvalues;
         while let Some(unwrap_upvalue) = upvalue {
-            if unsafe { (*unwrap_upvalue).location } <= location {
+            if unwrap_upvalue.get().location <= location {
                 break;
             }
             prev_upvalue = Some(unwrap_upvalue);
-            upvalue = unsafe { (*unwrap_upvalue).next_upvalue };
+            upvalue = unwrap_upvalue.get().next_upvalue;
         }
 
         if let Some(upvalue) = upvalue {
-            if unsafe { (*upvalue).location == location } {
+            if upvalue.get().location == location {
                 return upvalue;
             }
         }
@@ -372,7 +1314,7 @@ impl<'a> VM<'a> {
         new_upvalue.next_upvalue = upvalue;
         let new_upvalue_ptr = self.heap_alloc(new_upvalue);
         match prev_upvalue {
-            Some(prev_upvalue) => unsafe { (*prev_upvalue).next_upvalue = Some(new_upvalue_ptr) },
+            Some(prev_upvalue) => prev_upvalue.get_mut().next_upvalue = Some(new_upvalue_ptr),
             None => self.open_upvalues = Some(new_upvalue_ptr),
         };
         new_upvalue_ptr
@@ -380,17 +1322,36 @@ impl<'a> VM<'a> {
 
     fn close_upvalues(&mut self, last_location: usize) {
         while let Some(upvalue) = self.open_upvalues {
-            if unsafe { (*upvalue).location < last_location } {
+            let upvalue = upvalue.get_mut();
+            if upvalue.location < last_location {
                 break;
             }
-            unsafe {
-                (*upvalue).closed = Some(self.stack[(*upvalue).location].clone());
-                // TODO
-                self.open_upvalues = (*upvalue).next_upvalue;
-            }
+            let value = self.stack[upvalue.location].clone();
+            self.write_barrier(&value);
+            upvalue.closed = Some(value);
+            self.open_upvalues = upvalue.next_upvalue;
         }
     }
 
+    /// Returns the names and current values of the locals in scope in a call
+    /// frame, counting frames from the innermost (0) outwards. Returns an
+    /// empty list if there's no such frame.
+    pub fn frame_locals(&self, frame_index: usize) -> Vec<(String, Value)> {
+        let Some(frame) = self.frames.iter().rev().nth(frame_index) else {
+            return Vec::new();
+        };
+        let chunk = &frame.closure.get().function.get().chunk;
+        // The frame's ip has moved past the instruction it's executing
+        let offset = frame.ip.saturating_sub(1);
+        chunk
+            .locals_at(offset)
+            .map(|local| {
+                let value = self.stack[frame.first_slot + local.slot].clone();
+                (local.name.clone(), value)
+            })
+            .collect()
+    }
+
     fn read_byte(&mut self) -> u8 {
         self.frames.last_mut().unwrap().read_byte()
     }
@@ -403,7 +1364,7 @@ impl<'a> VM<'a> {
         self.frames.last_mut().unwrap().read_constant()
     }
 
-    fn read_string(&mut self) -> &str {
+    fn read_string(&mut self) -> Gc<ObjString> {
         self.frames.last_mut().unwrap().read_string()
     }
 
@@ -438,187 +1399,522 @@ impl<'a> VM<'a> {
         self.stack[self.stack_top - 1 - distance].clone()
     }
 
-    fn reset_stack(&mut self) {
+    /// Restores a clean state to interpret from, as after a runtime error:
+    /// an empty stack, with no calls, `try` blocks, or open upvalues. A
+    /// closure that captured a variable on the stack keeps the variable's last
+    /// value, as if its function had returned. Globals are kept, including
+    /// any defined before the error.
+    pub fn reset(&mut self) {
+        self.close_upvalues(0);
         self.stack_top = 0;
+        self.frames.clear();
+        self.handlers.clear();
+        self.instructions = 0;
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{message}");
-        for frame in self.frames.iter().rev() {
-            let function = unsafe { &(*(*frame.closure).function) };
-            let instruction = frame.ip - 1;
-            let line = function.chunk.lines[instruction];
-            eprintln!("[line {line}] in {function}");
+    /// Raises a runtime error as an exception holding `message`. Succeeds if a
+    /// `try` block caught it; if not, the VM's stack is reset and the error is
+    /// returned.
+    fn runtime_error(&mut self, message: &str) -> Result<(), RuntimeError> {
+        if !self.handlers.is_empty() {
+            let exception = self.heap_alloc(ObjString::new(message));
+            if self.throw_value(Value::ObjString(exception)) {
+                return Ok(());
+            }
         }
-        self.reset_stack();
+        Err(self.report_error(message))
     }
 
-    fn define_native(&mut self, name: &str, function: NativeFunction) {
-        let name = self.heap_alloc(ObjString::new(name));
-        self.push_stack(Value::ObjString(name));
-        let native = self.heap_alloc(ObjNative::new(function));
-        self.push_stack(Value::ObjNative(native));
+    /// Checks for an interrupt, counts the instruction just read against the
+    /// instruction limit, and checks the heap against its limit, returning why
+    /// to stop and the error to stop with if the script shouldn't go on.
+    fn check_stop(&mut self) -> Option<(Stop, String)> {
+        if self.interrupt.0.swap(false, Ordering::Relaxed) {
+            return Some((Stop::Interrupt, "Interrupted.".to_owned()));
+        }
+        self.instructions += 1;
+        if let Some(limit) = self.instruction_limit {
+            if self.instructions > limit {
+                let message = format!("Exceeded the limit of {limit} instructions.");
+                return Some((Stop::Limit, message));
+            }
+        }
+        let max = self.max_allocation_bytes?;
+        if self.allocator.bytes_allocated() > max {
+            // Only what's still reachable counts against the limit
+            self.collect_garbage();
+            if self.allocator.bytes_allocated() > max {
+                let message = format!("Exceeded the limit of {max} bytes allocated.");
+                return Some((Stop::Limit, message));
+            }
+        }
+        None
+    }
 
-        match self.stack[0] {
-            Value::ObjString(str) => self
-                .globals
-                .insert(unsafe { (*str).str.clone() }, self.stack[1].clone()),
-            _ => panic!("This shouldn't be possible..."),
+    /// Unwinds to the innermost `try` block and resumes at its catch block
+    /// with `exception` on the stack. Returns false if there's no handler.
+    fn throw_value(&mut self, exception: Value) -> bool {
+        let Some(handler) = self.handlers.pop() else {
+            return false;
         };
-
-        self.pop_stack();
-        self.pop_stack();
+        self.close_upvalues(handler.stack_top);
+        self.frames.truncate(handler.frame_count);
+        self.stack_top = handler.stack_top;
+        self.push_stack(exception);
+        self.frames.last_mut().unwrap().ip = handler.catch_ip;
+        true
     }
 
-    fn concatenate(&mut self) -> Result<(), InterpretResult> {
-        let b = self.pop_stack();
-        let a = self.pop_stack();
-        let (Value::ObjString(obj_str1), Value::ObjString(obj_str2)) = (a, b) else {
-            self.runtime_error("Concatenation operands must be strings.");
-            return Err(InterpretResult::CompileError);
+    /// Builds the error for an uncaught failure, quoting the failing
+    /// expression's source when it's known, along with a stack trace, then
+    /// resets the VM.
+    fn report_error(&mut self, message: &str) -> RuntimeError {
+        let frame = self.frames.last().unwrap();
+        let chunk = &frame.closure.get().function.get().chunk;
+        let instruction = frame.ip - 1;
+        let span = chunk.span_at(instruction);
+        let line = chunk.lines[instruction];
+        let range = match span {
+            Some(span) if span.line == line => SourceRange::of(&span.text, line, span.column),
+            _ => SourceRange::of("", line, chunk.columns[instruction]),
         };
+        let span = span.map(|span| span.text.clone());
+        let frames = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.closure.get().function.get();
+                let instruction = frame.ip - 1;
+                StackFrame {
+                    function: function.name.as_ref().map(|name| name.str.clone()),
+                    line: Some(function.chunk.lines[instruction]),
+                    file: function.chunk.file_at(instruction).map(str::to_owned),
+                }
+            })
+            .collect();
+        self.reset();
+        RuntimeError {
+            message: message.to_owned(),
+            span,
+            range: Some(range),
+            stack_trace: StackTrace { frames },
+        }
+    }
+
+    /// Builds the type error for the operands of the instruction being
+    /// executed, describing the offending operands.
+    fn operand_error(&self, message: &str) -> String {
+        let frame = self.frames.last().unwrap();
+        let chunk = &frame.closure.get().function.get().chunk;
 
-        unsafe {
-            let str1 = &(*obj_str1).str;
-            let str2 = &(*obj_str2).str;
-            let new_obj = self.heap_alloc(ObjString::new(format!("{}{}", str1, str2).as_str()));
-            let new_value = Value::ObjString(new_obj);
-            self.push_stack(new_value);
+        let instruction = Opcode::try_from(chunk.code[frame.ip - 1]);
+        match instruction {
+            Ok(Opcode::Negate | Opcode::BitNot) => {
+                format!("{message}, but got {}.", self.peek(0).describe())
+            }
+            _ => {
+                let (a, b) = (self.peek(1), self.peek(0));
+                let operand_is_valid = |value: &Value| match instruction {
+                    Ok(Opcode::Add) => matches!(value, Value::Number(_) | Value::ObjString(_)),
+                    _ => matches!(value, Value::Number(_)),
+                };
+                match (operand_is_valid(&a), operand_is_valid(&b)) {
+                    (true, false) => {
+                        format!("{message}, but the right operand is {}.", b.describe())
+                    }
+                    (false, true) => {
+                        format!("{message}, but the left operand is {}.", a.describe())
+                    }
+                    _ => format!(
+                        "{message}, but the left operand is {} and the right operand is {}.",
+                        a.describe(),
+                        b.describe()
+                    ),
+                }
+            }
         }
+    }
 
+    /// Defines the global in `slot` as the value on top of the stack, unless
+    /// it's a constant, which can't be redefined.
+    fn define_global(&mut self, slot: usize, is_const: bool) -> Result<(), String> {
+        if self.globals.is_const(slot) {
+            return Err(format!(
+                "Can't redefine constant '{}'.",
+                self.globals.name(slot)
+            ));
+        }
+        if is_const {
+            self.globals.define_const_slot(slot, self.peek(0));
+        } else {
+            self.globals.define_slot(slot, self.peek(0));
+        }
+        self.pop_stack();
         Ok(())
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
-        match callee {
-            Value::ObjNative(obj_native) => {
-                self.call_native(obj_native, arg_count);
-                true
+    /// Builds the error for an undefined global, suggesting the closest global
+    /// or in-scope local name. `instruction_len` is the size of the failing
+    /// global instruction, which has just been read.
+    fn undefined_variable_message(&self, name: &str, instruction_len: usize) -> String {
+        let frame = self.frames.last().unwrap();
+        let chunk = &frame.closure.get().function.get().chunk;
+        let offset = frame.ip - instruction_len;
+        let candidates = self
+            .globals
+            .iter()
+            .map(|(global, _)| global)
+            .chain(chunk.locals_at(offset).map(|local| local.name.as_str()));
+
+        match edit_distance::closest_match(name, candidates) {
+            Some(suggestion) => {
+                format!("Undefined variable '{name}'. Did you mean '{suggestion}'?")
             }
+            None => format!("Undefined variable '{name}'."),
+        }
+    }
+
+    fn define_native(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        function: Box<NativeFn>,
+    ) -> Gc<ObjNative> {
+        let native = self.heap_alloc(ObjNative::new(name, arity, function));
+        let interned = self.intern(name);
+        self.globals.define(interned, Value::ObjNative(native));
+        native
+    }
+
+    fn concatenate(&mut self) {
+        // Leave the operands on the stack until the result is allocated, so a
+        // collection can't free them first
+        // `Add` only concatenates once it's checked both operands are strings
+        let (obj_str1, obj_str2) = unsafe {
+            (
+                self.peek(1).as_obj_string_unchecked(),
+                self.peek(0).as_obj_string_unchecked(),
+            )
+        };
+
+        let str1 = &obj_str1.get().str;
+        let str2 = &obj_str2.get().str;
+        let new_obj = self.heap_alloc(ObjString::new(format!("{}{}", str1, str2).as_str()));
+        self.stack_top -= 2;
+        self.push_stack(Value::ObjString(new_obj));
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), String> {
+        match callee {
+            Value::ObjNative(obj_native) => self.call_native(obj_native, arg_count),
             Value::ObjClosure(obj_closure) => self.call(obj_closure, arg_count),
-            _ => {
-                self.runtime_error("Can only call functions and classes.");
-                false
+            _ => Err("Can only call functions and classes.".to_string()),
+        }
+    }
+
+    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> Result<(), String> {
+        let function = closure.get().function;
+        let Some(entry_point) = function.get().entry_point(arg_count) else {
+            let (min_arity, arity) = (function.get().min_arity(), function.get().arity);
+            return Err(if min_arity == arity as usize {
+                format!("Expected {arity} arguments but got {arg_count}")
+            } else {
+                format!("Expected {min_arity} to {arity} arguments but got {arg_count}")
+            });
+        };
+        if self.frames.len() == self.max_frames {
+            return Err("Stack overflow.".to_string());
+        }
+        let mut frame = CallFrame::new(closure, self.stack_top - arg_count - 1);
+        frame.ip = entry_point;
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: Gc<ObjNative>, arg_count: usize) -> Result<(), String> {
+        let native = native.get();
+        if let Some(arity) = native.arity {
+            if arg_count != arity {
+                return Err(format!("Expected {arity} arguments but got {arg_count}"));
             }
         }
+
+        let first_arg = self.stack_top - arg_count;
+        self.run_native(&*native.function, first_arg, first_arg - 1)
     }
 
-    fn call(&mut self, closure: *mut ObjClosure, arg_count: usize) -> bool {
-        let function = unsafe { (*closure).function };
-        let arity = unsafe { (*function).arity as usize };
+    /// Calls the method `name` of the receiver below the `arg_count`
+    /// arguments on top of the stack, replacing them all with its result.
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: usize) -> Result<(), String> {
+        let receiver = self.peek(arg_count);
+        let name = &name.get().str;
+        let Some((arity, method)) = methods::method(&receiver, name) else {
+            return Err(format!(
+                "Undefined method '{name}' for {}.",
+                receiver.type_name()
+            ));
+        };
         if arg_count != arity {
-            self.runtime_error(format!("Expected {arity} arguments but got {arg_count}").as_str());
-            return false;
+            return Err(format!("Expected {arity} arguments but got {arg_count}"));
         }
-        if self.frames.len() == FRAMES_MAX {
-            self.runtime_error("Stack overflow.");
-            return false;
-        }
-        self.frames.push(CallFrame {
-            closure,
-            first_slot: self.stack_top - arg_count - 1,
-            ip: 0,
-        });
-        true
+        let receiver_slot = self.stack_top - arg_count - 1;
+        self.run_native(&method, receiver_slot, receiver_slot)
     }
 
-    fn call_native(&mut self, native: *const ObjNative, arg_count: usize) {
-        let native = unsafe { &(*native) };
+    /// Runs `function` on the values from `first_arg` to the top of the
+    /// stack, then replaces everything from `result_slot` up with its result.
+    fn run_native(
+        &mut self,
+        function: &NativeFn,
+        first_arg: usize,
+        result_slot: usize,
+    ) -> Result<(), String> {
+        let args = &self.stack[first_arg..self.stack_top];
+        memory::set_script_line(self.current_line());
+        let result = function(self.allocator, args);
+        memory::set_script_line(None);
+        let result = result.map_err(|error| error.message)?;
 
-        let result = match native.native_function {
-            crate::object_native::NativeFunction::Clock => {
-                let time = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                Value::Number(time as f64)
+        // A native like `push` may have stored an argument in one of the
+        // others, which a collection may already have traced
+        if self.marking.is_some() {
+            for slot in first_arg..self.stack_top {
+                let arg = self.stack[slot].clone();
+                self.write_barrier(&arg);
             }
-        };
-
-        self.stack_top -= arg_count + 1;
+        }
+        self.stack_top = result_slot;
         self.push_stack(result);
+        if self.allocator.take_collection_request() {
+            self.collect_garbage();
+        }
+        Ok(())
     }
 
-    fn heap_alloc<T>(&mut self, obj: T) -> *mut T
+    #[track_caller]
+    fn heap_alloc<T>(&mut self, obj: T) -> Gc<T>
     where
         T: GC + std::fmt::Display + 'static,
     {
-        if self.debug_stress_gc {
-            self.collect_garbage()
+        if self.marking.is_none()
+            && self
+                .collector
+                .should_collect(self.allocator.bytes_allocated())
+        {
+            match self.collector.mark_slice() {
+                Some(_) => self.begin_collection(),
+                None => self.collect_garbage(),
+            }
+        }
+        memory::set_script_line(self.current_line());
+        let object = self.allocator.heap_alloc(obj);
+        memory::set_script_line(None);
+        // An object allocated while a collection is marking may only be
+        // referenced from objects it has already traced, so it's marked too
+        if let Some(gray) = &mut self.marking {
+            VM::mark_object(object.erase(), gray, self.debug_log_gc);
         }
-        self.allocator.heap_alloc(obj)
+        object
+    }
+
+    /// The script line of the instruction being executed, if any is.
+    fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        let chunk = &frame.closure.get().function.get().chunk;
+        chunk.lines.get(frame.ip.checked_sub(1)?).copied()
     }
 
+    /// Collects garbage all at once, finishing any collection that's marking
+    /// a slice at a time.
     fn collect_garbage(&mut self) {
+        if self.marking.is_none() {
+            self.begin_collection();
+        }
+        self.finish_collection();
+    }
+
+    /// Starts a collection by marking the roots, leaving the objects they
+    /// reference to be traced by `mark_slice` or `finish_collection`.
+    fn begin_collection(&mut self) {
+        let start = Instant::now();
         if self.debug_log_gc {
             println!("-- gc begin (vm)");
         }
+        let mut gray = Vec::new();
+        for root in self.roots() {
+            VM::mark_object(root, &mut gray, self.debug_log_gc);
+        }
+        self.marking = Some(gray);
+        self.allocator.record_pause(start.elapsed());
+    }
 
-        self.mark_roots();
+    /// Traces up to `slice` marked objects, finishing the collection once
+    /// there are none left to trace.
+    fn mark_slice(&mut self, slice: usize) {
+        let start = Instant::now();
+        let Some(gray) = &mut self.marking else {
+            return;
+        };
+        for _ in 0..slice {
+            let Some(object) = gray.pop() else {
+                break;
+            };
+            for reference in object.get().references() {
+                VM::mark_object(reference, gray, self.debug_log_gc);
+            }
+        }
+        let traced = gray.is_empty();
+        self.allocator.record_pause(start.elapsed());
+        if traced {
+            self.finish_collection();
+        }
+    }
+
+    /// Marks the roots again, since the stack and globals change without a
+    /// write barrier, traces every object still left, and frees the rest.
+    fn finish_collection(&mut self) {
+        let start = Instant::now();
+        let mut gray = self.marking.take().unwrap_or_default();
+        for root in self.roots() {
+            VM::mark_object(root, &mut gray, self.debug_log_gc);
+        }
+        while let Some(object) = gray.pop() {
+            for reference in object.get().references() {
+                VM::mark_object(reference, &mut gray, self.debug_log_gc);
+            }
+        }
+        self.remove_unmarked_identifiers();
+        let bytes_reclaimed = self.allocator.sweep();
+        self.collector.collected(self.allocator.bytes_allocated());
 
         if self.debug_log_gc {
-            println!("-- gc end (vm)");
+            println!(
+                "-- gc end (vm): collected {} bytes, {} remain",
+                bytes_reclaimed,
+                self.allocator.bytes_allocated()
+            );
+        }
+        self.allocator
+            .record_collection(start.elapsed(), bytes_reclaimed);
+
+        if self.debug_verify_heap {
+            if let Err(message) = self.verify_heap() {
+                panic!("Heap verification failed after collection: {message}");
+            }
         }
     }
 
-    fn mark_roots(&mut self) {
-        // Mark variables on the stack
-        for i in 0..self.stack_top {
-            VM::mark_value(&self.stack[i], self.debug_log_gc);
+    /// The objects the VM refers to directly.
+    fn roots(&self) -> Vec<Gc<dyn GC>> {
+        let mut roots = Vec::new();
+
+        // Variables on the stack
+        roots.extend(
+            self.stack[..self.stack_top]
+                .iter()
+                .filter_map(Value::as_object),
+        );
+
+        // Variables in the globals table
+        roots.extend(self.globals.values().filter_map(Value::as_object));
+
+        // Compile-time constants
+        roots.extend(self.defines.values().filter_map(Value::as_object));
+
+        // Pure builtins, kept so one can't be freed and its address reused
+        // by whatever replaced it
+        for native in self.pure_natives.values() {
+            roots.push(native.erase());
         }
 
-        // Mark variables in the globals table
-        for (_, val) in self.globals.iter_mut() {
-            VM::mark_value(val, self.debug_log_gc);
+        // Global names, which compiled code refers to by slot and errors
+        // name them by. The identifiers interned for anything else are only
+        // kept while something else uses them; see `remove_unmarked_identifiers`
+        for name in self.globals.names() {
+            roots.push(name.erase());
         }
 
-        // Mark closures in call frames
-        for frame in self.frames.iter_mut() {
-            VM::mark_value(&Value::ObjClosure(frame.closure), self.debug_log_gc)
+        // Closures in call frames
+        for frame in self.frames.iter() {
+            roots.push(frame.closure.erase());
         }
 
-        // Mark open upvalues
+        // Open upvalues
         let mut upvalue = self.open_upvalues;
         while let Some(unwrapped_upvalue) = upvalue {
-            unsafe {
-                if self.debug_log_gc {
-                    println!("mark {}", (*unwrapped_upvalue));
-                }
-                (*unwrapped_upvalue).is_marked = true;
-                upvalue = (*unwrapped_upvalue).next_upvalue;
-            }
+            roots.push(unwrapped_upvalue.erase());
+            upvalue = unwrapped_upvalue.get().next_upvalue;
         }
+
+        roots
     }
 
-    fn mark_value(value: &Value, debug_log_gc: bool) {
-        match value {
-            Value::Bool(_) | Value::Nil | Value::Number(_) => {
-                return;
-            }
-            Value::ObjString(obj_string) => {
-                if debug_log_gc {
-                    println!("mark {}", value);
-                }
-                unsafe { (*(*obj_string)).is_marked = true };
+    /// Forgets the interned identifiers nothing marked refers to, as clox's
+    /// `tableRemoveWhite` does, so the table doesn't keep them alive and
+    /// doesn't point at them once they're swept.
+    fn remove_unmarked_identifiers(&mut self) {
+        self.identifiers
+            .retain(|_, name| name.get().header().is_marked);
+    }
+
+    /// Marks the object `value` refers to, if a collection is marking, so
+    /// storing it in an object the collection has already traced can't hide
+    /// it from the collection.
+    fn write_barrier(&mut self, value: &Value) {
+        if let (Some(gray), Some(object)) = (&mut self.marking, value.as_object()) {
+            VM::mark_object(object, gray, self.debug_log_gc);
+        }
+    }
+
+    /// Marks `object`, queuing it on `gray` so the objects it references are
+    /// marked in turn.
+    fn mark_object(object: Gc<dyn GC>, gray: &mut Vec<Gc<dyn GC>>, debug_log_gc: bool) {
+        let object_ref = object.get_mut();
+        if object_ref.header().is_marked {
+            return;
+        }
+        if debug_log_gc {
+            println!("mark {}", object_ref.label());
+        }
+        object_ref.header_mut().is_marked = true;
+        gray.push(object);
+    }
+
+    /// Checks that every object reachable from the VM's roots is still
+    /// allocated, and that no object was left marked by the last sweep.
+    fn verify_heap(&self) -> Result<(), String> {
+        let objects = self.allocator.objects();
+        let live: HashSet<Gc<dyn GC>> = objects.iter().copied().collect();
+        for object in objects.iter() {
+            let object = object.get();
+            if object.header().is_marked {
+                return Err(format!(
+                    "{} {} is still marked",
+                    object.kind(),
+                    object.label()
+                ));
             }
-            Value::ObjFunction(obj_function) => {
-                if debug_log_gc {
-                    println!("mark {}", value);
-                }
-                unsafe { (*(*obj_function)).is_marked = true }
+        }
+
+        for (name, interned) in self.identifiers.iter() {
+            if !live.contains(&interned.erase()) {
+                return Err(format!("interned identifier {name} was freed"));
             }
-            Value::ObjNative(obj_native) => {
-                if debug_log_gc {
-                    println!("mark {}", value);
-                }
-                unsafe { (*(*obj_native)).is_marked = true }
+        }
+
+        let mut seen = HashSet::new();
+        let mut pending = self.roots();
+        while let Some(object) = pending.pop() {
+            if !seen.insert(object) {
+                continue;
             }
-            Value::ObjClosure(object_closure) => {
-                if debug_log_gc {
-                    println!("mark {}", value);
-                }
-                unsafe { (*(*object_closure)).is_marked = true }
+            // Check before dereferencing, since a freed object can't be read
+            if !live.contains(&object) {
+                return Err(format!("reachable object {:p} was freed", object.as_ptr()));
             }
+            pending.extend(object.get().references());
         }
+        Ok(())
     }
 }
@@ -0,0 +1,117 @@
+use crate::collector::CollectorStrategy;
+use crate::error::LoxError;
+use crate::memory::Allocator;
+use crate::vm::{FRAMES_MAX, STACK_MAX, VM};
+
+/// Configures a VM for embedding, including Lox code to run before any user
+/// script.
+pub struct VmBuilder<'a> {
+    allocator: &'a mut Allocator,
+    debug_stress_gc: bool,
+    debug_log_gc: bool,
+    collector: Option<Box<dyn CollectorStrategy>>,
+    debug_info: bool,
+    debug_print_code: bool,
+    debug_trace_execution: bool,
+    stack_max: usize,
+    max_frames: usize,
+    stdlib: bool,
+    preludes: Vec<String>,
+}
+
+impl<'a> VmBuilder<'a> {
+    pub fn new(allocator: &'a mut Allocator) -> VmBuilder<'a> {
+        VmBuilder {
+            allocator,
+            debug_stress_gc: false,
+            debug_log_gc: false,
+            collector: None,
+            debug_info: true,
+            debug_print_code: false,
+            debug_trace_execution: false,
+            stack_max: STACK_MAX,
+            max_frames: FRAMES_MAX,
+            stdlib: true,
+            preludes: Vec::new(),
+        }
+    }
+
+    pub fn stress_gc(mut self, debug_stress_gc: bool) -> Self {
+        self.debug_stress_gc = debug_stress_gc;
+        self
+    }
+
+    pub fn log_gc(mut self, debug_log_gc: bool) -> Self {
+        self.debug_log_gc = debug_log_gc;
+        self
+    }
+
+    /// Chooses when the VM collects garbage, overriding `stress_gc`.
+    pub fn collector(mut self, collector: impl CollectorStrategy + 'static) -> Self {
+        self.collector = Some(Box::new(collector));
+        self
+    }
+
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    pub fn print_code(mut self, debug_print_code: bool) -> Self {
+        self.debug_print_code = debug_print_code;
+        self
+    }
+
+    pub fn trace_execution(mut self, debug_trace_execution: bool) -> Self {
+        self.debug_trace_execution = debug_trace_execution;
+        self
+    }
+
+    /// Sets how many values the VM's stack holds; see `VM::set_stack_max`.
+    pub fn stack_max(mut self, slots: usize) -> Self {
+        self.stack_max = slots;
+        self
+    }
+
+    /// Sets how deeply calls may nest; see `VM::set_max_frames`.
+    pub fn max_frames(mut self, depth: usize) -> Self {
+        self.max_frames = depth;
+        self
+    }
+
+    /// Whether to load the standard library, before any prelude; see
+    /// `stdlib`. It's loaded unless this is turned off.
+    pub fn stdlib(mut self, stdlib: bool) -> Self {
+        self.stdlib = stdlib;
+        self
+    }
+
+    /// Adds Lox code to run when the VM is built, in the same global scope as
+    /// the scripts run on it later, so hosts can provide helper functions.
+    /// Preludes run in the order they're added.
+    pub fn prelude(mut self, source: &str) -> Self {
+        self.preludes.push(source.to_owned());
+        self
+    }
+
+    /// Creates the VM, loads the standard library, and runs the preludes,
+    /// failing with the error from the first that doesn't run cleanly.
+    pub fn build(self) -> Result<VM<'a>, LoxError> {
+        let mut vm = VM::new(self.allocator, self.debug_stress_gc, self.debug_log_gc);
+        if let Some(collector) = self.collector {
+            vm.set_collector(collector);
+        }
+        if self.stdlib {
+            vm.load_stdlib()?;
+        }
+        vm.set_debug_info(self.debug_info);
+        vm.set_print_code(self.debug_print_code);
+        vm.set_trace_execution(self.debug_trace_execution);
+        vm.set_stack_max(self.stack_max);
+        vm.set_max_frames(self.max_frames);
+        for prelude in self.preludes {
+            vm.interpret(prelude)?;
+        }
+        Ok(vm)
+    }
+}
@@ -0,0 +1,54 @@
+//! Checks that diffing the bytecode of two versions of a program shows only
+//! what changed between them.
+
+use rlox::chunk_diff::diff_programs;
+use rlox::memory::{Allocator, Gc};
+use rlox::object_function::ObjFunction;
+use rlox::{Compiler, CompilerLimits};
+use std::collections::HashMap;
+
+fn compile(allocator: &mut Allocator, source: &str) -> Gc<ObjFunction> {
+    let mut identifiers = HashMap::new();
+    let mut compiler = Compiler::new(
+        source,
+        allocator,
+        &mut identifiers,
+        CompilerLimits::default(),
+    );
+    compiler.prepare();
+    compiler
+        .compile(false)
+        .unwrap_or_else(|_| panic!("Expected the source to compile"))
+}
+
+#[test]
+fn an_inserted_statement_only_changes_its_own_lines() {
+    let old = "fun count(n) {
+  var total = 0;
+  for (var i = 0; i < n; i = i + 1) {
+    if (i > 2) total = total + i;
+  }
+  fun add(x) { return total + x; }
+  return add;
+}
+print count(5)(1);";
+    let new = old.replace(
+        "  var total = 0;\n",
+        "  var total = 0;\n  print \"counting\";\n",
+    );
+
+    let mut allocator = Allocator::new();
+    let old = compile(&mut allocator, old);
+    let new = compile(&mut allocator, &new);
+    let (diff, changed) = diff_programs(old, new);
+    assert!(changed);
+    let changes: Vec<&str> = diff
+        .lines()
+        .filter(|line| line.starts_with(['+', '-']))
+        .collect();
+    assert_eq!(
+        changes,
+        ["+ Constant         'counting'", "+ Print"],
+        "in:\n{diff}"
+    );
+}
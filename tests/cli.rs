@@ -0,0 +1,108 @@
+//! Runs the `rlox` binary to check how it splits its arguments between
+//! itself and the script, what `rlox test` reports, and what `rlox strip`
+//! writes.
+
+use std::process::Command;
+
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .output()
+        .expect("Expected rlox to run");
+    assert!(output.status.success(), "rlox {args:?} failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn arguments_after_the_script_are_passed_on_unchanged() {
+    let path = std::env::temp_dir().join(format!("rlox-cli-{}.lox", std::process::id()));
+    std::fs::write(&path, "print args;").unwrap();
+    let script = path.to_str().unwrap();
+    assert_eq!(
+        run(&[script, "foo", "-O", "--quiet", "bar"]),
+        "[foo, -O, --quiet, bar]\n"
+    );
+    assert_eq!(run(&["--quiet", script, "--", "x"]), "[--, x]\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn arguments_after_inline_code_are_passed_on_unchanged() {
+    assert_eq!(
+        run(&["-e", "print args;", "x", "--strip"]),
+        "[x, --strip]\n"
+    );
+    assert_eq!(run(&["-O", "-e", "print args;", "-O"]), "[-O]\n");
+}
+
+#[test]
+fn a_double_dash_ends_the_flags() {
+    assert_eq!(
+        run(&["--no-warnings", "--", "-e", "print args;", "-O"]),
+        "[-O]\n"
+    );
+}
+
+#[test]
+fn the_test_runner_passes_every_fixture() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(["test", "tests/fixtures", "examples"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("Expected rlox to run");
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{report}");
+    assert!(report.ends_with(" passed, 0 failed\n"), "{report}");
+    assert!(!report.starts_with("0 passed"), "No fixtures found");
+}
+
+#[test]
+fn the_test_runner_checks_printed_output() {
+    let path = std::env::temp_dir().join(format!("rlox-test-{}.lox", std::process::id()));
+    std::fs::write(&path, "print 1; // expect: 2\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("test")
+        .arg(&path)
+        .output()
+        .expect("Expected rlox to run");
+    std::fs::remove_file(&path).unwrap();
+    assert!(!output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        report.contains("Expected output '2' on line 1, but got '1'."),
+        "{report}"
+    );
+    assert!(report.ends_with("0 passed, 1 failed\n"), "{report}");
+}
+
+#[test]
+fn stripping_a_compiled_file_matches_compiling_it_stripped() {
+    let dir = std::env::temp_dir().join(format!("rlox-strip-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+    std::fs::write(
+        path("script.lox"),
+        "fun f(x) { var y = x + 1; return y; }\nprint f(1);\n",
+    )
+    .unwrap();
+
+    run(&["compile", &path("script.lox"), "-o", &path("full.loxc")]);
+    run(&[
+        "compile",
+        "--strip",
+        &path("script.lox"),
+        "-o",
+        &path("compiled.loxc"),
+    ]);
+    run(&["strip", &path("full.loxc"), "-o", &path("stripped.loxc")]);
+    let full = std::fs::read(path("full.loxc")).unwrap();
+    let stripped = std::fs::read(path("stripped.loxc")).unwrap();
+    assert_ne!(full, stripped);
+    assert_eq!(stripped, std::fs::read(path("compiled.loxc")).unwrap());
+    assert_eq!(run(&[&path("stripped.loxc")]), "2\n");
+
+    // Stripping again, in place, changes nothing
+    run(&["strip", &path("stripped.loxc")]);
+    assert_eq!(std::fs::read(path("stripped.loxc")).unwrap(), stripped);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,92 @@
+//! What the fixture and corpus tests share: running a script to see what it
+//! does, to compare with the expectations in its comments; see
+//! `rlox::expectations` for their forms.
+
+pub use rlox::expectations::Outcome;
+use rlox::{memory, CollectorStrategy, Stress, VmBuilder};
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Collects what a script prints.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Default,
+    StressGc,
+    IncrementalGc,
+    Optimized,
+}
+
+/// Starts a collection whenever none is running and traces one object
+/// between each instruction, so a store that skips the write barrier frees
+/// an object still in use.
+struct IncrementalStress;
+
+impl CollectorStrategy for IncrementalStress {
+    fn should_collect(&mut self, _bytes_allocated: usize) -> bool {
+        true
+    }
+
+    fn mark_slice(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+pub fn run(source: &str, mode: Mode) -> Outcome {
+    let mut allocator = memory::Allocator::new();
+    let builder = VmBuilder::new(&mut allocator);
+    let mut vm = match mode {
+        Mode::StressGc => builder.collector(Stress),
+        Mode::IncrementalGc => builder.collector(IncrementalStress),
+        _ => builder,
+    }
+    .build()
+    .expect("Failed to build VM");
+    vm.set_verify_heap(matches!(mode, Mode::StressGc | Mode::IncrementalGc));
+    vm.set_optimize(mode == Mode::Optimized);
+    let output = Output::default();
+    vm.set_output(Box::new(output.clone()));
+    vm.set_warnings(Box::new(std::io::sink()));
+
+    let result = vm.interpret(source.to_owned());
+    let printed = String::from_utf8(output.0.borrow().clone()).expect("Output isn't UTF-8");
+    Outcome::actual(&printed, &result)
+}
+
+/// Adds every `.lox` file under `dir` to `fixtures`, in order.
+pub fn collect_fixtures(dir: &Path, fixtures: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap_or_else(|error| panic!("Failed to read {}: {error}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_fixtures(&path, fixtures);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            fixtures.push(path);
+        }
+    }
+}
+
+pub const MODES: [Mode; 4] = [
+    Mode::Default,
+    Mode::StressGc,
+    Mode::IncrementalGc,
+    Mode::Optimized,
+];
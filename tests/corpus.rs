@@ -0,0 +1,118 @@
+//! Runs the Crafting Interpreters test suite, vendored under
+//! `tests/corpus/test` by `just corpus`, which records the revision it
+//! fetched in `tests/corpus/REVISION` and fetches that one from then on,
+//! checking each script against the expectations in its comments as the
+//! fixtures are. rlox doesn't implement all of Lox, so
+//! only the scripts in `tests/corpus/pass-list.txt` have to pass; one of them
+//! failing is a regression, and a script that passes without being listed is
+//! reported so the list can grow. A missing corpus or an empty pass list fails
+//! the test rather than letting it check nothing.
+//!
+//! The suite isn't checked in, so the test only runs when asked for, with
+//! `cargo test --test corpus -- --ignored`.
+//!
+//! The suite's scanning and expression chapters test parts of the
+//! tree-walking interpreter that clox doesn't have, and its benchmarks take
+//! too long to run here, so those directories are skipped.
+
+mod common;
+
+use common::{collect_fixtures, run, Outcome, MODES};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const SKIPPED: [&str; 3] = ["benchmark", "expressions", "scanning"];
+
+#[test]
+#[ignore = "needs the corpus vendored by `just corpus`"]
+fn corpus() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let pass_list_path = root.join("pass-list.txt");
+    let pass_list_text = std::fs::read_to_string(&pass_list_path).unwrap();
+    let pass_list: BTreeSet<&str> = pass_list_text
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    let suite = root.join("test");
+    assert!(
+        suite.is_dir(),
+        "No corpus at {}; run `just corpus` to vendor it",
+        suite.display()
+    );
+    let mut scripts = Vec::new();
+    collect_fixtures(&suite, &mut scripts);
+    assert!(!scripts.is_empty(), "No scripts in {}", suite.display());
+
+    let mut passing = BTreeSet::new();
+    let mut failures = Vec::new();
+    for path in scripts.iter() {
+        let name = path
+            .strip_prefix(&suite)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        if SKIPPED
+            .iter()
+            .any(|dir| name.starts_with(&format!("{dir}/")))
+        {
+            continue;
+        }
+        let source = std::fs::read_to_string(path).unwrap();
+        let expected = Outcome::expected(&source);
+        let failure = MODES.iter().find_map(|&mode| {
+            let actual = run(&source, mode);
+            (actual != expected).then(|| {
+                format!("{name} ({mode:?})\n  expected: {expected:?}\n  actual:   {actual:?}")
+            })
+        });
+        match failure {
+            None => {
+                passing.insert(name);
+            }
+            Some(failure) if pass_list.contains(name.as_str()) => failures.push(failure),
+            Some(_) => (),
+        }
+    }
+    for name in pass_list.iter() {
+        if !passing.contains(*name) && !scripts.iter().any(|path| path.ends_with(name)) {
+            failures.push(format!("{name} is in the pass list but not in the corpus"));
+        }
+    }
+
+    if std::env::var_os("RLOX_UPDATE_PASS_LIST").is_some() {
+        let header: String = pass_list_text
+            .lines()
+            .take_while(|line| line.starts_with('#'))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let names: String = passing.iter().map(|name| format!("{name}\n")).collect();
+        std::fs::write(&pass_list_path, header + &names).unwrap();
+        return;
+    }
+
+    assert!(
+        !pass_list.is_empty(),
+        "The pass list is empty; fill it with RLOX_UPDATE_PASS_LIST=1 cargo test --test corpus -- --ignored"
+    );
+    let unlisted: Vec<&String> = passing
+        .iter()
+        .filter(|name| !pass_list.contains(name.as_str()))
+        .collect();
+    if !unlisted.is_empty() {
+        eprintln!(
+            "{} corpus scripts pass but aren't in the pass list; update it with \
+             RLOX_UPDATE_PASS_LIST=1:",
+            unlisted.len()
+        );
+        for name in unlisted {
+            eprintln!("  {name}");
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} corpus scripts in the pass list failed:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
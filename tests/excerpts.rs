@@ -0,0 +1,123 @@
+//! Checks the columns errors are located at, and the source excerpts that
+//! point at them.
+
+use rlox::error::MAX_SHOWN_ERRORS;
+use rlox::{bytecode, memory, Compiler, CompilerLimits, LoxError, SourceRange, VM};
+use std::collections::HashMap;
+
+#[test]
+fn compile_errors_point_at_the_offending_token() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    let source = "var a = 1;\nprint a + );";
+    let Err(error @ LoxError::Compile(_)) = vm.interpret(source.to_owned()) else {
+        panic!("Expected a compile error");
+    };
+    let LoxError::Compile(errors) = &error else {
+        unreachable!()
+    };
+    assert_eq!(
+        errors[0].range(),
+        SourceRange {
+            line: 2,
+            column: 11,
+            len: 1
+        }
+    );
+    assert_eq!(
+        error.with_excerpts(None, source),
+        "[line 2] Error at ')': Expect expression with prefix parser.\n\
+         2 | print a + );\n  |           ^ expected an expression, found ')'"
+    );
+}
+
+#[test]
+fn compile_errors_name_the_expected_token() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    let source = "print (1;\nvar = 2;";
+    let Err(error) = vm.interpret(source.to_owned()) else {
+        panic!("Expected a compile error");
+    };
+    assert_eq!(
+        error.with_excerpts(None, source),
+        "[line 1] Error at ';': Expect ')' after expression.\n\
+         1 | print (1;\n  |         ^ expected ')', found ';'\n\
+         [line 2] Error at '=': Expect variable name.\n\
+         2 | var = 2;\n  |     ^ expected an identifier, found '='\n\
+         Aborting due to 2 compile errors."
+    );
+}
+
+#[test]
+fn only_the_first_compile_errors_are_shown() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    let source = "print ;\n".repeat(MAX_SHOWN_ERRORS + 3);
+    let Err(error) = vm.interpret(source.clone()) else {
+        panic!("Expected a compile error");
+    };
+    let shown = error.with_excerpts(None, &source);
+    let mut lines = shown.lines().rev();
+    assert_eq!(
+        lines.next(),
+        Some(format!("Aborting due to {} compile errors.", MAX_SHOWN_ERRORS + 3).as_str())
+    );
+    assert_eq!(lines.next(), Some("... and 3 more errors."));
+    assert_eq!(shown.matches("Error at").count(), MAX_SHOWN_ERRORS);
+}
+
+#[test]
+fn runtime_errors_underline_the_failing_expression() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    let source = "fun f(x) {\n  return x - \"s\";\n}\nf(1);";
+    let Err(LoxError::Runtime(error)) = vm.interpret(source.to_owned()) else {
+        panic!("Expected a runtime error");
+    };
+    assert_eq!(
+        error.with_excerpt(None, source),
+        "Operands must be numbers, but the right operand is a string (\"s\").\n\
+         2 |   return x - \"s\";\n  |          ^^^^^^^\n\
+         [line 2] in f()\n[line 4] in script"
+    );
+    // An excerpt is only taken from the file the error happened in
+    assert_eq!(
+        error.with_excerpt(Some("other.lox"), source),
+        error.to_string()
+    );
+}
+
+#[test]
+fn columns_survive_serialization() {
+    let source = "var a = nil;\nprint -a;";
+    let bytes = {
+        let mut allocator = memory::Allocator::new();
+        let mut identifiers = HashMap::new();
+        let mut compiler = Compiler::new(
+            "",
+            &mut allocator,
+            &mut identifiers,
+            CompilerLimits::default(),
+        );
+        compiler.prepare();
+        let function = compiler
+            .compile_sources(&[("a.lox", source)], false)
+            .unwrap();
+        bytecode::serialize(function)
+    };
+    let mut allocator = memory::Allocator::new();
+    let function = bytecode::deserialize(&bytes, &mut allocator).unwrap();
+    let mut vm = VM::new(&mut allocator, false, false);
+    let Err(LoxError::Runtime(error)) = vm.run_function(function) else {
+        panic!("Expected a runtime error");
+    };
+    assert_eq!(
+        error.range,
+        Some(SourceRange {
+            line: 2,
+            column: 7,
+            len: 2
+        })
+    );
+}
@@ -0,0 +1,44 @@
+//! Runs the scripts under `tests/fixtures`, and the examples under
+//! `examples` that `rlox examples` renders, checking each against the
+//! expectations in its comments; see `common` for their forms.
+//!
+//! Each script runs four times: as usual, collecting garbage before every
+//! allocation, collecting incrementally without pause, and with the peephole
+//! optimizer on, so a collector or optimizer bug shows up as a failing
+//! script.
+
+mod common;
+
+use common::{collect_fixtures, run, Outcome, MODES};
+use std::path::Path;
+
+#[test]
+fn fixtures() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut fixtures = Vec::new();
+    collect_fixtures(&root.join("tests/fixtures"), &mut fixtures);
+    collect_fixtures(&root.join("examples"), &mut fixtures);
+    assert!(!fixtures.is_empty(), "No fixtures found");
+
+    let mut failures = Vec::new();
+    for path in fixtures.iter() {
+        let source = std::fs::read_to_string(path).unwrap();
+        let expected = Outcome::expected(&source);
+        for mode in MODES {
+            let actual = run(&source, mode);
+            if actual != expected {
+                failures.push(format!(
+                    "{} ({mode:?})\n  expected: {expected:?}\n  actual:   {actual:?}",
+                    path.display()
+                ));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} fixture runs failed:\n{}",
+        failures.len(),
+        fixtures.len() * MODES.len(),
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,42 @@
+//! Checks that interning a name doesn't keep it alive once nothing uses it.
+
+use rlox::compiler::{Compiler, CompilerLimits};
+use rlox::{bytecode, memory, VM};
+use std::collections::HashMap;
+
+/// Whether a string with the text `text` is allocated.
+fn allocated(allocator: &memory::Allocator, text: &str) -> bool {
+    allocator.objects().iter().any(|object| {
+        let object = object.get();
+        object.kind() == "string" && object.label() == text
+    })
+}
+
+#[test]
+fn name_is_freed_with_the_code_that_used_it() {
+    let mut allocator = memory::Allocator::new();
+    // Compiled without a VM, so globals are looked up by name, which the VM
+    // interns as it loads the code
+    let bytes = {
+        let mut identifiers = HashMap::new();
+        let mut compiler = Compiler::new(
+            "",
+            &mut allocator,
+            &mut identifiers,
+            CompilerLimits::default(),
+        );
+        compiler.prepare();
+        let function = compiler
+            .compile_sources(&[("test", "{ fun f() { return undefinedName; } }")], false)
+            .unwrap();
+        bytecode::serialize(function)
+    };
+    let mut vm = VM::new(&mut allocator, false, false);
+    vm.set_verify_heap(true);
+    let function = bytecode::deserialize(&bytes, vm.allocator).unwrap();
+    vm.run_function(function).unwrap();
+    assert!(allocated(vm.allocator, "undefinedName"));
+
+    vm.leak_report();
+    assert!(!allocated(vm.allocator, "undefinedName"));
+}
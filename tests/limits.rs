@@ -0,0 +1,117 @@
+//! Checks that the execution limits a host sets, and interrupts it sends, stop
+//! a script, and that the script can't catch the error to keep going.
+
+use rlox::vm::SLOTS_PER_FRAME;
+use rlox::{memory, LoxError, VM};
+use std::time::Duration;
+
+fn run(source: &str, configure: impl FnOnce(&mut VM)) -> Result<(), LoxError> {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    vm.set_output(Box::new(std::io::sink()));
+    configure(&mut vm);
+    vm.interpret(source.to_owned())
+}
+
+#[test]
+fn instruction_limit_stops_infinite_loop() {
+    let result = run("try { while (true) {} } catch (e) {}", |vm| {
+        vm.set_instruction_limit(10_000)
+    });
+    match result {
+        Err(LoxError::LimitExceeded(error)) => {
+            assert_eq!(error.message, "Exceeded the limit of 10000 instructions.")
+        }
+        other => panic!("Expected the instruction limit to be exceeded, got {other:?}"),
+    }
+}
+
+#[test]
+fn instruction_limit_allows_short_script() {
+    let result = run(
+        "var x = 0; for (var i = 0; i < 10; i = i + 1) x = x + i;",
+        |vm| vm.set_instruction_limit(10_000),
+    );
+    assert!(result.is_ok(), "{result:?}");
+}
+
+#[test]
+fn allocation_limit_stops_growing_heap() {
+    let result = run("var kept = []; while (true) push(kept, [1, 2, 3]);", |vm| {
+        vm.set_max_allocation_bytes(64 * 1024)
+    });
+    assert!(
+        matches!(result, Err(LoxError::LimitExceeded(_))),
+        "Expected the allocation limit to be exceeded, got {result:?}"
+    );
+}
+
+#[test]
+fn allocation_limit_allows_collected_garbage() {
+    let result = run(
+        "var kept; for (var i = 0; i < 10000; i = i + 1) kept = [i, i];",
+        |vm| vm.set_max_allocation_bytes(64 * 1024),
+    );
+    assert!(result.is_ok(), "{result:?}");
+}
+
+fn assert_allocation_limit_exceeded(source: &str) {
+    let result = run(source, |vm| vm.set_max_allocation_bytes(64 * 1024));
+    assert!(
+        matches!(result, Err(LoxError::LimitExceeded(_))),
+        "Expected the allocation limit to be exceeded, got {result:?}"
+    );
+}
+
+#[test]
+fn allocation_limit_counts_string_contents() {
+    // Doubling 24 times makes a 16 MiB string from few objects
+    assert_allocation_limit_exceeded(
+        "var s = \"x\"; for (var i = 0; i < 24; i = i + 1) s = s + s;",
+    );
+}
+
+#[test]
+fn allocation_limit_counts_list_growth() {
+    assert_allocation_limit_exceeded(
+        "var l = []; for (var i = 0; i < 1000000; i = i + 1) push(l, i);",
+    );
+}
+
+#[test]
+fn allocation_limit_counts_map_growth() {
+    assert_allocation_limit_exceeded(
+        "var m = {}; for (var i = 0; i < 1000000; i = i + 1) m[i] = i;",
+    );
+}
+
+#[test]
+fn interrupt_stops_infinite_loop() {
+    let result = run("try { while (true) {} } catch (e) {}", |vm| {
+        let handle = vm.interrupt_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+    });
+    match result {
+        Err(LoxError::Interrupted(error)) => assert_eq!(error.message, "Interrupted."),
+        other => panic!("Expected the script to be interrupted, got {other:?}"),
+    }
+}
+
+#[test]
+fn max_frames_allows_deeper_recursion() {
+    let source = "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); }
+depth(1000);";
+    let result = run(source, |_| {});
+    assert!(
+        matches!(&result, Err(LoxError::Runtime(error)) if error.message == "Stack overflow."),
+        "Expected the default depth to overflow, got {result:?}"
+    );
+    let result = run(source, |vm| {
+        vm.set_max_frames(2000);
+        vm.set_stack_max(2000 * SLOTS_PER_FRAME);
+    });
+    assert!(result.is_ok(), "{result:?}");
+}
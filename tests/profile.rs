@@ -0,0 +1,36 @@
+//! Checks that a VM's self-profile accounts for each phase of the scripts it
+//! runs, and is only kept when asked for.
+
+use rlox::{memory, VM};
+
+#[test]
+fn profile_covers_each_phase() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, true, false);
+    vm.set_output(Box::new(std::io::sink()));
+    vm.set_self_profile(true);
+    vm.interpret("var kept; for (var i = 0; i < 100; i = i + 1) kept = [i];".to_owned())
+        .unwrap();
+
+    let profile = vm.profile().expect("Profiling was turned on");
+    assert!(profile.tokens > 20, "{profile:?}");
+    assert!(profile.collections > 0, "{profile:?}");
+    assert!(!profile.execution.is_zero(), "{profile:?}");
+    assert_eq!(
+        profile.total(),
+        profile.scanning + profile.compiling + profile.gc + profile.execution
+    );
+    let json = profile.to_json();
+    for phase in ["scanning", "compiling", "gc", "execution"] {
+        assert!(json.contains(&format!("\"{phase}\": ")), "{json}");
+    }
+}
+
+#[test]
+fn profile_is_off_by_default() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    vm.set_output(Box::new(std::io::sink()));
+    vm.interpret("print 1;".to_owned()).unwrap();
+    assert!(vm.profile().is_none());
+}
@@ -0,0 +1,34 @@
+//! Checks that a session carries on cleanly after a line fails.
+
+use rlox::{memory, LoxError, Session};
+
+#[test]
+fn closure_keeps_captured_value_after_runtime_error() {
+    let mut allocator = memory::Allocator::new();
+    let mut session = Session::new(&mut allocator);
+    session
+        .eval("var get; fun fail() { var x = \"kept\"; fun g() { return x; } get = g; nil(); }")
+        .unwrap();
+    assert!(matches!(session.eval("fail();"), Err(LoxError::Runtime(_))));
+    // Reuses the stack slots `fail` ran in
+    session
+        .eval("fun clobber() { var a = \"clobbered\"; var b = a; return get(); }")
+        .unwrap();
+    let value = session.eval("clobber()").unwrap().unwrap();
+    assert_eq!(value.to_string(), "kept");
+}
+
+#[test]
+fn session_runs_after_runtime_error_in_nested_call() {
+    let mut allocator = memory::Allocator::new();
+    let mut session = Session::new(&mut allocator);
+    session
+        .eval("fun deep(n) { if (n == 0) return -\"a\"; return deep(n - 1); }")
+        .unwrap();
+    assert!(matches!(
+        session.eval("deep(10);"),
+        Err(LoxError::Runtime(_))
+    ));
+    let value = session.eval("1 + 2").unwrap().unwrap();
+    assert_eq!(value.to_string(), "3");
+}
@@ -0,0 +1,40 @@
+//! Checks the stack trace attached to an uncaught runtime error.
+
+use rlox::{memory, LoxError, StackFrame, VM};
+
+fn frame(function: Option<&str>, line: Option<usize>) -> StackFrame {
+    StackFrame {
+        function: function.map(str::to_owned),
+        line,
+        file: None,
+    }
+}
+
+#[test]
+fn stack_trace_lists_calls_innermost_first() {
+    let mut allocator = memory::Allocator::new();
+    let mut vm = VM::new(&mut allocator, false, false);
+    let source = "fun inner(x) {
+  return len(x);
+}
+fun outer() {
+  return inner(3);
+}
+outer();";
+    let Err(LoxError::Runtime(error)) = vm.interpret(source.to_owned()) else {
+        panic!("Expected a runtime error");
+    };
+    assert_eq!(
+        error.stack_trace.frames,
+        [
+            frame(Some("len"), None),
+            frame(Some("inner"), Some(2)),
+            frame(Some("outer"), Some(5)),
+            frame(None, Some(7)),
+        ]
+    );
+    assert_eq!(
+        error.stack_trace.to_string(),
+        "[native] in len()\n[line 2] in inner()\n[line 5] in outer()\n[line 7] in script"
+    );
+}
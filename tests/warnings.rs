@@ -0,0 +1,100 @@
+//! Checks the warnings the compiler reports alongside a program that still
+//! compiles.
+
+use rlox::{memory, Compiler, CompilerLimits};
+use std::collections::HashMap;
+
+fn warnings(source: &str) -> Vec<String> {
+    let mut allocator = memory::Allocator::new();
+    let mut identifiers = HashMap::new();
+    let mut compiler = Compiler::new(
+        source,
+        &mut allocator,
+        &mut identifiers,
+        CompilerLimits::default(),
+    );
+    compiler.prepare();
+    compiler
+        .compile(false)
+        .expect("Expected the source to compile");
+    compiler
+        .take_warnings()
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[test]
+fn unused_locals_are_flagged() {
+    let source = "fun f(unusedParameter) {
+  var unused = 1;
+  var _ignored = 2;
+  var assigned;
+  assigned = 3;
+  var read = 4;
+  fun helper() { return read; }
+  return helper;
+}
+{
+  var inner = 5;
+}";
+    assert_eq!(
+        warnings(source),
+        [
+            "[line 2] Warning at 'unused': Unused variable 'unused'; prefix it with '_' if that's intended.",
+            "[line 4] Warning at 'assigned': Unused variable 'assigned'; prefix it with '_' if that's intended.",
+            "[line 11] Warning at 'inner': Unused variable 'inner'; prefix it with '_' if that's intended.",
+        ]
+    );
+}
+
+#[test]
+fn unread_loop_and_catch_variables_are_not_flagged() {
+    for fixture in [
+        "variables/unread_loop_variable.lox",
+        "exceptions/unread_catch_variable.lox",
+    ] {
+        let path = format!("{}/tests/fixtures/{fixture}", env!("CARGO_MANIFEST_DIR"));
+        let source = std::fs::read_to_string(path).unwrap();
+        assert_eq!(warnings(&source), Vec::<String>::new(), "in {fixture}");
+    }
+}
+
+#[test]
+fn code_after_return_is_flagged_once() {
+    let source = "fun f() {
+  return 1;
+  print 2;
+  print 3;
+}
+f();";
+    assert_eq!(
+        warnings(source),
+        ["[line 3] Warning at 'print': Unreachable code."]
+    );
+}
+
+#[test]
+fn constant_conditions_are_flagged() {
+    let source = "if (true) print 1;
+if (1 > 2) print 2;
+while (false) print 3;
+while (true) { print 4; }
+var x = 1;
+if (x) print 5;";
+    assert_eq!(
+        warnings(source),
+        [
+            "[line 1] Warning at 'true': Condition is always true.",
+            "[line 2] Warning at '1': Condition is always false.",
+            "[line 3] Warning at 'false': Condition is always false.",
+        ]
+    );
+}
+
+#[test]
+fn the_standard_library_compiles_without_warnings() {
+    for (name, source) in rlox::stdlib::SOURCES {
+        assert_eq!(warnings(source), Vec::<String>::new(), "in {name}");
+    }
+}